@@ -1,10 +1,11 @@
 use crate::enclave;
 use crate::page;
 use std::mem;
-use crate::error_code::ERROR;
+use crate::error_code::SmError;
 use crate::crypto;
 use crate::sm;
 use crate::pmp;
+use crate::opensbi;
 
 type pte_t = usize;
 
@@ -15,7 +16,7 @@ unsafe fn any_as_u8_slice<T: Sized>(p: &T) -> &[u8] {
   )
 }
 
-pub fn validate_and_hash_enclave(enclave: &mut enclave::enclave) -> usize {
+pub fn validate_and_hash_enclave(enclave: &mut enclave::enclave) -> Result<(), SmError> {
 
     let hash_ctx: crypto::hash_ctx;
     let ptlevel: i32 = page::RISCV_PGLEVEL_TOP as i32;
@@ -27,24 +28,69 @@ pub fn validate_and_hash_enclave(enclave: &mut enclave::enclave) -> usize {
       // hash the runtime parameters
       crypto::hash_extend(&mut hash_ctx, params, mem::size_of::<sm::runtime_va_params_t>());
       // crypto.rs
+
+      // hash the launch configuration
+      let config: &[u8] = any_as_u8_slice(&enclave.config);
+      crypto::hash_extend(&mut hash_ctx, config, mem::size_of::<enclave::enclave_config>());
+
+    }
+
+    // hash the entry points of the threads in use, the same on every profile and XLEN
+    for tid in 0..enclave.config.max_threads as usize {
+      crypto::hash_extend(&mut hash_ctx, &(enclave.thread_entries[tid] as u64).to_le_bytes(), mem::size_of::<u64>());
     }
     
     let runtime_max_seen: usize = 0;
     let user_max_seen: usize = 0;
   
     // hash the epm contents including the virtual addresses
-    let valid: i32 = validate_and_hash_epm(&mut hash_ctx, ptlevel, enclave.encl_satp << page::RISCV_PGSHIFT, 0, 0, enclave, &mut runtime_max_seen, &mut user_max_seen);
-  
-    if valid == -1 {
-      return ERROR::SBI_ERR_SM_ENCLAVE_ILLEGAL_PTE;
-    }
+    validate_and_hash_epm(&mut hash_ctx, ptlevel, enclave.encl_satp << page::RISCV_PGSHIFT, 0, 0, enclave, &mut runtime_max_seen, &mut user_max_seen)?;
   
     crypto::hash_finalize(&mut enclave.hash, &mut hash_ctx); // crypto.rs
   
-    return ERROR::SBI_ERR_SM_ENCLAVE_SUCCESS;
+    return Ok(());
   }
 
-fn validate_and_hash_epm(hash_ctx: &mut crypto::hash_ctx, level: i32, tb: pte_t, vaddr: usize, contiguous: i32, encl: &mut enclave::enclave, runtime_max_seen: &mut usize, user_max_seen: &mut usize) -> i32 {
+/*
+ * Checks the optional signer signature over the enclave measurement.
+ * On success the enclave records the hash of the signer public key,
+ * which signer-bound sealing keys are derived from.
+ */
+pub fn verify_enclave_signer(enclave: &mut enclave::enclave, public_key: &[u8], signature: &[u8]) -> Result<(), SmError> {
+  if public_key.iter().all(|b| *b == 0) {
+    enclave.signer = [0; crypto::MDSIZE];
+    return Ok(());
+  }
+
+  if !crypto::verify(signature, &enclave.hash, public_key) {
+    return Err(SmError::IllegalArgument);
+  }
+
+  let hash_ctx: crypto::hash_ctx;
+  crypto::hash_init(&mut hash_ctx);
+  crypto::hash_extend(&mut hash_ctx, public_key, crypto::PUBLIC_KEY_SIZE);
+  crypto::hash_finalize(&mut enclave.signer, &mut hash_ctx);
+  enclave.sign.copy_from_slice(&signature[..crypto::SIGNATURE_SIZE]);
+
+  return Ok(());
+}
+
+/* PMP permission of the UTM region holding `phys_addr`, None outside UTM */
+fn utm_perm_of(encl: &enclave::enclave, phys_addr: usize) -> Option<u8> {
+  for region in encl.regions.iter() {
+    if region.region_type != enclave::enclave_region_type::REGION_UTM {
+      continue;
+    }
+    let start: usize = pmp::pmp_region_get_addr(region.pmp_rid) as usize;
+    let size: usize = pmp::pmp_region_get_size(region.pmp_rid) as usize;
+    if phys_addr >= start && phys_addr < start + size {
+      return Some(region.perm);
+    }
+  }
+  return None;
+}
+
+fn validate_and_hash_epm(hash_ctx: &mut crypto::hash_ctx, level: i32, tb: pte_t, vaddr: usize, contiguous: i32, encl: &mut enclave::enclave, runtime_max_seen: &mut usize, user_max_seen: &mut usize) -> Result<i32, SmError> {
       
   let walk: pte_t;
   let i: i32;
@@ -52,15 +98,10 @@ fn validate_and_hash_epm(hash_ctx: &mut crypto::hash_ctx, level: i32, tb: pte_t,
   //TODO check for failures
   let epm_start: usize;
   let epm_size: usize;
-  let utm_start: usize;
-  let utm_size: usize;
 
   let idx: i32 = enclave::get_enclave_region_index(encl.eid, enclave::enclave_region_type::REGION_EPM);
   epm_start = pmp::pmp_region_get_addr(encl.regions[idx as usize].pmp_rid) as usize;
   epm_size = pmp::pmp_region_get_size(encl.regions[idx as usize].pmp_rid) as usize;
-  idx = enclave::get_enclave_region_index(encl.eid, enclave::enclave_region_type::REGION_UTM);
-  utm_start = pmp::pmp_region_get_addr(encl.regions[idx as usize].pmp_rid) as usize;
-  utm_size = pmp::pmp_region_get_size(encl.regions[idx as usize].pmp_rid) as usize;
 
   /* iterate over PTEs */
   walk = tb;
@@ -78,11 +119,12 @@ fn validate_and_hash_epm(hash_ctx: &mut crypto::hash_ctx, level: i32, tb: pte_t,
 
     /* Check for blatently invalid mappings */
     let map_in_epm: bool = phys_addr >= epm_start && phys_addr < epm_start + epm_size;
-    let map_in_utm: bool = phys_addr >= utm_start && phys_addr < utm_start + utm_size;
+    let utm_perm: Option<u8> = utm_perm_of(encl, phys_addr);
+    let map_in_utm: bool = utm_perm.is_some();
 
     /* EPM may map anything, UTM may not map pgtables */
     if !map_in_epm && (!map_in_utm || level != 1) {
-      return -1;
+      return Err(SmError::IllegalPte);
     }
 
     /* propagate the highest bit of the VA */
@@ -125,18 +167,28 @@ fn validate_and_hash_epm(hash_ctx: &mut crypto::hash_ctx, level: i32, tb: pte_t,
 
       /* Validate U bit */
       if in_user && (walk & page::PTE_U) == 0 {
-        return -1;
+        return Err(SmError::IllegalPte);
+      }
+
+      /* UTM pages are never executable and only writable if their region is */
+      if let Some(perm) = utm_perm {
+        if walk & page::PTE_X != 0 {
+          return Err(SmError::IllegalPte);
+        }
+        if walk & page::PTE_W != 0 && perm & opensbi::PMP_W == 0 {
+          return Err(SmError::IllegalPte);
+        }
       }
 
       /* If the vaddr is in UTM, the paddr must be in UTM */
       if va_start >= encl.params.untrusted_ptr as usize && va_start < (encl.params.untrusted_ptr + encl.params.untrusted_size) as usize && !map_in_utm {
-        return -1;
+        return Err(SmError::IllegalPte);
       }
 
       /* Do linear mapping validation */
       if in_runtime {
         if phys_addr <= *runtime_max_seen {
-          return -1;
+          return Err(SmError::IllegalPte);
         }
         else {
           *runtime_max_seen = phys_addr;
@@ -144,7 +196,7 @@ fn validate_and_hash_epm(hash_ctx: &mut crypto::hash_ctx, level: i32, tb: pte_t,
       }
       else if in_user {
         if phys_addr <= *user_max_seen {
-          return -1;
+          return Err(SmError::IllegalPte);
         }
         else {
           *user_max_seen = phys_addr;
@@ -155,7 +207,7 @@ fn validate_and_hash_epm(hash_ctx: &mut crypto::hash_ctx, level: i32, tb: pte_t,
       }
       else {
         //printm("BAD GENERIC MAP %x %x %x\n", in_runtime, in_user, map_in_utm);
-        return -1;
+        return Err(SmError::IllegalPte);
       }
   
       /* Page is valid, add it to the hash */
@@ -170,7 +222,7 @@ fn validate_and_hash_epm(hash_ctx: &mut crypto::hash_ctx, level: i32, tb: pte_t,
     }
     else {
       /* otherwise, recurse on a lower level */
-      contiguous = validate_and_hash_epm(hash_ctx,
+      let ret: Result<i32, SmError> = validate_and_hash_epm(hash_ctx,
                         level - 1,
                         phys_addr,
                         vpn,
@@ -178,7 +230,10 @@ fn validate_and_hash_epm(hash_ctx: &mut crypto::hash_ctx, level: i32, tb: pte_t,
                         encl,
                         runtime_max_seen,
                         user_max_seen);
-      if contiguous == -1 {
+      if let Ok(c) = ret {
+        contiguous = c;
+      }
+      else {
         println!("BAD MAP: {}->{} epm {} {} uer {} {}\n",
         va_start, phys_addr, 
         //in_runtime,
@@ -187,11 +242,11 @@ fn validate_and_hash_epm(hash_ctx: &mut crypto::hash_ctx, level: i32, tb: pte_t,
         0,
         //in_user,
         encl.pa_params.user_base);
-        return -1;
+        return ret;
       }
     }
     walk += 1;
   }
   
-  return contiguous;
+  return Ok(contiguous);
 }
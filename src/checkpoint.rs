@@ -0,0 +1,341 @@
+use crate::error_code::SmError;
+use crate::platform::storage_slot;
+use crate::storage::storage_backend;
+use crate::storage;
+use crate::crypto;
+use crate::opensbi;
+use crate::sm;
+
+use std::mem;
+
+/*
+ * Sealed blobs for enclave checkpoints.
+ * A blob carries an opaque state block and a memory range, encrypted and
+ * MAC'd under keys derived from the SM key, so only this SM on this
+ * device can open it. There is no entropy source for a nonce, so the
+ * MAC of the plaintext doubles as the IV (SIV); sealing the same image
+ * twice gives the same blob and nothing more. Callers with keys of their
+ * own, such as migration, use the *_with_keys variants.
+ *
+ *   | checkpoint_header | state | memory |
+ *
+ * A blob alone could be opened any number of times. Callers that must
+ * not fork what they seal bind a ticket into the state: a persistent
+ * slot holding the identity the blob was taken of and a sequence number.
+ * Opening for real redeems the ticket, so the blob restores once, even
+ * across reboots of the host.
+ */
+
+const CHECKPOINT_MAGIC: u64 = 0x4b53_434b_5054_0001;
+const CHECKPOINT_KEY_INFO: &[u8] = b"keystone-checkpoint";
+
+/* a cipher key and a MAC key */
+pub const CHECKPOINT_KEYS_SIZE: usize = 2 * crypto::MDSIZE;
+
+const CHECKPOINT_TICKETS_MAX: usize = 8;
+
+/* in_use of a ticket record */
+const TICKET_FREE: u64 = 0;
+const TICKET_ISSUED: u64 = 1;
+
+/* sealed into a blob, a zero seq is no ticket */
+#[derive(Clone, Copy)]
+pub struct checkpoint_ticket {
+  pub slot: u64,
+  pub seq: u64
+}
+
+impl checkpoint_ticket {
+  pub fn new() -> Self {
+    Self {
+      slot: 0,
+      seq: 0
+    }
+  }
+}
+
+/* a freed slot keeps its seq, so the next ticket from it differs */
+struct ticket_record {
+  in_use: u64,
+  seq: u64,
+  owner: [u8; crypto::MDSIZE]
+}
+
+impl ticket_record {
+  pub fn new() -> Self {
+    Self {
+      in_use: TICKET_FREE,
+      seq: 0,
+      owner: [0; crypto::MDSIZE]
+    }
+  }
+}
+
+static ticket_lock: opensbi::spinlock_t = opensbi::spinlock_t::new();
+
+static mut tickets_reserved: [bool; CHECKPOINT_TICKETS_MAX] = [false; CHECKPOINT_TICKETS_MAX];
+
+static mut backend: storage::sm_storage = storage::sm_storage::new();
+
+pub struct checkpoint_header {
+  magic: u64,
+  state_size: u64,
+  mem_size: u64,
+  tag: [u8; crypto::MDSIZE] // MAC of header fields and plaintext, IV of the cipher
+}
+
+impl checkpoint_header {
+  pub fn new() -> Self {
+    Self {
+      magic: 0,
+      state_size: 0,
+      mem_size: 0,
+      tag: [0; crypto::MDSIZE]
+    }
+  }
+}
+
+unsafe fn any_as_u8_slice<T: Sized>(p: &T) -> &[u8] {
+  ::std::slice::from_raw_parts(
+      (p as *const T) as *const u8,
+      ::std::mem::size_of::<T>(),
+  )
+}
+
+unsafe fn any_as_u8_slice_mut<T: Sized>(p: &mut T) -> &mut [u8] {
+  ::std::slice::from_raw_parts_mut(
+      (p as *mut T) as *mut u8,
+      ::std::mem::size_of::<T>(),
+  )
+}
+
+fn load_ticket(slot: usize, record: &mut ticket_record) -> Result<(), SmError> {
+  unsafe {
+    return backend.read(storage_slot::SLOT_CHECKPOINTS_BASE + slot, any_as_u8_slice_mut(record));
+  }
+}
+
+fn store_ticket(slot: usize, record: &ticket_record) -> Result<(), SmError> {
+  unsafe {
+    return backend.write(storage_slot::SLOT_CHECKPOINTS_BASE + slot, any_as_u8_slice(record));
+  }
+}
+
+/* the record of `ticket` as issued to `owner`, ticket_lock must be held */
+fn load_issued(ticket: &checkpoint_ticket, owner: &[u8], record: &mut ticket_record) -> Result<(), SmError> {
+  if ticket.seq == 0 || ticket.slot as usize >= CHECKPOINT_TICKETS_MAX {
+    return Err(SmError::CheckpointInvalid);
+  }
+  if load_ticket(ticket.slot as usize, record).is_err() {
+    return Err(SmError::CheckpointInvalid);
+  }
+  if record.in_use != TICKET_ISSUED || record.seq != ticket.seq || record.owner[..] != owner[..crypto::MDSIZE] {
+    return Err(SmError::CheckpointInvalid);
+  }
+  return Ok(());
+}
+
+/* issues and persists a ticket for a blob of the enclave measured `owner` */
+pub fn ticket_issue(owner: &[u8]) -> Result<checkpoint_ticket, SmError> {
+  let record: ticket_record = ticket_record::new();
+  let ret: Result<checkpoint_ticket, SmError> = Err(SmError::NoFreeResource);
+
+  opensbi::spin_lock(&mut ticket_lock);
+  for slot in 0..CHECKPOINT_TICKETS_MAX {
+    // an unreadable slot may hold a ticket, leave it be
+    if load_ticket(slot, &mut record).is_err() || record.in_use != TICKET_FREE {
+      continue;
+    }
+    record.in_use = TICKET_ISSUED;
+    record.seq = record.seq.wrapping_add(1).max(1);
+    record.owner.copy_from_slice(&owner[..crypto::MDSIZE]);
+    ret = store_ticket(slot, &record).map(|_| checkpoint_ticket { slot: slot as u64, seq: record.seq });
+    break;
+  }
+  opensbi::spin_unlock(&mut ticket_lock);
+  return ret;
+}
+
+/* takes back a ticket whose blob was never handed out */
+pub fn ticket_withdraw(ticket: &checkpoint_ticket, owner: &[u8]) -> Result<(), SmError> {
+  let record: ticket_record = ticket_record::new();
+
+  opensbi::spin_lock(&mut ticket_lock);
+  let ret: Result<(), SmError> = load_issued(ticket, owner, &mut record).and_then(|_| {
+    record.in_use = TICKET_FREE;
+    return store_ticket(ticket.slot as usize, &record);
+  });
+  opensbi::spin_unlock(&mut ticket_lock);
+  return ret;
+}
+
+/*
+ * Checks that `ticket` was issued to `owner` and not redeemed, and
+ * reserves it: until ticket_redeem or ticket_release no one else can.
+ */
+pub fn ticket_reserve(ticket: &checkpoint_ticket, owner: &[u8]) -> Result<(), SmError> {
+  let record: ticket_record = ticket_record::new();
+
+  opensbi::spin_lock(&mut ticket_lock);
+  let ret: Result<(), SmError> = load_issued(ticket, owner, &mut record).and_then(|_| {
+    if tickets_reserved[ticket.slot as usize] {
+      return Err(SmError::CheckpointInvalid);
+    }
+    tickets_reserved[ticket.slot as usize] = true;
+    return Ok(());
+  });
+  opensbi::spin_unlock(&mut ticket_lock);
+  return ret;
+}
+
+/* redeems `ticket`, reserved by ticket_reserve, for good */
+pub fn ticket_redeem(ticket: &checkpoint_ticket) -> Result<(), SmError> {
+  let record: ticket_record = ticket_record::new();
+
+  opensbi::spin_lock(&mut ticket_lock);
+  let ret: Result<(), SmError> = load_ticket(ticket.slot as usize, &mut record).and_then(|_| {
+    record.in_use = TICKET_FREE;
+    return store_ticket(ticket.slot as usize, &record);
+  });
+  if ret.is_ok() {
+    tickets_reserved[ticket.slot as usize] = false;
+  }
+  opensbi::spin_unlock(&mut ticket_lock);
+  return ret;
+}
+
+/* gives up a reservation of ticket_reserve, the ticket stays valid */
+pub fn ticket_release(ticket: &checkpoint_ticket) {
+  opensbi::spin_lock(&mut ticket_lock);
+  tickets_reserved[ticket.slot as usize] = false;
+  opensbi::spin_unlock(&mut ticket_lock);
+}
+
+/* true if a ticket issued to `owner` is not redeemed yet; unreadable slots count */
+pub fn tickets_outstanding(owner: &[u8]) -> bool {
+  let record: ticket_record = ticket_record::new();
+  let ret: bool = false;
+
+  opensbi::spin_lock(&mut ticket_lock);
+  for slot in 0..CHECKPOINT_TICKETS_MAX {
+    if load_ticket(slot, &mut record).is_err()
+      || (record.in_use != TICKET_FREE && record.owner[..] == owner[..crypto::MDSIZE]) {
+      ret = true;
+      break;
+    }
+  }
+  opensbi::spin_unlock(&mut ticket_lock);
+  return ret;
+}
+
+/* bytes taken by a blob of `state_size` and `mem_size` */
+pub fn blob_size(state_size: usize, mem_size: usize) -> usize {
+  mem::size_of::<checkpoint_header>() + state_size + mem_size
+}
+
+/* cipher key first, MAC key second */
+fn derive_keys(keys: &mut [u8; CHECKPOINT_KEYS_SIZE]) -> Result<(), SmError> {
+  if sm::sm_derive_key(keys, CHECKPOINT_KEY_INFO) != 0 {
+    return Err(SmError::UnknownError);
+  }
+  return Ok(());
+}
+
+fn compute_tag(mac_key: &[u8], header: &mut checkpoint_header, state: &[u8], mem_base: usize) {
+  let ctx: crypto::mac_ctx = crypto::mac_init(mac_key);
+
+  crypto::mac_extend(&mut ctx, &header.magic.to_le_bytes());
+  crypto::mac_extend(&mut ctx, &header.state_size.to_le_bytes());
+  crypto::mac_extend(&mut ctx, &header.mem_size.to_le_bytes());
+  crypto::mac_extend(&mut ctx, state);
+  unsafe {
+    crypto::mac_extend(&mut ctx, ::std::slice::from_raw_parts(mem_base as *const u8, header.mem_size as usize));
+  }
+  crypto::mac_finalize(&mut header.tag, &mut ctx);
+}
+
+/*
+ * Seals `state` and [mem_base, mem_base + mem_size) into the blob at
+ * `blob`, which has room for `blob_max` bytes, for this SM only.
+ * Returns the size of the blob.
+ */
+pub fn seal(blob: usize, blob_max: usize, state: &[u8], mem_base: usize, mem_size: usize) -> Result<usize, SmError> {
+  let keys: [u8; CHECKPOINT_KEYS_SIZE] = [0; CHECKPOINT_KEYS_SIZE];
+
+  derive_keys(&mut keys)?;
+  let ret: Result<usize, SmError> = seal_with_keys(&keys, blob, blob_max, state, mem_base, mem_size);
+  opensbi::sbi_memset(keys.as_ptr() as usize, 0, keys.len());
+  return ret;
+}
+
+/*
+ * Opens the blob at `blob`, sealed by seal(), into `state` and
+ * [mem_base, mem_base + mem_size). Both sizes must be the ones it was
+ * sealed with. The destination memory must be out of the host's reach,
+ * it holds plaintext before the tag is checked; it is scrubbed if the
+ * check fails.
+ */
+pub fn open(blob: usize, blob_max: usize, state: &mut [u8], mem_base: usize, mem_size: usize) -> Result<(), SmError> {
+  let keys: [u8; CHECKPOINT_KEYS_SIZE] = [0; CHECKPOINT_KEYS_SIZE];
+
+  derive_keys(&mut keys)?;
+  let ret: Result<(), SmError> = open_with_keys(&keys, blob, blob_max, state, mem_base, mem_size);
+  opensbi::sbi_memset(keys.as_ptr() as usize, 0, keys.len());
+  return ret;
+}
+
+/* seal() under `keys`, cipher key first and MAC key second */
+pub fn seal_with_keys(keys: &[u8], blob: usize, blob_max: usize, state: &[u8], mem_base: usize, mem_size: usize) -> Result<usize, SmError> {
+  let header: checkpoint_header = checkpoint_header::new();
+  let hdr_size: usize = mem::size_of::<checkpoint_header>();
+
+  if blob_size(state.len(), mem_size) > blob_max {
+    return Err(SmError::NoFreeResource);
+  }
+
+  header.magic = CHECKPOINT_MAGIC;
+  header.state_size = state.len() as u64;
+  header.mem_size = mem_size as u64;
+  compute_tag(&keys[crypto::MDSIZE..], &mut header, state, mem_base);
+
+  opensbi::sbi_memcpy(blob, &header as *const checkpoint_header as usize, hdr_size);
+  crypto::keystream_xor(&keys[..crypto::MDSIZE], &header.tag, 0, state.as_ptr() as usize, blob + hdr_size, state.len());
+  crypto::keystream_xor(&keys[..crypto::MDSIZE], &header.tag, state.len(), mem_base, blob + hdr_size + state.len(), mem_size);
+
+  return Ok(blob_size(state.len(), mem_size));
+}
+
+/* open() under `keys`, cipher key first and MAC key second */
+pub fn open_with_keys(keys: &[u8], blob: usize, blob_max: usize, state: &mut [u8], mem_base: usize, mem_size: usize) -> Result<(), SmError> {
+  let header: checkpoint_header = checkpoint_header::new();
+  let check: checkpoint_header = checkpoint_header::new();
+  let hdr_size: usize = mem::size_of::<checkpoint_header>();
+  let diff: u8 = 0;
+
+  if blob_size(state.len(), mem_size) > blob_max {
+    return Err(SmError::CheckpointInvalid);
+  }
+  // the blob is in host memory, read every field exactly once
+  opensbi::sbi_memcpy(&header as *const checkpoint_header as usize, blob, hdr_size);
+  if header.magic != CHECKPOINT_MAGIC || header.state_size != state.len() as u64 || header.mem_size != mem_size as u64 {
+    return Err(SmError::CheckpointInvalid);
+  }
+
+  crypto::keystream_xor(&keys[..crypto::MDSIZE], &header.tag, 0, blob + hdr_size, state.as_ptr() as usize, state.len());
+  crypto::keystream_xor(&keys[..crypto::MDSIZE], &header.tag, state.len(), blob + hdr_size + state.len(), mem_base, mem_size);
+
+  check.magic = header.magic;
+  check.state_size = header.state_size;
+  check.mem_size = header.mem_size;
+  compute_tag(&keys[crypto::MDSIZE..], &mut check, state, mem_base);
+
+  for i in 0..crypto::MDSIZE {
+    diff |= header.tag[i] ^ check.tag[i];
+  }
+  if diff != 0 {
+    opensbi::sbi_memset(state.as_ptr() as usize, 0, state.len());
+    opensbi::sbi_memset(mem_base, 0, mem_size);
+    return Err(SmError::CheckpointInvalid);
+  }
+  return Ok(());
+}
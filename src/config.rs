@@ -0,0 +1,117 @@
+/*
+ * Build-time limits of the security monitor.
+ *
+ * The default profile keeps the limits of the reference Keystone SM.
+ * Enable the `sm-small` feature for parts with few harts and little RAM,
+ * or `sm-large` for servers. Every profile is checked below against the
+ * hard limits of the PMP code. The two sized profiles are also checked
+ * against the whole PMP budget, so that ENCL_MAX enclaves and an enclave
+ * with every region in use always fit; on the default one, creating
+ * enclaves or regions past what the PMP holds fails at run time.
+ */
+
+#[cfg(all(feature = "sm-small", feature = "sm-large"))]
+compile_error!("features `sm-small` and `sm-large` are mutually exclusive");
+
+#[cfg(feature = "sm-small")]
+mod profile {
+  pub const ENCL_MAX: usize = 3;
+  pub const ENCLAVE_REGIONS_MAX: usize = 4;
+  pub const MAX_ENCL_THREADS: usize = 2;
+  pub const MAX_HARTS: usize = 2;
+  pub const PMP_N_REG: usize = 8;
+  pub const PMP_MAX_N_REGION: usize = 8;
+  pub const ATTEST_DATA_MAXLEN: usize = 256;
+  pub const SWAP_SLOTS_MAX: usize = 16;
+}
+
+#[cfg(feature = "sm-large")]
+mod profile {
+  pub const ENCL_MAX: usize = 7;
+  pub const ENCLAVE_REGIONS_MAX: usize = 8;
+  pub const MAX_ENCL_THREADS: usize = 16;
+  pub const MAX_HARTS: usize = 64;
+  pub const PMP_N_REG: usize = 16;
+  pub const PMP_MAX_N_REGION: usize = 32;
+  pub const ATTEST_DATA_MAXLEN: usize = 4096;
+  pub const SWAP_SLOTS_MAX: usize = 256;
+}
+
+#[cfg(not(any(feature = "sm-small", feature = "sm-large")))]
+mod profile {
+  pub const ENCL_MAX: usize = 16;
+  pub const ENCLAVE_REGIONS_MAX: usize = 8;
+  pub const MAX_ENCL_THREADS: usize = 8;
+  pub const MAX_HARTS: usize = 16;
+  pub const PMP_N_REG: usize = 8;
+  pub const PMP_MAX_N_REGION: usize = 16;
+  pub const ATTEST_DATA_MAXLEN: usize = 1024;
+  pub const SWAP_SLOTS_MAX: usize = 64;
+}
+
+pub use profile::*;
+
+/* regions reserved at boot for the SM itself and the untrusted OS */
+pub const SM_RESERVED_REGIONS: usize = 2;
+
+/*
+ * Longest an enclave with an execution budget runs before the SM looks at
+ * it again, in timer ticks. The host's own timer event is put back when
+ * the thread leaves, or left in place if it comes first.
+ */
+pub const WATCHDOG_TICKS: u64 = 100000;
+
+/* most enclave memory one destroy call scrubs, bounding its time in M-mode */
+pub const DESTROY_CHUNK: usize = 1 << 20;
+
+const _: () = assert!(ENCL_MAX > 0, "ENCL_MAX must be at least 1");
+const _: () = assert!(MAX_ENCL_THREADS > 0, "MAX_ENCL_THREADS must be at least 1");
+const _: () = assert!(MAX_HARTS > 0, "MAX_HARTS must be at least 1");
+const _: () = assert!(ATTEST_DATA_MAXLEN > 0, "ATTEST_DATA_MAXLEN must be at least 1");
+const _: () = assert!(DESTROY_CHUNK > 0, "DESTROY_CHUNK must be at least 1");
+const _: () = assert!(SWAP_SLOTS_MAX > 0, "SWAP_SLOTS_MAX must be at least 1");
+
+/* region and register bitmaps in pmp.rs are u32 */
+const _: () = assert!(PMP_MAX_N_REGION <= 32, "PMP_MAX_N_REGION exceeds the region bitmap");
+const _: () = assert!(PMP_N_REG <= 16, "RISC-V has at most 16 PMP registers");
+
+/*
+ * A running enclave needs its EPM (TOR, two registers) next to the SM
+ * (top register) and the OS (bottom register, shared with the UTM).
+ */
+const _: () = assert!(PMP_N_REG >= 4, "PMP_N_REG cannot fit the SM, the OS and an enclave");
+
+/* a single enclave with every region in use must fit next to SM and OS */
+const _: () = assert!(ENCLAVE_REGIONS_MAX >= 2, "an enclave needs an EPM and a UTM region");
+const _: () = assert!(
+  ENCLAVE_REGIONS_MAX + SM_RESERVED_REGIONS <= PMP_MAX_N_REGION,
+  "ENCLAVE_REGIONS_MAX does not fit in the PMP region budget"
+);
+
+/*
+ * Registers are taken when a region is created and held until it is
+ * freed. The SM and the OS hold one each and the main UTM shares the OS
+ * one; any other region, extra UTMs included, may be TOR and take two.
+ */
+#[cfg(any(feature = "sm-small", feature = "sm-large"))]
+mod budget {
+  use super::*;
+
+  const PMP_TOR_REGS: usize = 2;
+  const PMP_FREE_REGS: usize = PMP_N_REG - 2;
+
+  const _: () = assert!(
+    (ENCLAVE_REGIONS_MAX - 1) * PMP_TOR_REGS <= PMP_FREE_REGS,
+    "an enclave with every region in use runs out of PMP registers"
+  );
+
+  /* every enclave holds its EPM and its UTM region while it exists */
+  const _: () = assert!(
+    ENCL_MAX * 2 + SM_RESERVED_REGIONS <= PMP_MAX_N_REGION,
+    "ENCL_MAX enclaves do not fit in the PMP region budget"
+  );
+  const _: () = assert!(
+    ENCL_MAX * PMP_TOR_REGS <= PMP_FREE_REGS,
+    "ENCL_MAX enclaves run out of PMP registers"
+  );
+}
@@ -0,0 +1,263 @@
+use crate::error_code::SmError;
+use crate::platform::storage_slot;
+use crate::storage::storage_backend;
+use crate::storage;
+use crate::crypto;
+use crate::mprv;
+use crate::opensbi;
+
+use std::mem;
+
+/*
+ * Monotonic counters for rollback protection.
+ * Each counter is owned by the enclave identity (measurement) that
+ * created it, and lives in its own persistent storage slot so that it
+ * survives reboots of the host.
+ */
+
+const MAX_COUNTERS: usize = 16;
+
+/* in_use of a record, anything else is a damaged slot and never reused */
+const COUNTER_FREE: u64 = 0;
+const COUNTER_IN_USE: u64 = 1;
+
+struct counter_record {
+  in_use: u64,
+  value: u64,
+  owner: [u8; crypto::MDSIZE]
+}
+
+impl counter_record {
+  pub fn new() -> Self {
+    Self {
+      in_use: COUNTER_FREE,
+      value: 0,
+      owner: [0; crypto::MDSIZE]
+    }
+  }
+}
+
+static counter_lock: opensbi::spinlock_t = opensbi::spinlock_t::new();
+
+static mut backend: storage::sm_storage = storage::sm_storage::new();
+
+unsafe fn any_as_u8_slice<T: Sized>(p: &T) -> &[u8] {
+  ::std::slice::from_raw_parts(
+      (p as *const T) as *const u8,
+      ::std::mem::size_of::<T>(),
+  )
+}
+
+unsafe fn any_as_u8_slice_mut<T: Sized>(p: &mut T) -> &mut [u8] {
+  ::std::slice::from_raw_parts_mut(
+      (p as *mut T) as *mut u8,
+      ::std::mem::size_of::<T>(),
+  )
+}
+
+fn load_record<B: storage_backend>(store: &B, id: usize, record: &mut counter_record) -> Result<(), SmError> {
+  unsafe {
+    return store.read(storage_slot::SLOT_COUNTERS_BASE + id, any_as_u8_slice_mut(record));
+  }
+}
+
+fn store_record<B: storage_backend>(store: &mut B, id: usize, record: &counter_record) -> Result<(), SmError> {
+  unsafe {
+    return store.write(storage_slot::SLOT_COUNTERS_BASE + id, any_as_u8_slice(record));
+  }
+}
+
+/* loads counter `id` and checks that it is owned by `owner` */
+fn load_owned_record<B: storage_backend>(store: &B, id: usize, owner: &[u8], record: &mut counter_record) -> Result<(), SmError> {
+  if id >= MAX_COUNTERS {
+    return Err(SmError::CounterInvalid);
+  }
+  if load_record(store, id, record).is_err() {
+    return Err(SmError::CounterInvalid);
+  }
+  if record.in_use != COUNTER_IN_USE || record.owner[..] != owner[..crypto::MDSIZE] {
+    return Err(SmError::CounterInvalid);
+  }
+  return Ok(());
+}
+
+/*
+ * Takes the first free slot for a counter of `owner` and stores it.
+ * A slot that cannot be read or holds anything but a free record is
+ * skipped: a counter lost to a storage error must not be handed out
+ * again from zero.
+ */
+fn create_record<B: storage_backend>(store: &mut B, owner: &[u8]) -> Result<usize, SmError> {
+  let record: counter_record = counter_record::new();
+
+  for id in 0..MAX_COUNTERS {
+    if load_record(store, id, &mut record).is_err() || record.in_use != COUNTER_FREE {
+      continue;
+    }
+
+    record.in_use = COUNTER_IN_USE;
+    record.value = 0;
+    record.owner.copy_from_slice(&owner[..crypto::MDSIZE]);
+    store_record(store, id, &record)?;
+    return Ok(id);
+  }
+  return Err(SmError::NoFreeResource);
+}
+
+/* persists the next value of counter `id` and returns it */
+fn increment_record<B: storage_backend>(store: &mut B, id: usize, owner: &[u8]) -> Result<u64, SmError> {
+  let record: counter_record = counter_record::new();
+
+  load_owned_record(store, id, owner, &mut record)?;
+  record.value = match record.value.checked_add(1) {
+    Some(value) => value,
+    None => return Err(SmError::CounterOverflow)
+  };
+  store_record(store, id, &record)?;
+  return Ok(record.value);
+}
+
+fn read_record<B: storage_backend>(store: &B, id: usize, owner: &[u8]) -> Result<u64, SmError> {
+  let record: counter_record = counter_record::new();
+
+  load_owned_record(store, id, owner, &mut record)?;
+  return Ok(record.value);
+}
+
+fn destroy_record<B: storage_backend>(store: &mut B, id: usize, owner: &[u8]) -> Result<(), SmError> {
+  let record: counter_record = counter_record::new();
+
+  load_owned_record(store, id, owner, &mut record)?;
+  return store_record(store, id, &counter_record::new());
+}
+
+/* copies a counter value into the enclave */
+fn copy_counter_value(dest: usize, value: u64) -> Result<(), SmError> {
+  if mprv::copy_from_sm(dest, &value as *const u64 as usize, mem::size_of::<u64>()) != 0 {
+    return Err(SmError::IllegalArgument);
+  }
+  return Ok(());
+}
+
+pub fn counter_create(id_ptr: usize, owner: &[u8]) -> Result<(), SmError> {
+  let ret: Result<(), SmError>;
+
+  opensbi::spin_lock(&mut counter_lock);
+
+  /* stored before the id is published, and given back if it cannot be */
+  ret = create_record(&mut backend, owner).and_then(|id| {
+    let copied: Result<(), SmError> = copy_counter_value(id_ptr, id as u64);
+    if copied.is_err() {
+      let _ = destroy_record(&mut backend, id, owner);
+    }
+    return copied;
+  });
+
+  opensbi::spin_unlock(&mut counter_lock);
+  return ret;
+}
+
+pub fn counter_increment(id: usize, value_ptr: usize, owner: &[u8]) -> Result<(), SmError> {
+  let ret: Result<(), SmError>;
+
+  opensbi::spin_lock(&mut counter_lock);
+
+  /* persist first, so the enclave never observes a value that may be lost */
+  ret = increment_record(&mut backend, id, owner)
+    .and_then(|value| copy_counter_value(value_ptr, value));
+
+  opensbi::spin_unlock(&mut counter_lock);
+  return ret;
+}
+
+pub fn counter_read(id: usize, value_ptr: usize, owner: &[u8]) -> Result<(), SmError> {
+  let ret: Result<(), SmError>;
+
+  opensbi::spin_lock(&mut counter_lock);
+
+  ret = read_record(&backend, id, owner)
+    .and_then(|value| copy_counter_value(value_ptr, value));
+
+  opensbi::spin_unlock(&mut counter_lock);
+  return ret;
+}
+
+pub fn counter_destroy(id: usize, owner: &[u8]) -> Result<(), SmError> {
+  let ret: Result<(), SmError>;
+
+  opensbi::spin_lock(&mut counter_lock);
+
+  ret = destroy_record(&mut backend, id, owner);
+
+  opensbi::spin_unlock(&mut counter_lock);
+  return ret;
+}
+
+#[cfg(all(test, feature = "file-storage"))]
+mod tests {
+  use super::*;
+  use crate::storage::file_storage;
+
+  const OWNER: [u8; crypto::MDSIZE] = [0xa5; crypto::MDSIZE];
+  const OTHER: [u8; crypto::MDSIZE] = [0x5a; crypto::MDSIZE];
+
+  fn fresh_storage(dir: &'static str) -> file_storage {
+    let _ = std::fs::remove_dir_all(dir);
+    return file_storage::in_dir(dir);
+  }
+
+  #[test]
+  fn create_increment_read_destroy() {
+    let store: file_storage = fresh_storage("target/counter-test-lifecycle");
+
+    let id: usize = create_record(&mut store, &OWNER).unwrap();
+    assert_eq!(read_record(&store, id, &OWNER), Ok(0));
+    assert_eq!(increment_record(&mut store, id, &OWNER), Ok(1));
+    assert_eq!(increment_record(&mut store, id, &OWNER), Ok(2));
+    assert_eq!(read_record(&store, id, &OWNER), Ok(2));
+
+    // owned by the measurement that created it
+    assert_eq!(read_record(&store, id, &OTHER), Err(SmError::CounterInvalid));
+    assert_eq!(increment_record(&mut store, id, &OTHER), Err(SmError::CounterInvalid));
+    assert_eq!(destroy_record(&mut store, id, &OTHER), Err(SmError::CounterInvalid));
+
+    assert_eq!(destroy_record(&mut store, id, &OWNER), Ok(()));
+    assert_eq!(read_record(&store, id, &OWNER), Err(SmError::CounterInvalid));
+    assert_eq!(read_record(&store, MAX_COUNTERS, &OWNER), Err(SmError::CounterInvalid));
+
+    // the slot is free again
+    assert_eq!(create_record(&mut store, &OTHER), Ok(id));
+  }
+
+  #[test]
+  fn counters_persist() {
+    let store: file_storage = fresh_storage("target/counter-test-persist");
+
+    let id: usize = create_record(&mut store, &OWNER).unwrap();
+    assert_eq!(increment_record(&mut store, id, &OWNER), Ok(1));
+
+    // as after a reboot
+    let store: file_storage = file_storage::in_dir("target/counter-test-persist");
+    assert_eq!(read_record(&store, id, &OWNER), Ok(1));
+    assert_eq!(increment_record(&mut store, id, &OWNER), Ok(2));
+  }
+
+  #[test]
+  fn slots_run_out() {
+    let store: file_storage = fresh_storage("target/counter-test-full");
+
+    for id in 0..MAX_COUNTERS {
+      assert_eq!(create_record(&mut store, &OWNER), Ok(id));
+    }
+    assert_eq!(create_record(&mut store, &OWNER), Err(SmError::NoFreeResource));
+  }
+
+  #[test]
+  fn damaged_slot_is_not_reused() {
+    let store: file_storage = fresh_storage("target/counter-test-damaged");
+
+    // a record of the wrong size cannot be read
+    assert!(store.write(storage_slot::SLOT_COUNTERS_BASE, &[1, 2, 3]).is_ok());
+    assert_eq!(create_record(&mut store, &OWNER), Ok(1));
+  }
+}
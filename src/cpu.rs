@@ -1,21 +1,28 @@
 use crate::enclave;
 use crate::opensbi;
+use crate::config;
 
 struct cpu_state {
   is_enclave: i32,
-  eid: enclave::enclave_id
+  eid: enclave::enclave_id,
+  tid: usize,
+  entries: usize, // times a thread took this hart, see cpu_interrupt_enclave
+  host_deadline: u64 // host timer event put off by the watchdog, 0 for none
 }
 
 impl cpu_state {
   pub fn new() -> Self {
     Self {
       is_enclave: 0,
-      eid: 0
+      eid: 0,
+      tid: 0,
+      entries: 0,
+      host_deadline: 0
     }
   }
 }
 
-const MAX_HARTS: usize = 16;
+const MAX_HARTS: usize = config::MAX_HARTS;
 
 static cpus: [cpu_state;MAX_HARTS] = [cpu_state::new();MAX_HARTS];
 
@@ -27,11 +34,44 @@ pub fn cpu_get_enclave_id() -> usize {
   return cpus[opensbi::csr_read("mhartid")].eid;
 }
 
-pub fn cpu_enter_enclave_context(eid: enclave::enclave_id) {
+pub fn cpu_get_thread_id() -> usize {
+  return cpus[opensbi::csr_read("mhartid")].tid;
+}
+
+pub fn cpu_enter_enclave_context(eid: enclave::enclave_id, tid: usize) {
   cpus[opensbi::csr_read("mhartid")].is_enclave = 1;
   cpus[opensbi::csr_read("mhartid")].eid = eid;
+  cpus[opensbi::csr_read("mhartid")].tid = tid;
+  cpus[opensbi::csr_read("mhartid")].entries = cpus[opensbi::csr_read("mhartid")].entries.wrapping_add(1);
 }
 
 pub fn cpu_exit_enclave_context() {
   cpus[opensbi::csr_read("mhartid")].is_enclave = 0;
 }
+
+/*
+ * Interrupts every hart running enclave `eid`, the interrupt stops the
+ * thread on it. `marks` holds the entries of each hart when it was last
+ * interrupted, start with zeros; a hart is interrupted again only once a
+ * thread entered it after that.
+ */
+pub fn cpu_interrupt_enclave(eid: enclave::enclave_id, marks: &mut [usize; MAX_HARTS]) {
+  for hartid in 0..MAX_HARTS {
+    if cpus[hartid].is_enclave != 0 && cpus[hartid].eid == eid && cpus[hartid].entries != marks[hartid] {
+      marks[hartid] = cpus[hartid].entries;
+      opensbi::ipi_raw_send(hartid);
+    }
+  }
+}
+
+/* remembers the host timer event the watchdog replaces on this hart */
+pub fn cpu_save_host_deadline(deadline: u64) {
+  cpus[opensbi::csr_read("mhartid")].host_deadline = deadline;
+}
+
+/* the host timer event saved on this hart, 0 for none, forgets it */
+pub fn cpu_take_host_deadline() -> u64 {
+  let deadline: u64 = cpus[opensbi::csr_read("mhartid")].host_deadline;
+  cpus[opensbi::csr_read("mhartid")].host_deadline = 0;
+  return deadline;
+}
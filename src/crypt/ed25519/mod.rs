@@ -88,3 +88,56 @@ pub fn verify(signature: &[u8], message: &[u8], public_key: &[u8]) -> bool {
     }
 }
 
+/* X25519 on the Montgomery form of an ed25519 key pair */
+pub fn key_exchange(shared_secret: &mut [u8], public_key: &[u8], private_key: &[u8]) {
+    let mut e: [u8; 32] = [0; 32];
+    e.copy_from_slice(&private_key[..32]);
+    e[0] &= 248;
+    e[31] &= 63;
+    e[31] |= 64;
+
+    // montgomery x = (edwards y + 1) / (1 - edwards y)
+    let y = Fe::from(&public_key[..32]);
+    let one = Fe::new_one();
+    let x1 = Fe::mul(&Fe::add(&y, &one), &Fe::invert(&Fe::sub(&one, &y)));
+
+    let mut x2 = Fe::new_one();
+    let mut z2 = Fe::new();
+    let mut x3 = x1;
+    let mut z3 = Fe::new_one();
+    let mut swap = false;
+
+    for pos in (0..255).rev() {
+        let b = (e[pos / 8] >> (pos & 7)) & 1 == 1;
+        swap ^= b;
+        Fe::cswap(&mut x2, &mut x3, swap);
+        Fe::cswap(&mut z2, &mut z3, swap);
+        swap = b;
+
+        let mut tmp0 = Fe::sub(&x3, &z3);
+        let mut tmp1 = Fe::sub(&x2, &z2);
+        x2 = Fe::add(&x2, &z2);
+        z2 = Fe::add(&x3, &z3);
+        z3 = Fe::mul(&tmp0, &x2);
+        z2 = Fe::mul(&z2, &tmp1);
+        tmp0 = Fe::sq(&tmp1);
+        tmp1 = Fe::sq(&x2);
+        x3 = Fe::add(&z3, &z2);
+        z2 = Fe::sub(&z3, &z2);
+        x2 = Fe::mul(&tmp1, &tmp0);
+        tmp1 = Fe::sub(&tmp1, &tmp0);
+        z2 = Fe::sq(&z2);
+        z3 = Fe::mul121666(&tmp1);
+        x3 = Fe::sq(&x3);
+        tmp0 = Fe::add(&tmp0, &z3);
+        z3 = Fe::mul(&x1, &z2);
+        z2 = Fe::mul(&tmp1, &tmp0);
+    }
+
+    Fe::cswap(&mut x2, &mut x3, swap);
+    Fe::cswap(&mut z2, &mut z3, swap);
+
+    let shared: [u8; 32] = Fe::mul(&x2, &Fe::invert(&z2)).into();
+    shared_secret[..32].copy_from_slice(&shared);
+    e.iter_mut().for_each(|b| *b = 0);
+}
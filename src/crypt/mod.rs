@@ -1,4 +1,4 @@
 pub mod sha3;
-mod hmac_sha3;
+pub mod hmac_sha3;
 pub mod hkdf_sha3;
 pub mod ed25519;
\ No newline at end of file
@@ -1,14 +1,18 @@
 use crate::crypt::hkdf_sha3;
 use crate::crypt::ed25519;
 use crate::crypt::sha3;
+use crate::crypt::hmac_sha3;
 use crate::page;
 
 pub const MDSIZE: usize = 64;
 pub const PUBLIC_KEY_SIZE: usize = 32;
 pub const SIGNATURE_SIZE: usize = 64;
 pub const PRIVATE_KEY_SIZE: usize = 64;
+/* maximum output length of kdf() */
+pub const KDF_MAX_OKM_SIZE: usize = 255 * MDSIZE;
 
 pub type hash_ctx = sha3::Sha3Ctx;
+pub type mac_ctx = hmac_sha3::HmacSha3Ctx;
 
 pub fn hash_init(hash_ctx: &mut hash_ctx) {
   hash_ctx = &mut sha3::Sha3Ctx::new(MDSIZE);
@@ -26,10 +30,65 @@ pub fn hash_finalize(md: &mut [u8], hash_ctx: &mut hash_ctx) {
   hash_ctx.finalize(md);
 }
 
+pub fn create_keypair(private_key: &mut [u8], seed: &[u8]) -> [u8; PUBLIC_KEY_SIZE] {
+    return ed25519::create_keypair(private_key, seed);
+}
+
 pub fn sign(data: &[u8], public_key: &[u8], private_key: &[u8]) -> [u8; 64] {
     return ed25519::sign(data, public_key, private_key);
 }
 
+pub fn verify(signature: &[u8], data: &[u8], public_key: &[u8]) -> bool {
+    return ed25519::verify(signature, data, public_key);
+}
+
+/* shared secret of our ed25519 private key and the peer's public key */
+pub fn key_exchange(shared_secret: &mut [u8], public_key: &[u8], private_key: &[u8]) {
+    ed25519::key_exchange(shared_secret, public_key, private_key);
+}
+
+pub fn mac(key: &mut [u8], data: &[u8], mac: &mut [u8]) {
+    hmac_sha3::hmac_sha3(key, data, mac);
+}
+
+pub fn mac_init(key: &[u8]) -> mac_ctx {
+    return hmac_sha3::HmacSha3Ctx::new(key);
+}
+
+pub fn mac_extend(mac_ctx: &mut mac_ctx, data: &[u8]) {
+    mac_ctx.update(data);
+}
+
+pub fn mac_finalize(mac: &mut [u8], mac_ctx: &mut mac_ctx) {
+    mac_ctx.finalize(mac);
+}
+
+/*
+ * XORs `len` bytes from `src` into `dest` with the keystream of `iv`,
+ * starting `pos` bytes into the stream. Block n of the stream is
+ * mac(key, iv || n), so an (key, iv) pair must never be used twice on
+ * different data.
+ */
+pub fn keystream_xor(key: &[u8], iv: &[u8], pos: usize, src: usize, dest: usize, len: usize) {
+    let mut block: [u8; MDSIZE] = [0; MDSIZE];
+    let mut block_idx: usize = usize::MAX;
+
+    for i in 0..len {
+        let n: usize = (pos + i) / MDSIZE;
+        if n != block_idx {
+            let mut ctx: mac_ctx = mac_init(key);
+            mac_extend(&mut ctx, iv);
+            mac_extend(&mut ctx, &(n as u64).to_le_bytes());
+            mac_finalize(&mut block, &mut ctx);
+            block_idx = n;
+        }
+        unsafe {
+            *((dest + i) as *mut u8) = *((src + i) as *const u8) ^ block[(pos + i) % MDSIZE];
+        }
+    }
+    block.iter_mut().for_each(|b| *b = 0);
+}
+
 pub fn kdf(salt: &mut [u8], ikm: &[u8], info: &[u8], okm: &mut [u8]) -> i32 {
     return hkdf_sha3::hkdf_sha3_512(salt, ikm, info, okm) as i32;
 }
\ No newline at end of file
@@ -1,14 +1,22 @@
-use crate::error_code::ERROR;
+use crate::error_code::SmError;
 use crate::mprv::copy_to_sm;
 use crate::sm::keystone_sbi_create;
+use crate::sm::keystone_sbi_utm_region;
+use crate::sm::keystone_sbi_clone;
+use crate::sm::keystone_sbi_regs;
+use crate::sm::keystone_sbi_fault;
+use crate::sm::keystone_sbi_stats;
+use crate::sm::keystone_sbi_enclave_info;
 use crate::pmp;
 use crate::sm::runtime_va_params_t;
 use crate::sm::runtime_pa_params;
 use crate::pmp::region_id;
 use crate::thread::thread_state;
+use crate::thread::thread_status;
 use crate::platform::platform_enclave_data;
 use crate::pmp::pmp_priority;
 use crate::thread;
+use crate::assert;
 use crate::attest;
 use crate::crypto;
 use crate::mprv;
@@ -18,21 +26,63 @@ use crate::cpu;
 use crate::opensbi;
 use crate::sbi_trap;
 use crate::platform;
+use crate::shm;
+use crate::mailbox;
+use crate::checkpoint;
+use crate::migrate;
+use crate::swap;
+use crate::config;
 
 use std::mem;
 
 pub type enclave_id = usize;
 
-const ENCLAVE_REGIONS_MAX: usize = 8;
-const MAX_ENCL_THREADS: usize = 1;
-const ENCL_MAX: usize = 16;
+const ENCLAVE_REGIONS_MAX: usize = config::ENCLAVE_REGIONS_MAX;
+pub const MAX_ENCL_THREADS: usize = config::MAX_ENCL_THREADS;
+const ENCL_MAX: usize = config::ENCL_MAX;
 
 pub const STOP_TIMER_INTERRUPT: usize = 0;
 const STOP_EDGE_CALL_HOST: usize = 1;
 const STOP_EXIT_ENCLAVE: usize = 2;
+pub const STOP_DEBUG_TRAP: usize = 3;
+
+/* how resume_thread lets the thread run */
+const RESUME_NORMAL: usize = 0;
+const RESUME_DEBUG: usize = 1; // breakpoints stop the thread, debug enclaves only
+const RESUME_STEP: usize = 2; // and so does the next instruction
+
+const ATTEST_DATA_MAXLEN: usize = config::ATTEST_DATA_MAXLEN;
+const SEALING_KEY_MAXLEN: usize = crypto::KDF_MAX_OKM_SIZE;
+const KEY_IDENT_MAXLEN: usize = 64;
+const LOCAL_REPORT_DATA_LEN: usize = 64;
+const REPORT_KEY_SIZE: usize = crypto::MDSIZE;
+const RELEASED_RANGES_MAX: usize = 8;
+pub const EXTRA_UTM_MAX: usize = 3; // fixed by keystone_sbi_create
+/* extra UTMs take the region slots after the EPM and the main UTM */
+const EXTRA_UTM_USABLE: usize = if ENCLAVE_REGIONS_MAX - 2 < EXTRA_UTM_MAX {
+  ENCLAVE_REGIONS_MAX - 2
+} else {
+  EXTRA_UTM_MAX
+};
+/* the platform cannot tell when the host timer is due, see platform_timer_deadline */
+const HOST_DEADLINE_UNKNOWN: u64 = u64::MAX;
+const REPORT_KEY_INFO: &[u8] = b"keystone-local-report";
+const SEALING_KEY_INFO: &[u8] = b"keystone-seal";
+
+/* identities a sealing key can be bound to */
+pub mod seal_key_policy {
+  pub const SEAL_POLICY_MEASUREMENT: u32 = 1 << 0;
+  pub const SEAL_POLICY_SIGNER: u32 = 1 << 1;
+
+  pub const SEAL_POLICY_MASK: u32 = SEAL_POLICY_MEASUREMENT | SEAL_POLICY_SIGNER;
+}
+
+/* enclave attributes requested by the host at creation */
+pub mod enclave_attributes {
+  pub const ENCLAVE_ATTR_DEBUG: u64 = 1 << 0;
 
-const ATTEST_DATA_MAXLEN: usize = 1024;
-const SEALING_KEY_SIZE: usize = 128;
+  pub const ENCLAVE_ATTR_MASK: u64 = ENCLAVE_ATTR_DEBUG;
+}
 
 static encl_lock: opensbi::spinlock_t = opensbi::spinlock_t::new();
 
@@ -56,38 +106,142 @@ pub mod enclave_region_type {
 	pub const REGION_INVALID: isize = 0;
 	pub const REGION_EPM: isize = 1;
 	pub const REGION_UTM: isize = 2;
-	pub const REGION_OTHER: isize = 3;
+	pub const REGION_OTHER: isize = 3; // donated by the host, not accepted yet
 }
 
-// #[derive(PartialEq)]
-// enum enclave_state {
-//   INVALID,
-//   DESTROYING,
-//   ALLOCATED,
-//   FRESH,
-//   STOPPED,
-//   RUNNING,
-// }
+/* enclave-side permissions of a UTM region, READ is required */
+pub mod utm_perm {
+  pub const UTM_PERM_READ: u32 = 1 << 0;
+  pub const UTM_PERM_WRITE: u32 = 1 << 1;
+  pub const UTM_PERM_RW: u32 = UTM_PERM_READ | UTM_PERM_WRITE;
+}
+
+/* Lifecycle of an enclave
+ *
+ *   INVALID -> ALLOCATED -> FRESH -> RUNNING <-> STOPPED
+ *      ^           |          |                     |
+ *      |           v          v                     v
+ *      +-----------+------ DESTROYING <-------------+
+ *
+ * RUNNING -> RUNNING is allowed for additional threads entering.
+ * ALLOCATED -> STOPPED is taken by the restore of a stopped enclave.
+ */
+/* the values are ABI, the host sees them in keystone_sbi_enclave_info */
+#[derive(PartialEq, Clone, Copy)]
+#[repr(u64)]
+pub enum enclave_state {
+  INVALID = 0,
+  DESTROYING = 1,
+  ALLOCATED = 2,
+  FRESH = 3,
+  STOPPED = 4,
+  RUNNING = 5,
+}
+
+impl enclave_state {
+  /*
+   * Checks whether `self -> to` is a legal transition.
+   * Returns the error describing why it is not otherwise.
+   */
+  pub fn transition(&self, to: enclave_state) -> Result<(), SmError> {
+    let legal: bool = match (*self, to) {
+      (enclave_state::INVALID, enclave_state::ALLOCATED) => true,
+      (enclave_state::ALLOCATED, enclave_state::FRESH) => true,
+      (enclave_state::ALLOCATED, enclave_state::STOPPED) => true, // restore of a stopped enclave
+      (enclave_state::ALLOCATED, enclave_state::INVALID) => true,
+      (enclave_state::FRESH, enclave_state::RUNNING) => true,
+      (enclave_state::FRESH, enclave_state::DESTROYING) => true,
+      (enclave_state::RUNNING, enclave_state::RUNNING) => true,
+      (enclave_state::RUNNING, enclave_state::STOPPED) => true,
+      (enclave_state::STOPPED, enclave_state::RUNNING) => true,
+      (enclave_state::STOPPED, enclave_state::DESTROYING) => true,
+      (enclave_state::DESTROYING, enclave_state::INVALID) => true,
+      _ => false
+    };
+
+    if legal {
+      return Ok(());
+    }
+
+    match (*self, to) {
+      (enclave_state::INVALID, _) => return Err(SmError::InvalidId),
+      (_, enclave_state::ALLOCATED) => return Err(SmError::NoFreeResource),
+      (_, enclave_state::FRESH) => return Err(SmError::NotFresh),
+      (enclave_state::ALLOCATED, enclave_state::RUNNING) => return Err(SmError::NotInitialized),
+      (_, enclave_state::RUNNING) => return Err(SmError::NotRunnable),
+      (_, enclave_state::STOPPED) => return Err(SmError::NotRunning),
+      (_, enclave_state::DESTROYING) => return Err(SmError::NotDestroyable),
+      _ => return Err(SmError::UnknownError)
+    }
+  }
 
-pub mod enclave_state {
-  pub const INVALID: isize = -1;
-	pub const DESTROYING: isize = 0;
-	pub const ALLOCATED: isize = 1;
-	pub const FRESH: isize = 2;
-	pub const STOPPED: isize = 3;
-  pub const RUNNING: isize = 4;
+  /* validated, hashed and not being torn down */
+  pub fn is_initialized(&self) -> bool {
+    *self == enclave_state::FRESH || *self == enclave_state::STOPPED || *self == enclave_state::RUNNING
+  }
 }
 
 struct enclave_region {
   pub pmp_rid: region_id,
-  pub region_type: isize
+  pub region_type: isize,
+  pub perm: u8, // PMP permission while the enclave runs
+  pub busy: bool // being reprogrammed on every hart without encl_lock, leave it alone
 }
 
 impl enclave_region {
   pub fn new() -> Self {
     Self {
       pmp_rid: 0,
-      region_type: enclave_region_type::REGION_INVALID
+      region_type: enclave_region_type::REGION_INVALID,
+      perm: pmp::PMP_NO_PERM,
+      busy: false
+    }
+  }
+}
+
+/* physical range given back to the host, waiting to be collected */
+#[derive(Clone, Copy)]
+pub struct released_range {
+  pub paddr: usize,
+  pub size: usize
+}
+
+impl released_range {
+  pub fn new() -> Self {
+    Self {
+      paddr: 0,
+      size: 0
+    }
+  }
+}
+
+/* Launch configuration of the enclave.
+ * This block is hashed into the measurement and carried in the report,
+ * so a verifier can reject enclaves launched with weaker settings.
+ * Hashed as raw bytes: keep it repr(C) with fixed-width fields only.
+ */
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct enclave_config {
+  pub attributes: u64,
+  pub utm_size: u64,
+  pub extra_utm_sizes: [u64; EXTRA_UTM_MAX], // 0 if unused
+  pub extra_utm_perms: [u64; EXTRA_UTM_MAX], // utm_perm bits
+  pub max_threads: u64,
+  pub debug: u64,
+  pub svn: u64
+}
+
+impl enclave_config {
+  pub fn new() -> Self {
+    Self {
+      attributes: 0,
+      utm_size: 0,
+      extra_utm_sizes: [0; EXTRA_UTM_MAX],
+      extra_utm_perms: [0; EXTRA_UTM_MAX],
+      max_threads: 0,
+      debug: 0,
+      svn: 0
     }
   }
 }
@@ -96,22 +250,48 @@ pub struct enclave {
   // let lock: spinlock_t, // local enclave lock. we don't need this until we have multithreaded enclave
   pub eid: enclave_id, //enclave id
   pub encl_satp: usize, // enclave's page table base
-  pub state: isize, // global state of the enclave
+  pub state: enclave_state, // global state of the enclave
 
   /* Physical memory regions associate with this enclave */
   pub regions: [enclave_region; ENCLAVE_REGIONS_MAX],
+  pub released: [released_range; RELEASED_RANGES_MAX], // released by the enclave, not yet seen by the host
+  pub n_released: usize,
+  pub n_releasing: usize, // releases that reserved a slot in released, not done yet
 
   /* measurement */
   pub hash: [u8; crypto::MDSIZE],
   pub sign: [u8; crypto::SIGNATURE_SIZE],
+  pub signer: [u8; crypto::MDSIZE], // hash of the signer public key, zero if unsigned
 
   /* parameters */
   pub params: runtime_va_params_t,
   pub pa_params: runtime_pa_params,
+  pub config: enclave_config,
+
+  /* device the enclave agreed to be migrated to */
+  pub migrate_authorized: bool,
+  pub migrate_to: [u8; crypto::PUBLIC_KEY_SIZE],
+
+  pub killed: bool, // being destroyed by kill_enclave, no thread may enter
+  pub copies: usize, // copies of its memory in progress, see pin_copy
+  pub consumed: bool, // the copy in progress takes its place
+
+  /* progress of a DESTROYING enclave, see teardown_enclave */
+  pub destroying: bool, // a hart is on the current chunk
+  pub teardown_begun: bool,
+  pub scrubbed: usize, // zeroed bytes at the start of the next private region
 
   /* enclave execution context */
-  pub n_thread: usize,
+  pub n_thread: usize, // number of threads currently on a hart
   pub threads: [thread_state; MAX_ENCL_THREADS], // thread.rs
+  pub thread_entries: [usize; MAX_ENCL_THREADS], // user entry of each thread
+  pub debug_trap: [bool; MAX_ENCL_THREADS], // breakpoints go to the SM, debug enclaves only
+  pub faulted: [bool; MAX_ENCL_THREADS], // last stopped by a fault, as in faults
+  pub faults: [keystone_sbi_fault; MAX_ENCL_THREADS],
+
+  /* execution time, kept by thread_enter and thread_leave */
+  pub stats: keystone_sbi_stats, // with the budget set at creation
+  pub entered_at: [u64; MAX_ENCL_THREADS], // timer value when the thread last got a hart
 
   pub ped: platform_enclave_data // platform.rs
 }
@@ -121,43 +301,88 @@ impl enclave {
     Self {
       eid: 0,
       encl_satp: 0,
-      state: enclave_state::FRESH,
+      state: enclave_state::INVALID,
       regions: [enclave_region::new(); ENCLAVE_REGIONS_MAX],
+      released: [released_range::new(); RELEASED_RANGES_MAX],
+      n_released: 0,
+      n_releasing: 0,
       hash: [0; crypto::MDSIZE],
       sign: [0; crypto::SIGNATURE_SIZE],
+      signer: [0; crypto::MDSIZE],
       params: runtime_va_params_t::new(),
       pa_params: runtime_pa_params::new(),
+      config: enclave_config::new(),
+      migrate_authorized: false,
+      migrate_to: [0; crypto::PUBLIC_KEY_SIZE],
+      killed: false,
+      copies: 0,
+      consumed: false,
+      destroying: false,
+      teardown_begun: false,
+      scrubbed: 0,
       n_thread: 0,
       threads: [thread_state::new(); MAX_ENCL_THREADS],
+      thread_entries: [0; MAX_ENCL_THREADS],
+      debug_trap: [false; MAX_ENCL_THREADS],
+      faulted: [false; MAX_ENCL_THREADS],
+      faults: [keystone_sbi_fault::new(); MAX_ENCL_THREADS],
+      stats: keystone_sbi_stats::new(),
+      entered_at: [0; MAX_ENCL_THREADS],
       ped: platform_enclave_data::new(),
     }
   }
 }
 
+#[repr(C)]
 struct enclave_report {
   hash: [u8; crypto::MDSIZE],
+  config: enclave_config,
   data_len: u64,
   data: [u8; ATTEST_DATA_MAXLEN],
   signature: [u8; crypto::SIGNATURE_SIZE]
 }
 
+#[repr(C)]
 struct sm_report {
   hash: [u8; crypto::MDSIZE],
   public_key: [u8; crypto::SIGNATURE_SIZE],
-  signature: [u8; crypto::SIGNATURE_SIZE]
+  signature: [u8; crypto::SIGNATURE_SIZE],
+  features: u64 // sm::sm_feature bits, fixed by the SM image and so by its hash
 }
 
+#[repr(C)]
 pub struct report {
   enclave: enclave_report,
   sm: sm_report,
   dev_public_key: [u8; crypto::PUBLIC_KEY_SIZE]
 }
 
-pub struct sealing_key {
-  key: [u8; SEALING_KEY_SIZE],
-  signature: [u8; crypto::SIGNATURE_SIZE]
+/* Report for another enclave on the same device.
+ * MAC'd with the report key of the target enclave, so only the SM and
+ * the target can produce or check it.
+ */
+#[repr(C)]
+pub struct local_report {
+  hash: [u8; crypto::MDSIZE], // measurement of the reporting enclave
+  config: enclave_config,
+  target: [u8; crypto::MDSIZE], // measurement of the target enclave
+  data: [u8; LOCAL_REPORT_DATA_LEN],
+  mac: [u8; crypto::MDSIZE]
+}
+
+/* Key request passed by the enclave to get_sealing_key */
+pub struct sealing_key_request {
+  policy: u32, // seal_key_policy flags
+  purpose: u32,
+  svn: u32,
+  key_len: u32,
+  key_ident_size: u32,
+  key_ident: [u8; KEY_IDENT_MAXLEN]
 }
 
+/* derived keys can be as long as the KDF allows, keep them off the stack */
+static mut sealing_key_buf: [u8; SEALING_KEY_MAXLEN] = [0; SEALING_KEY_MAXLEN];
+
 unsafe fn any_as_u8_slice<T: Sized>(p: &T) -> &[u8] {
   ::std::slice::from_raw_parts(
       (p as *const T) as *const u8,
@@ -165,35 +390,43 @@ unsafe fn any_as_u8_slice<T: Sized>(p: &T) -> &[u8] {
   )
 }
 
-pub fn copy_enclave_create_args(src: usize, dest: &mut keystone_sbi_create) -> usize {
+pub fn copy_enclave_create_args(src: usize, dest: &mut keystone_sbi_create) -> Result<(), SmError> {
   unsafe {
     let dst: usize = dest as *const keystone_sbi_create as usize;
     let region_overlap: i32 = copy_to_sm(dst, src, mem::size_of::<keystone_sbi_create>()); // mprv.rs
 
     if region_overlap != 0 {
-      return ERROR::SBI_ERR_SM_ENCLAVE_REGION_OVERLAPS; // error_code.rs
+      return Err(SmError::RegionOverlaps); // error_code.rs
     }
     else {
-      return ERROR::SBI_ERR_SM_ENCLAVE_SUCCESS;
+      return Ok(());
     }
   }
 }
 
-pub fn create_enclave(eidptr: *mut usize, create_args: keystone_sbi_create) -> usize {
+pub fn copy_enclave_clone_args(src: usize, dest: &mut keystone_sbi_clone) -> Result<(), SmError> {
+  let dst: usize = dest as *const keystone_sbi_clone as usize;
+
+  if copy_to_sm(dst, src, mem::size_of::<keystone_sbi_clone>()) != 0 {
+    return Err(SmError::RegionOverlaps);
+  }
+  return Ok(());
+}
+
+pub fn create_enclave(eidptr: *mut usize, create_args: keystone_sbi_create) -> Result<(), SmError> {
   /* EPM and UTM parameters */
   let base: usize = create_args.epm_region.paddr as usize;
   let size: usize = create_args.epm_region.size as usize;
   let utbase: usize = create_args.utm_region.paddr as usize;
   let utsize: usize = create_args.utm_region.size as usize;
 
-  let eid: enclave_id = 0;
-  let ret: usize = 0;
-  let region: i32 = 0;
-  let shared_region: i32 = 0;
+  let eid: enclave_id;
+  let region: region_id;
+  let shared_region: region_id;
 
   /* Runtime parameters */
   if is_create_args_valid(&mut create_args) == 0 { // enclave.rs
-    return ERROR::SBI_ERR_SM_ENCLAVE_ILLEGAL_ARGUMENT;
+    return Err(SmError::IllegalArgument);
   } 
 
   /* set va params */
@@ -207,31 +440,30 @@ pub fn create_enclave(eidptr: *mut usize, create_args: keystone_sbi_create) -> u
 
 
   // allocate eid
-  ret = ERROR::SBI_ERR_SM_ENCLAVE_NO_FREE_RESOURCE;
-  if encl_alloc_eid(&mut eid) != ERROR::SBI_ERR_SM_ENCLAVE_SUCCESS {
-    return ret;
-  }
+  eid = encl_alloc_eid()?;
 
   // create a PMP region bound to the enclave
-  ret = ERROR::SBI_ERR_SM_ENCLAVE_PMP_FAILURE;
-  if pmp::pmp_region_init_atomic(base, size, pmp_priority::PMP_PRI_ANY, &mut region, 0) != 0 { // pmp.rs
-    encl_free_eid(eid);
-    return ret;
-  }
+  region = match pmp::pmp_region_init_atomic(base, size, pmp_priority::PMP_PRI_ANY, 0) { // pmp.rs
+    Ok(rid) => rid,
+    Err(_) => return Err(abandon_enclave(eid, SmError::PmpFailure))
+  };
+  // recorded right away, so that abandon_enclave gives it back
+  enclaves[eid].regions[0].pmp_rid = region;
+  enclaves[eid].regions[0].region_type = enclave_region_type::REGION_EPM;
+  enclaves[eid].regions[0].perm = pmp::PMP_ALL_PERM;
 
   // create PMP region for shared memory
-  if pmp::pmp_region_init_atomic(utbase, utsize, pmp_priority::PMP_PRI_BOTTOM, &mut shared_region, 0) != 0 { // pmp.rs
-    pmp::pmp_region_free_atomic(region); // pmp.rs
-    encl_free_eid(eid);
-    return ret;
-  }
+  shared_region = match pmp::pmp_region_init_atomic(utbase, utsize, pmp_priority::PMP_PRI_BOTTOM, 0) { // pmp.rs
+    Ok(rid) => rid,
+    Err(_) => return Err(abandon_enclave(eid, SmError::PmpFailure))
+  };
+  enclaves[eid].regions[1].pmp_rid = shared_region;
+  enclaves[eid].regions[1].region_type = enclave_region_type::REGION_UTM;
+  enclaves[eid].regions[1].perm = utm_pmp_perm(utm_perm::UTM_PERM_RW);
 
   // set pmp registers for private region (not shared)
-  if pmp::pmp_set_global(region, pmp::PMP_NO_PERM) != 0 { // pmp.rs
-    pmp::pmp_region_free_atomic(shared_region);
-    pmp::pmp_region_free_atomic(region);
-    encl_free_eid(eid);
-    return ret;
+  if pmp::pmp_set_global(region, pmp::PMP_NO_PERM).is_err() { // pmp.rs
+    return Err(abandon_enclave(eid, SmError::PmpFailure));
   }
 
   // cleanup some memory regions for sanity See issue #38
@@ -239,10 +471,17 @@ pub fn create_enclave(eidptr: *mut usize, create_args: keystone_sbi_create) -> u
 
   // initialize enclave metadata
   enclaves[eid].eid = eid;
-  enclaves[eid].regions[0].pmp_rid = region;
-  enclaves[eid].regions[0].region_type = enclave_region_type::REGION_EPM;
-  enclaves[eid].regions[1].pmp_rid = shared_region;
-  enclaves[eid].regions[1].region_type = enclave_region_type::REGION_UTM;
+
+  // additional shared regions, each with its own permission
+  for i in 0..EXTRA_UTM_MAX {
+    let utm: &keystone_sbi_utm_region = &create_args.extra_utm_regions[i];
+    if utm.size == 0 {
+      continue;
+    }
+    if let Err(err) = add_utm_region(eid, 2 + i, utm) {
+      return Err(abandon_enclave(eid, err));
+    }
+  }
 
   if cfg!(target_pointer_width = "32") {
     enclaves[eid].encl_satp = (base >> page::RISCV_PGSHIFT) | (opensbi::SATP_MODE_SV32 << opensbi::Const::HGATP_MODE_SHIFT); // opensbi
@@ -254,42 +493,140 @@ pub fn create_enclave(eidptr: *mut usize, create_args: keystone_sbi_create) -> u
   enclaves[eid].n_thread = 0;
   enclaves[eid].params = params;
   enclaves[eid].pa_params = pa_params;
+  init_enclave_config(&mut enclaves[eid].config, &create_args);
+  // host policy, not part of the measurement
+  enclaves[eid].stats.max_ticks = create_args.max_ticks;
+  enclaves[eid].stats.resume_ticks = create_args.resume_ticks;
 
   /* Init enclave state (regs etc) */
-  thread::clean_state(&mut enclaves[eid].threads[0]); // thread.rs
+  for tid in 0..MAX_ENCL_THREADS {
+    if tid < create_args.max_threads as usize {
+      thread::clean_state(&mut enclaves[eid].threads[tid]); // thread.rs
+      // a zero entry means the thread starts at the main user entry
+      if create_args.thread_entries[tid] != 0 {
+        enclaves[eid].thread_entries[tid] = create_args.thread_entries[tid] as usize;
+      }
+      else {
+        enclaves[eid].thread_entries[tid] = params.user_entry as usize;
+      }
+    }
+    else {
+      enclaves[eid].threads[tid].status = thread_status::THREAD_INVALID;
+      enclaves[eid].thread_entries[tid] = 0;
+    }
+  }
 
   /* Platform create happens as the last thing before hashing/etc since
      it may modify the enclave struct */
-  ret = platform::platform_create_enclave(&mut enclaves[eid]);
-  if ret != 0 {
-    pmp::pmp_unset_global(region);
-    pmp::pmp_region_free_atomic(shared_region);
-    pmp::pmp_region_free_atomic(region);
-    encl_free_eid(eid);
-    return ret;
+  if let Err(err) = SmError::from_code(platform::platform_create_enclave(&mut enclaves[eid])) {
+    return Err(abandon_enclave(eid, err));
   }
 
   /* Validate memory, prepare hash and signature for attestation */
   opensbi::spin_lock(&mut encl_lock); // FIXME This should error for second enter.
-  ret = attest::validate_and_hash_enclave(&mut enclaves[eid]) as usize;
+  let ret: Result<(), SmError> = attest::validate_and_hash_enclave(&mut enclaves[eid])
+    .and_then(|_| attest::verify_enclave_signer(&mut enclaves[eid], &create_args.signer_public_key, &create_args.signer_signature));
   /* The enclave is fresh if it has been validated and hashed but not run yet. */
-  if ret != 0 {
+  if let Err(err) = ret {
     opensbi::spin_unlock(&mut encl_lock);
     platform::platform_destroy_enclave(&mut enclaves[eid]);
-    pmp::pmp_unset_global(region);
-    pmp::pmp_region_free_atomic(shared_region);
-    pmp::pmp_region_free_atomic(region);
-    encl_free_eid(eid);
-    return ret;
+    return Err(abandon_enclave(eid, err));
   }
 
-  enclaves[eid].state = enclave_state::FRESH;
-  /* EIDs are unsigned int in size, copy via simple copy */
-  *eidptr = eid;
+  let ret: Result<(), SmError> = set_enclave_state(eid, enclave_state::FRESH);
+  if ret.is_ok() {
+    /* EIDs are unsigned int in size, copy via simple copy */
+    *eidptr = eid;
+  }
 
   opensbi::spin_unlock(&mut encl_lock);
-  return ERROR::SBI_ERR_SM_ENCLAVE_SUCCESS;
+  return ret;
+
+}
+
+/* PMP bits for a UTM permission, never executable */
+fn utm_pmp_perm(perm: u32) -> u8 {
+  let pmp_perm: u8 = 0;
+
+  if perm & utm_perm::UTM_PERM_READ != 0 {
+    pmp_perm |= opensbi::PMP_R;
+  }
+  if perm & utm_perm::UTM_PERM_WRITE != 0 {
+    pmp_perm |= opensbi::PMP_W;
+  }
+  return pmp_perm;
+}
+
+/* sets up a shared region of enclave `eid` in regions[slot] */
+fn add_utm_region(eid: enclave_id, slot: usize, utm: &keystone_sbi_utm_region) -> Result<(), SmError> {
+  let paddr: usize = utm.paddr as usize;
+  let size: usize = utm.size as usize;
+
+  if slot >= ENCLAVE_REGIONS_MAX {
+    return Err(SmError::NoFreeResource);
+  }
+  // PMP reserves W without R, write-only is refused rather than widened
+  if utm.perm & utm_perm::UTM_PERM_READ == 0 || utm.perm & !utm_perm::UTM_PERM_RW != 0 {
+    return Err(SmError::IllegalArgument);
+  }
+
+  // the bottom register is taken by the main UTM, extra ones need their own
+  let rid: region_id = match pmp::pmp_region_init_atomic(paddr, size, pmp_priority::PMP_PRI_ANY, 0) {
+    Ok(rid) => rid,
+    Err(_) => return Err(SmError::PmpFailure)
+  };
+  clean_enclave_memory(paddr, size);
+
+  enclaves[eid].regions[slot].pmp_rid = rid;
+  enclaves[eid].regions[slot].region_type = enclave_region_type::REGION_UTM;
+  enclaves[eid].regions[slot].perm = utm_pmp_perm(utm.perm);
+  return Ok(());
+}
+
+/* logs a cleanup step that failed, cleanup goes on with the next one */
+fn cleanup_step(what: &str, step: Result<(), SmError>) -> Result<(), SmError> {
+  if let Err(err) = step {
+    println!("[SM] cleanup failed - {} ({:?})", what, err);
+  }
+  return step;
+}
+
+/*
+ * Gives back every PMP region of a half-created enclave.
+ * All regions are tried, a failed one is logged and left to the PMP
+ * allocator; returns the first failure.
+ */
+fn free_enclave_regions(eid: enclave_id) -> Result<(), SmError> {
+  let ret: Result<(), SmError> = Ok(());
+
+  for i in 0..ENCLAVE_REGIONS_MAX {
+    let rid: region_id = enclaves[eid].regions[i].pmp_rid;
+    let step: Result<(), SmError> = match enclaves[eid].regions[i].region_type {
+      enclave_region_type::REGION_INVALID => continue,
+      enclave_region_type::REGION_UTM => pmp::pmp_region_free_atomic(rid),
+      _ => cleanup_step("PMP unset", pmp::pmp_unset_global(rid))
+        .and(pmp::pmp_region_free_atomic(rid))
+    };
+    ret = ret.and(cleanup_step("PMP region", step));
+    enclaves[eid].regions[i].region_type = enclave_region_type::REGION_INVALID;
+  }
+  return ret;
+}
+
+/*
+ * Gives back the regions and the id of enclave `eid`, whose creation
+ * failed with `err`, and returns `err`. Every step runs even if one
+ * before it failed.
+ */
+fn abandon_enclave(eid: enclave_id, err: SmError) -> SmError {
+  let _ = free_enclave_regions(eid);
+  let _ = cleanup_step("enclave id", encl_free_eid(eid));
+  return err;
+}
 
+// an extra UTM past the last free region slot would always fail to add
+fn extra_utms_fit(regions: &[keystone_sbi_utm_region; EXTRA_UTM_MAX]) -> bool {
+  return regions[EXTRA_UTM_USABLE..].iter().all(|utm| utm.size == 0);
 }
 
 fn is_create_args_valid(args: &mut keystone_sbi_create) -> i32 {
@@ -342,9 +679,32 @@ fn is_create_args_valid(args: &mut keystone_sbi_create) -> i32 {
     return 0;
   }
 
+  // check the requested configuration
+  if args.max_threads == 0 || args.max_threads as usize > MAX_ENCL_THREADS {
+    return 0;
+  }
+  if args.attributes as u64 & !enclave_attributes::ENCLAVE_ATTR_MASK != 0 {
+    return 0;
+  }
+  if !extra_utms_fit(&args.extra_utm_regions) {
+    return 0;
+  }
+
   return 1;
 }
 
+fn init_enclave_config(config: &mut enclave_config, args: &keystone_sbi_create) {
+  config.attributes = args.attributes as u64;
+  config.utm_size = args.utm_region.size as u64;
+  for i in 0..EXTRA_UTM_MAX {
+    config.extra_utm_sizes[i] = args.extra_utm_regions[i].size as u64;
+    config.extra_utm_perms[i] = if args.extra_utm_regions[i].size != 0 { args.extra_utm_regions[i].perm as u64 } else { 0 };
+  }
+  config.max_threads = args.max_threads as u64;
+  config.debug = (args.attributes as u64 & enclave_attributes::ENCLAVE_ATTR_DEBUG != 0) as u64;
+  config.svn = args.svn as u64;
+}
+
 pub fn get_enclave_region_index(eid: enclave_id, entype: isize) -> i32 {
   let i: usize;
   for i in 0..ENCLAVE_REGIONS_MAX {
@@ -357,192 +717,1634 @@ pub fn get_enclave_region_index(eid: enclave_id, entype: isize) -> i32 {
 }
 
 /*
- * Fully destroys an enclave
- * Deallocates EID, clears epm, etc
- * Fails only if the enclave isn't running.
+ * Destroys enclave `eid`, FRESH or STOPPED, a piece at a time.
+ * A call scrubs at most DESTROY_CHUNK bytes of its memory. While some is
+ * left the enclave stays DESTROYING and the call returns DestroyPending;
+ * the host calls again until the eid is freed. Every private region
+ * stays locked away by PMP until it is all zero.
  */
-pub fn destroy_enclave(eid: enclave_id) -> usize {
-  let destroyable: i32;
+pub fn destroy_enclave(eid: enclave_id) -> Result<(), SmError> {
+  let ret: Result<(), SmError>;
 
   opensbi::spin_lock(&mut encl_lock);
-  destroyable = (enclave_exists(eid as usize) && enclaves[eid as usize].state <= enclave_state::STOPPED) as i32;
+  if enclave_exists(eid) && enclaves[eid].state == enclave_state::DESTROYING {
+    // another hart is on the current chunk
+    if enclaves[eid].destroying {
+      opensbi::spin_unlock(&mut encl_lock);
+      return Err(SmError::DestroyPending);
+    }
+    enclaves[eid].destroying = true;
+    opensbi::spin_unlock(&mut encl_lock);
+    return teardown_enclave(eid);
+  }
+  /* kill_enclave waits for its threads and destroys it itself */
+  if enclave_exists(eid) && enclaves[eid].killed {
+    opensbi::spin_unlock(&mut encl_lock);
+    return Err(SmError::NotDestroyable);
+  }
+  /* a running party still has the shared memory mapped on its hart */
+  if shm::shared_with_running(eid) {
+    opensbi::spin_unlock(&mut encl_lock);
+    return Err(SmError::NotDestroyable);
+  }
   /* update the enclave state first so that
    * no SM can run the enclave any longer */
-  if destroyable != 0 {
-    enclaves[eid as usize].state = enclave_state::DESTROYING;
+  ret = start_destroying(eid);
+  opensbi::spin_unlock(&mut encl_lock);
+
+  ret?;
+
+  return teardown_enclave(eid);
+}
+
+/*
+ * Destroys enclave `eid` even while it runs. No thread may enter it any
+ * more, and the harts it runs on are interrupted until its last thread
+ * left; those threads leave as EXITED and their callers get Killed.
+ * Fails like destroy_enclave if a peer sharing memory with it runs, and
+ * like it leaves the rest to further destroy calls on DestroyPending.
+ * Until then destroy_enclave refuses it.
+ */
+pub fn kill_enclave(eid: enclave_id) -> Result<(), SmError> {
+  let ret: Result<(), SmError>;
+
+  opensbi::spin_lock(&mut encl_lock);
+  if !enclave_exists(eid) {
+    opensbi::spin_unlock(&mut encl_lock);
+    return Err(SmError::InvalidId);
+  }
+  // someone else is killing it already, or it is pinned by a copy
+  if !enclaves[eid].state.is_initialized() || enclaves[eid].killed || enclaves[eid].copies != 0 {
+    opensbi::spin_unlock(&mut encl_lock);
+    return Err(SmError::NotDestroyable);
   }
+  enclaves[eid].killed = true;
   opensbi::spin_unlock(&mut encl_lock);
 
-  if destroyable == 0 {
-    return ERROR::SBI_ERR_SM_ENCLAVE_NOT_DESTROYABLE;
+  // a thread may be between thread_enter and its hart taking the context,
+  // it is interrupted once it shows up; the rest only once
+  let marks: [usize; config::MAX_HARTS] = [0; config::MAX_HARTS];
+  loop {
+    cpu::cpu_interrupt_enclave(eid, &mut marks);
+    if unsafe { core::ptr::read_volatile(&enclaves[eid].n_thread) } == 0 {
+      break;
+    }
+    core::hint::spin_loop();
+  }
+
+  // destroy_enclave refuses a killed enclave, so `eid` is still ours
+  opensbi::spin_lock(&mut encl_lock);
+  ret = if shm::shared_with_running(eid) { Err(SmError::NotDestroyable) } else { start_destroying(eid) };
+  if ret.is_err() {
+    // stopped but alive, the kill can be retried
+    enclaves[eid].killed = false;
   }
+  opensbi::spin_unlock(&mut encl_lock);
+
+  ret?;
+
+  return teardown_enclave(eid);
+}
+
+/* moves enclave `eid` to DESTROYING, owned by the caller, encl_lock must be held */
+fn start_destroying(eid: enclave_id) -> Result<(), SmError> {
+  set_enclave_state(eid, enclave_state::DESTROYING)?;
+  enclaves[eid].destroying = true;
+  enclaves[eid].teardown_begun = false;
+  enclaves[eid].scrubbed = 0;
+  return Ok(());
+}
 
-  // 0. Let the platform specifics do cleanup/modifications
-  platform::platform_destroy_enclave(&mut enclaves[eid]);
+/* lets the next destroy call carry on with `result` of this one */
+fn pause_teardown(eid: enclave_id, result: SmError) -> Result<(), SmError> {
+  opensbi::spin_lock(&mut encl_lock);
+  enclaves[eid].destroying = false;
+  opensbi::spin_unlock(&mut encl_lock);
+  return Err(result);
+}
+
+/*
+ * Does the next piece of tearing down enclave `eid`, which is DESTROYING
+ * and owned by the caller: at most DESTROY_CHUNK bytes of memory are
+ * scrubbed. Frees the eid once all is done, and returns DestroyPending
+ * before that.
+ */
+fn teardown_enclave(eid: enclave_id) -> Result<(), SmError> {
+  let left: usize = config::DESTROY_CHUNK;
+
+  if !enclaves[eid].teardown_begun {
+    // end the memory sharing before any region goes back to the host
+    if let Err(err) = shm::revoke_shared(eid) {
+      return pause_teardown(eid, err);
+    }
+    mailbox::mailbox_clear(eid);
+    swap::swap_clear(eid);
 
+    // 0. Let the platform specifics do cleanup/modifications
+    platform::platform_destroy_enclave(&mut enclaves[eid]);
+    enclaves[eid].teardown_begun = true;
+  }
 
-  // 1. clear all the data in the enclave pages
+  // 1. clear all the data in the enclave pages, region by region
   // requires no lock (single runner)
-  let i: i32;
-  let base: usize;
-  let size: usize;
-  let rid: region_id;
   for i in 0..ENCLAVE_REGIONS_MAX {
-
     if enclaves[eid].regions[i].region_type == enclave_region_type::REGION_INVALID || enclaves[eid].regions[i].region_type == enclave_region_type::REGION_UTM {
-        continue;
-       }
-      
-    //1.a Clear all pages
-    rid = enclaves[eid].regions[i].pmp_rid;
-    base = pmp::pmp_region_get_addr(rid) as usize;
-    size = pmp::pmp_region_get_size(rid) as usize;
-    opensbi::sbi_memset(base, 0, size); // opensbi 函数
+      continue;
+    }
+
+    //1.a Clear the next chunk, `scrubbed` bytes of this region are zero already
+    let rid: region_id = enclaves[eid].regions[i].pmp_rid;
+    let base: usize = pmp::pmp_region_get_addr(rid) as usize;
+    let size: usize = pmp::pmp_region_get_size(rid) as usize;
+    let n: usize = (size - enclaves[eid].scrubbed).min(left);
+    opensbi::sbi_memset(base + enclaves[eid].scrubbed, 0, n); // opensbi 函数
+    enclaves[eid].scrubbed += n;
+    left -= n;
+    if enclaves[eid].scrubbed < size {
+      return pause_teardown(eid, SmError::DestroyPending);
+    }
 
-    //1.b free pmp region
-    pmp::pmp_unset_global(rid);
-    pmp::pmp_region_free_atomic(rid);
+    //1.b free pmp region, the host may have it now
+    if let Err(err) = pmp::pmp_unset_global(rid).and_then(|_| pmp::pmp_region_free_atomic(rid)) {
+      return pause_teardown(eid, err);
+    }
+    enclaves[eid].regions[i].region_type = enclave_region_type::REGION_INVALID;
+    enclaves[eid].scrubbed = 0;
   }
 
-  // 2. free pmp region for UTM
-  rid = get_enclave_region_index(eid, enclave_region_type::REGION_UTM);
-  if rid != -1 {
-    pmp::pmp_region_free_atomic(enclaves[eid as usize].regions[rid as usize].pmp_rid);
+  // 2. free pmp regions for UTM
+  for i in 0..ENCLAVE_REGIONS_MAX {
+    if enclaves[eid].regions[i].region_type == enclave_region_type::REGION_UTM {
+      if let Err(err) = pmp::pmp_region_free_atomic(enclaves[eid].regions[i].pmp_rid) {
+        return pause_teardown(eid, err);
+      }
+      enclaves[eid].regions[i].region_type = enclave_region_type::REGION_INVALID;
+    }
   }
 
   enclaves[eid].encl_satp = 0;
   enclaves[eid].n_thread = 0;
+  for tid in 0..MAX_ENCL_THREADS {
+    enclaves[eid].threads[tid].status = thread_status::THREAD_INVALID;
+    enclaves[eid].thread_entries[tid] = 0;
+    enclaves[eid].debug_trap[tid] = false;
+    enclaves[eid].faulted[tid] = false;
+    enclaves[eid].faults[tid] = keystone_sbi_fault::new();
+    enclaves[eid].entered_at[tid] = 0;
+  }
+  enclaves[eid].stats = keystone_sbi_stats::new();
   enclaves[eid].params = runtime_va_params_t::new();
   enclaves[eid].pa_params = runtime_pa_params::new();
-  for i in 0..ENCLAVE_REGIONS_MAX {
-    enclaves[eid].regions[i].region_type = enclave_region_type::REGION_INVALID;
-  }
+  enclaves[eid].config = enclave_config::new();
+  enclaves[eid].signer = [0; crypto::MDSIZE];
+  // the host gets everything back, pending notifications are moot
+  enclaves[eid].n_released = 0;
+  enclaves[eid].n_releasing = 0;
+  enclaves[eid].migrate_authorized = false;
+  enclaves[eid].killed = false;
+  enclaves[eid].teardown_begun = false;
+  enclaves[eid].destroying = false;
 
   // 3. release eid
-  encl_free_eid(eid);
-
-  return ERROR::SBI_ERR_SM_ENCLAVE_SUCCESS;
+  return encl_free_eid(eid);
 }
 
-// opensbi
-pub fn run_enclave(regs: &mut sbi_trap::sbi_trap_regs, eid: enclave_id) -> usize {
-  let runable: bool;
+/*
+ * Hands the physical range [paddr, paddr + size) to enclave `eid`.
+ * The range is taken away from the host and zeroed right away, but the
+ * enclave only gets access once it accepts it with accept_memory.
+ */
+pub fn donate_memory(eid: enclave_id, paddr: usize, size: usize) -> Result<(), SmError> {
+  let rid: region_id;
+  let slot: usize = ENCLAVE_REGIONS_MAX;
 
-  opensbi::spin_lock(&mut encl_lock);
-  runable = enclave_exists(eid) && enclaves[eid].state == enclave_state::FRESH;
-  if runable {
-    enclaves[eid].state = enclave_state::RUNNING;
-    enclaves[eid].n_thread += 1;
+  if size == 0 || paddr & (page::RISCV_PGSIZE - 1) != 0 || size & (page::RISCV_PGSIZE - 1) != 0 {
+    return Err(SmError::IllegalArgument);
+  }
+  if paddr.checked_add(size).is_none() {
+    return Err(SmError::IllegalArgument);
   }
-  opensbi::spin_unlock(&mut encl_lock);
 
-  if !runable {
-    return ERROR::SBI_ERR_SM_ENCLAVE_NOT_FRESH;
+  // overlapping any SM or enclave region is refused by the PMP allocator
+  rid = match pmp::pmp_region_init_atomic(paddr, size, pmp_priority::PMP_PRI_ANY, 0) {
+    Ok(rid) => rid,
+    Err(pmp_err) => {
+      if pmp_err == SmError::PmpRegionOverlap {
+        return Err(SmError::RegionOverlaps);
+      }
+      return Err(SmError::PmpFailure);
+    }
+  };
+  if pmp::pmp_set_global(rid, pmp::PMP_NO_PERM).is_err() {
+    let _ = cleanup_step("PMP region", pmp::pmp_region_free_atomic(rid));
+    return Err(SmError::PmpFailure);
   }
+  opensbi::sbi_memset(paddr, 0, size);
 
-  // Enclave is OK to run, context switch to it
-  context_switch_to_enclave(regs, eid, 1);
+  opensbi::spin_lock(&mut encl_lock);
+  if enclave_exists(eid) && enclaves[eid].state.is_initialized() {
+    for i in 0..ENCLAVE_REGIONS_MAX {
+      if enclaves[eid].regions[i].region_type == enclave_region_type::REGION_INVALID {
+        slot = i;
+        break;
+      }
+    }
+  }
+  if slot == ENCLAVE_REGIONS_MAX {
+    let err: SmError = if enclave_exists(eid) && enclaves[eid].state.is_initialized() {
+      SmError::NoFreeResource
+    }
+    else {
+      SmError::NotInitialized
+    };
+    opensbi::spin_unlock(&mut encl_lock);
+    let _ = cleanup_step("PMP unset", pmp::pmp_unset_global(rid));
+    let _ = cleanup_step("PMP region", pmp::pmp_region_free_atomic(rid));
+    return Err(err);
+  }
+  enclaves[eid].regions[slot].pmp_rid = rid;
+  enclaves[eid].regions[slot].region_type = enclave_region_type::REGION_OTHER;
+  enclaves[eid].regions[slot].perm = pmp::PMP_ALL_PERM;
+  opensbi::spin_unlock(&mut encl_lock);
 
-  return ERROR::SBI_ERR_SM_ENCLAVE_SUCCESS;
+  return Ok(());
 }
 
-pub fn exit_enclave(regs: &mut sbi_trap::sbi_trap_regs, eid: enclave_id) -> usize {
+/*
+ * Called by enclave `eid` to take a donated range into its EPM.
+ * The range must match a donation exactly, so the host cannot make the
+ * enclave believe it owns memory the SM does not protect.
+ */
+pub fn accept_memory(eid: enclave_id, paddr: usize, size: usize) -> Result<(), SmError> {
+  let ret: Result<(), SmError> = Err(SmError::IllegalArgument);
 
   opensbi::spin_lock(&mut encl_lock);
-  let exitable = enclaves[eid].state == enclave_state::RUNNING;
-  if exitable {
-    enclaves[eid].n_thread -= 1;
-    if enclaves[eid].n_thread == 0 {
-      enclaves[eid].state = enclave_state::STOPPED;
+  for i in 0..ENCLAVE_REGIONS_MAX {
+    let rid: region_id = enclaves[eid].regions[i].pmp_rid;
+    if enclaves[eid].regions[i].region_type == enclave_region_type::REGION_OTHER
+      && pmp::pmp_region_get_addr(rid) as usize == paddr
+      && pmp::pmp_region_get_size(rid) as usize == size {
+      enclaves[eid].regions[i].region_type = enclave_region_type::REGION_EPM;
+      // other harts of the enclave pick it up on their next entry
+      ret = pmp::pmp_set_keystone(rid, pmp::PMP_ALL_PERM as u8).map_err(|_| SmError::PmpFailure);
+      break;
     }
   }
   opensbi::spin_unlock(&mut encl_lock);
 
-  if !exitable {
-    return ERROR::SBI_ERR_SM_ENCLAVE_NOT_RUNNING;
-  }
-  context_switch_to_host(regs, eid, false);
+  return ret;
+}
 
-  return ERROR::SBI_ERR_SM_ENCLAVE_SUCCESS;
+/*
+ * Finds the EPM region of enclave `eid` that ends with [paddr, paddr + size)
+ * and may give that range away: the first region keeps its measured
+ * runtime and user images, and a busy one is being changed already.
+ * encl_lock must be held.
+ */
+fn find_epm_tail(eid: enclave_id, paddr: usize, size: usize) -> Option<usize> {
+  if size == 0 || paddr & (page::RISCV_PGSIZE - 1) != 0 || size & (page::RISCV_PGSIZE - 1) != 0 {
+    return None;
+  }
 
+  for i in 0..ENCLAVE_REGIONS_MAX {
+    if enclaves[eid].regions[i].region_type != enclave_region_type::REGION_EPM || enclaves[eid].regions[i].busy {
+      continue;
+    }
+    let rid: region_id = enclaves[eid].regions[i].pmp_rid;
+    let start: usize = pmp::pmp_region_get_addr(rid) as usize;
+    let end: usize = start + pmp::pmp_region_get_size(rid) as usize;
+    if paddr < start || paddr.checked_add(size) != Some(end) {
+      continue;
+    }
+    if i == 0 && paddr < enclaves[eid].pa_params.free_base {
+      return None;
+    }
+    return Some(i);
+  }
+  return None;
 }
-// opensbi函数
-pub fn stop_enclave(regs: &mut sbi_trap::sbi_trap_regs, request: usize, eid: enclave_id) -> usize {
-  let stoppable: bool;
+
+/*
+ * Called by enclave `eid` to give the tail [paddr, paddr + size) of one
+ * of its EPM regions back to the host. The range is scrubbed first and
+ * queued for the host to collect with collect_released_memory.
+ * The PMP is reprogrammed on every hart with encl_lock dropped: a hart
+ * spinning on it could not answer the IPI. The region is marked busy
+ * meanwhile, and the enclave cannot go away as its thread is in here.
+ */
+pub fn release_memory(eid: enclave_id, paddr: usize, size: usize) -> Result<(), SmError> {
+  let ret: Result<(), SmError>;
 
   opensbi::spin_lock(&mut encl_lock);
-  stoppable = enclaves[eid].state == enclave_state::RUNNING;
-  if stoppable {
-    enclaves[eid].n_thread -= 1;
-    if enclaves[eid].n_thread == 0 {
-      enclaves[eid].state = enclave_state::STOPPED;
-    }
+  if enclaves[eid].n_released + enclaves[eid].n_releasing == RELEASED_RANGES_MAX {
+    opensbi::spin_unlock(&mut encl_lock);
+    return Err(SmError::NoFreeResource);
   }
+
+  let i: usize = match find_epm_tail(eid, paddr, size) {
+    Some(i) => i,
+    None => {
+      opensbi::spin_unlock(&mut encl_lock);
+      return Err(SmError::IllegalArgument);
+    }
+  };
+  let rid: region_id = enclaves[eid].regions[i].pmp_rid;
+  let start: usize = pmp::pmp_region_get_addr(rid) as usize;
+  enclaves[eid].regions[i].busy = true;
+  enclaves[eid].n_releasing += 1;
   opensbi::spin_unlock(&mut encl_lock);
 
-  if !stoppable {
-    return ERROR::SBI_ERR_SM_ENCLAVE_NOT_RUNNING;
+  // scrub before the range leaves the region, the enclave gave it up anyway
+  opensbi::sbi_memset(paddr, 0, size);
+  if paddr == start {
+    ret = pmp::pmp_unset(rid)
+      .and_then(|_| pmp::pmp_unset_global(rid))
+      .and_then(|_| pmp::pmp_region_free_atomic(rid));
+  }
+  else {
+    // every hart still holds the old bounds, reprogram them all before the tail is handed out
+    ret = pmp::pmp_region_shrink_atomic(rid, paddr - start)
+      .and_then(|_| pmp::pmp_set_global_owned(rid, pmp::PMP_ALL_PERM as u8, 1 << eid));
   }
 
-  context_switch_to_host(regs, eid, request == STOP_EDGE_CALL_HOST);
-
-  match request {
-    STOP_TIMER_INTERRUPT => return ERROR::SBI_ERR_SM_ENCLAVE_INTERRUPTED,
-    STOP_EDGE_CALL_HOST => return ERROR::SBI_ERR_SM_ENCLAVE_EDGE_CALL_HOST,
-    _ => return ERROR::SBI_ERR_SM_ENCLAVE_UNKNOWN_ERROR
+  opensbi::spin_lock(&mut encl_lock);
+  enclaves[eid].regions[i].busy = false;
+  enclaves[eid].n_releasing -= 1;
+  if ret.is_ok() {
+    // a region that failed to go stays in the slot, still protected
+    if paddr == start {
+      enclaves[eid].regions[i].region_type = enclave_region_type::REGION_INVALID;
+    }
+    else if i == 0 {
+      enclaves[eid].pa_params.dram_size = paddr - start;
+    }
+    let n: usize = enclaves[eid].n_released;
+    enclaves[eid].released[n] = released_range { paddr: paddr, size: size };
+    enclaves[eid].n_released += 1;
   }
+  opensbi::spin_unlock(&mut encl_lock);
+
+  return ret;
 }
 
-pub fn resume_enclave(regs: &mut sbi_trap::sbi_trap_regs, eid: enclave_id) -> usize {
-  let resumable: bool;
+/*
+ * Takes the tail [paddr, paddr + size) of an EPM region away from
+ * enclave `eid` and puts it in a PMP region of its own, returned.
+ * The contents are kept; used to share memory with other enclaves.
+ * Every hart is reprogrammed before this returns: harts running the
+ * enclave get `tail_perm` on the tail, all others no access. As in
+ * release_memory, that happens without encl_lock.
+ */
+pub fn split_epm_tail(eid: enclave_id, paddr: usize, size: usize, tail_perm: u8) -> Result<region_id, SmError> {
+  let ret: Result<region_id, SmError>;
 
   opensbi::spin_lock(&mut encl_lock);
-  resumable = enclave_exists(eid) && (enclaves[eid].state == enclave_state::RUNNING || enclaves[eid].state == enclave_state::STOPPED) && enclaves[eid].n_thread < MAX_ENCL_THREADS;
+  let i: usize = match find_epm_tail(eid, paddr, size) {
+    Some(i) => i,
+    None => {
+      opensbi::spin_unlock(&mut encl_lock);
+      return Err(SmError::IllegalArgument);
+    }
+  };
+  let rid: region_id = enclaves[eid].regions[i].pmp_rid;
+  let start: usize = pmp::pmp_region_get_addr(rid) as usize;
+  let perm: u8 = enclaves[eid].regions[i].perm;
+  enclaves[eid].regions[i].busy = true;
+  opensbi::spin_unlock(&mut encl_lock);
 
-  if !resumable {
-    opensbi::spin_unlock(&mut encl_lock);
-    return ERROR::SBI_ERR_SM_ENCLAVE_NOT_RESUMABLE;
+  if paddr == start {
+    // a whole donated region changes hands as is
+    ret = pmp::pmp_set_global_owned(rid, tail_perm, 1 << eid).map(|_| rid);
   }
   else {
-    enclaves[eid].n_thread += 1;
-    enclaves[eid].state = enclave_state::RUNNING;
+    // the tail is locked away on every hart before the head stops covering it
+    ret = pmp::pmp_region_split_atomic(rid, paddr - start)
+      .and_then(|tail| pmp::pmp_set_global_owned(tail, tail_perm, 1 << eid).map(|_| tail))
+      .and_then(|tail| pmp::pmp_set_global_owned(rid, perm, 1 << eid).map(|_| tail))
+      .map_err(|_| SmError::PmpFailure);
   }
-  opensbi::spin_unlock(&mut encl_lock);
 
-  // Enclave is OK to resume, context switch to it
-  context_switch_to_enclave(regs, eid, 0);
+  opensbi::spin_lock(&mut encl_lock);
+  enclaves[eid].regions[i].busy = false;
+  if ret.is_ok() {
+    if paddr == start {
+      enclaves[eid].regions[i].region_type = enclave_region_type::REGION_INVALID;
+    }
+    else if i == 0 {
+      enclaves[eid].pa_params.dram_size = paddr - start;
+    }
+  }
+  opensbi::spin_unlock(&mut encl_lock);
 
-  return ERROR::SBI_ERR_SM_ENCLAVE_SUCCESS;
+  return ret;
 }
 
-pub fn attest_enclave(report_ptr: usize, data: usize, size: usize, eid: enclave_id) -> usize {
-  let attestable: bool;
-  let report: report;
-  let ret: usize;
-
-  if size > ATTEST_DATA_MAXLEN {
-    return ERROR::SBI_ERR_SM_ENCLAVE_ILLEGAL_ARGUMENT;
-  }
+/*
+ * Tells the host about the oldest range enclave `eid` released.
+ * Copies a zero-sized range to `range_ptr` once nothing is pending.
+ */
+pub fn collect_released_memory(eid: enclave_id, range_ptr: usize) -> Result<(), SmError> {
+  let range: released_range = released_range::new();
 
   opensbi::spin_lock(&mut encl_lock);
-  attestable = enclave_exists(eid) && (enclaves[eid].state >= enclave_state::FRESH);
-
-  if !attestable {
-    ret = ERROR::SBI_ERR_SM_ENCLAVE_NOT_INITIALIZED;
+  if !enclave_exists(eid) {
     opensbi::spin_unlock(&mut encl_lock);
-    return ret;
+    return Err(SmError::InvalidId);
   }
+  if enclaves[eid].n_released > 0 {
+    range = enclaves[eid].released[0];
+    enclaves[eid].released.copy_within(1..RELEASED_RANGES_MAX, 0);
+    enclaves[eid].n_released -= 1;
+  }
+  opensbi::spin_unlock(&mut encl_lock);
 
-  unsafe {
-    let enclave_data: usize = (&report.enclave.data as *const u8) as usize;
+  if mprv::copy_from_sm(range_ptr, &range as *const released_range as usize, mem::size_of::<released_range>()) != 0 {
+    return Err(SmError::IllegalArgument);
+  }
+  return Ok(());
+}
+
+/* where the memory of a source enclave lands in its clone */
+struct clone_map {
+  epm_from: usize,
+  epm_to: usize,
+  epm_size: usize,
+  utm_from: usize,
+  utm_to: usize,
+  utm_size: usize
+}
+
+impl clone_map {
+  /* address of [pa, pa + size) of the source in the clone, None if it is not enclave memory */
+  fn relocate(&self, pa: usize, size: usize) -> Option<usize> {
+    if pa >= self.epm_from && size <= self.epm_size && pa - self.epm_from <= self.epm_size - size {
+      return Some(pa - self.epm_from + self.epm_to);
+    }
+    if pa >= self.utm_from && size <= self.utm_size && pa - self.utm_from <= self.utm_size - size {
+      return Some(pa - self.utm_from + self.utm_to);
+    }
+    return None;
+  }
+
+  fn relocate_satp(&self, satp: usize) -> Option<usize> {
+    let root: usize = (satp & opensbi::Const::SATP_PPN) << page::RISCV_PGSHIFT;
+    let new_root: usize = self.relocate(root, page::RISCV_PGSIZE)?;
+    if new_root < self.epm_to || new_root >= self.epm_to + self.epm_size {
+      return None;
+    }
+    return Some((satp & !opensbi::Const::SATP_PPN) | (new_root >> page::RISCV_PGSHIFT));
+  }
+}
+
+/*
+ * Points every PTE of the page table at `table`, already copied into the
+ * clone, at the clone's memory. Page tables must be in the EPM, and the
+ * UTM may only be mapped with leaf pages, as validate_and_hash_epm checks.
+ * A table reached twice fails, its entries already point into the clone.
+ */
+fn relocate_page_table(map: &clone_map, table: usize, level: usize) -> Result<(), SmError> {
+  let n_pte: usize = page::RISCV_PGSIZE / mem::size_of::<usize>();
+
+  for i in 0..n_pte {
+    let pte: &mut usize = unsafe { &mut *((table + i * mem::size_of::<usize>()) as *mut usize) };
+    if *pte & page::PTE_V == 0 {
+      continue;
+    }
+
+    let leaf: bool = *pte & (page::PTE_R | page::PTE_W | page::PTE_X) != 0;
+    let span: usize = if leaf { page::RISCV_PGSIZE << (page::page::RISCV_PGLEVEL_BITS * (level - 1)) } else { page::RISCV_PGSIZE };
+    let pa: usize = (*pte >> page::PTE_PPN_SHIFT) << page::RISCV_PGSHIFT;
+    let new_pa: usize = match map.relocate(pa, span) {
+      Some(new_pa) => new_pa,
+      None => return Err(SmError::IllegalPte)
+    };
+    *pte = (*pte & ((1 << page::PTE_PPN_SHIFT) - 1)) | ((new_pa >> page::RISCV_PGSHIFT) << page::PTE_PPN_SHIFT);
+
+    if !leaf {
+      if level == 1 || new_pa < map.epm_to || new_pa >= map.epm_to + map.epm_size {
+        return Err(SmError::IllegalPte);
+      }
+      relocate_page_table(map, new_pa, level - 1)?;
+    }
+  }
+  return Ok(());
+}
+
+/*
+ * Everything of a FRESH or STOPPED enclave but its memory.
+ * Physical addresses are as of `epm_base` and `utm_base`; they are moved
+ * with a clone_map when the image is installed somewhere else.
+ */
+#[derive(Clone, Copy)]
+struct enclave_image {
+  stopped: u64,
+  epm_base: usize,
+  epm_size: usize,
+  utm_base: usize,
+  utm_size: usize,
+  encl_satp: usize,
+  hash: [u8; crypto::MDSIZE],
+  sign: [u8; crypto::SIGNATURE_SIZE],
+  signer: [u8; crypto::MDSIZE],
+  params: runtime_va_params_t,
+  pa_params: runtime_pa_params,
+  config: enclave_config,
+  thread_entries: [usize; MAX_ENCL_THREADS],
+  threads: [thread_state; MAX_ENCL_THREADS],
+  ticket: checkpoint::checkpoint_ticket // of a checkpoint, none in a clone or an export
+}
+
+impl enclave_image {
+  pub fn new() -> Self {
+    Self {
+      stopped: 0,
+      epm_base: 0,
+      epm_size: 0,
+      utm_base: 0,
+      utm_size: 0,
+      encl_satp: 0,
+      hash: [0; crypto::MDSIZE],
+      sign: [0; crypto::SIGNATURE_SIZE],
+      signer: [0; crypto::MDSIZE],
+      params: runtime_va_params_t::new(),
+      pa_params: runtime_pa_params::new(),
+      config: enclave_config::new(),
+      thread_entries: [0; MAX_ENCL_THREADS],
+      threads: [thread_state::new(); MAX_ENCL_THREADS],
+      ticket: checkpoint::checkpoint_ticket::new()
+    }
+  }
+}
+
+unsafe fn any_as_u8_slice_mut<T: Sized>(p: &mut T) -> &mut [u8] {
+  ::std::slice::from_raw_parts_mut(
+      (p as *mut T) as *mut u8,
+      ::std::mem::size_of::<T>(),
+  )
+}
+
+/*
+ * Checks that enclave `src`, FRESH or STOPPED, can be copied out,
+ * encl_lock must be held.
+ * Donated memory, extra UTMs and shared regions have no place in a copy.
+ */
+fn check_copyable(src: enclave_id) -> Result<(), SmError> {
+  if !enclave_exists(src) {
+    return Err(SmError::InvalidId);
+  }
+  // a running enclave changes under our feet
+  if enclaves[src].state != enclave_state::FRESH
+    && enclaves[src].state != enclave_state::STOPPED {
+    return Err(SmError::NotFresh);
+  }
+  if enclaves[src].killed {
+    return Err(SmError::Killed);
+  }
+
+  if enclaves[src].regions[0].region_type != enclave_region_type::REGION_EPM
+    || enclaves[src].regions[1].region_type != enclave_region_type::REGION_UTM {
+    return Err(SmError::IllegalArgument);
+  }
+  for i in 2..ENCLAVE_REGIONS_MAX {
+    if enclaves[src].regions[i].region_type != enclave_region_type::REGION_INVALID {
+      return Err(SmError::IllegalArgument);
+    }
+  }
+
+  // evicted pages are bound to this instance and would be lost
+  if swap::has_evicted(src) {
+    return Err(SmError::IllegalArgument);
+  }
+
+  // a stopped thread must resume on the page table that gets relocated
+  for tid in 0..MAX_ENCL_THREADS {
+    if enclaves[src].threads[tid].status == thread_status::THREAD_STOPPED
+      && thread::get_prev_satp(&enclaves[src].threads[tid]) != enclaves[src].encl_satp {
+      return Err(SmError::NotResumable);
+    }
+  }
+  return Ok(());
+}
+
+/*
+ * Checks enclave `src` with check_copyable and pins it: until unpin_copy
+ * it can neither run, nor be destroyed or debugged, so its memory can be
+ * read without encl_lock. Returns its image. encl_lock must be held.
+ * Clones may pin it together. A copy that `consumes` the enclave, which
+ * is destroyed once it is done, pins it alone.
+ */
+fn pin_copy(src: enclave_id, consumes: bool) -> Result<enclave_image, SmError> {
+  check_copyable(src)?;
+  if consumes && enclaves[src].copies != 0 {
+    return Err(SmError::NotDestroyable);
+  }
+  // on its way out
+  if enclaves[src].consumed {
+    return Err(SmError::NotFresh);
+  }
+  enclaves[src].copies += 1;
+  enclaves[src].consumed = consumes;
+  return Ok(capture_image(src));
+}
+
+/* ends a pin_copy of enclave `src`, encl_lock must be held */
+fn unpin_copy(src: enclave_id) {
+  enclaves[src].copies -= 1;
+  enclaves[src].consumed = false;
+}
+
+/* image of enclave `src`, checked by check_copyable */
+fn capture_image(src: enclave_id) -> enclave_image {
+  let image: enclave_image = enclave_image::new();
+
+  image.stopped = (enclaves[src].state == enclave_state::STOPPED) as u64;
+  image.epm_base = pmp::pmp_region_get_addr(enclaves[src].regions[0].pmp_rid) as usize;
+  image.epm_size = pmp::pmp_region_get_size(enclaves[src].regions[0].pmp_rid) as usize;
+  image.utm_base = pmp::pmp_region_get_addr(enclaves[src].regions[1].pmp_rid) as usize;
+  image.utm_size = pmp::pmp_region_get_size(enclaves[src].regions[1].pmp_rid) as usize;
+  image.encl_satp = enclaves[src].encl_satp;
+  image.hash = enclaves[src].hash;
+  image.sign = enclaves[src].sign;
+  image.signer = enclaves[src].signer;
+  image.params = enclaves[src].params;
+  image.pa_params = enclaves[src].pa_params;
+  image.config = enclaves[src].config;
+  image.thread_entries = enclaves[src].thread_entries;
+  image.threads = enclaves[src].threads;
+  return image;
+}
+
+/*
+ * Allocates an enclave id with an EPM and a UTM region to copy an
+ * enclave into. The UTM is left as is, it may carry a checkpoint.
+ */
+fn alloc_copy_target(base: usize, size: usize, utbase: usize, utsize: usize) -> Result<enclave_id, SmError> {
+  let eid: enclave_id;
+  let region: region_id;
+  let shared_region: region_id;
+
+  eid = encl_alloc_eid()?;
+
+  region = match pmp::pmp_region_init_atomic(base, size, pmp_priority::PMP_PRI_ANY, 0) {
+    Ok(rid) => rid,
+    Err(_) => return Err(abandon_enclave(eid, SmError::PmpFailure))
+  };
+  enclaves[eid].regions[0].pmp_rid = region;
+  enclaves[eid].regions[0].region_type = enclave_region_type::REGION_EPM;
+  enclaves[eid].regions[0].perm = pmp::PMP_ALL_PERM;
+
+  shared_region = match pmp::pmp_region_init_atomic(utbase, utsize, pmp_priority::PMP_PRI_BOTTOM, 0) {
+    Ok(rid) => rid,
+    Err(_) => return Err(abandon_enclave(eid, SmError::PmpFailure))
+  };
+  enclaves[eid].regions[1].pmp_rid = shared_region;
+  enclaves[eid].regions[1].region_type = enclave_region_type::REGION_UTM;
+  enclaves[eid].regions[1].perm = utm_pmp_perm(utm_perm::UTM_PERM_RW);
+
+  if pmp::pmp_set_global(region, pmp::PMP_NO_PERM).is_err() {
+    return Err(abandon_enclave(eid, SmError::PmpFailure));
+  }
+
+  enclaves[eid].eid = eid;
+  return Ok(eid);
+}
+
+/* scrubs and gives back a copy that failed with `err`, and returns `err` */
+fn discard_copy(eid: enclave_id, base: usize, size: usize, err: SmError) -> SmError {
+  opensbi::sbi_memset(base, 0, size);
+  return abandon_enclave(eid, err);
+}
+
+/*
+ * Turns enclave `eid`, whose EPM already holds the memory of `image`,
+ * into that enclave and writes its id to `eidptr`. The page table and
+ * the saved page tables of stopped threads are moved with `map`.
+ * `commit` runs once nothing else can fail, for what must only happen
+ * if the enclave comes to life, such as redeeming a checkpoint ticket.
+ * The caller discards the copy if this fails.
+ */
+fn install_image(eidptr: *mut usize, eid: enclave_id, image: &enclave_image, map: &clone_map, commit: &dyn Fn() -> Result<(), SmError>) -> Result<(), SmError> {
+  let state: enclave_state = if image.stopped != 0 { enclave_state::STOPPED } else { enclave_state::FRESH };
+
+  let ret: Result<(), SmError> = match map.relocate_satp(image.encl_satp) {
+    Some(satp) => {
+      enclaves[eid].encl_satp = satp;
+      relocate_page_table(map, (satp & opensbi::Const::SATP_PPN) << page::RISCV_PGSHIFT, page::RISCV_PGLEVEL_TOP)
+    },
+    None => Err(SmError::IllegalPte)
+  };
+  ret?;
+
+  enclaves[eid].hash = image.hash;
+  enclaves[eid].sign = image.sign;
+  enclaves[eid].signer = image.signer;
+  enclaves[eid].params = image.params;
+  enclaves[eid].config = image.config;
+
+  enclaves[eid].pa_params = image.pa_params;
+  enclaves[eid].pa_params.dram_base = map.epm_to;
+  enclaves[eid].pa_params.runtime_base = image.pa_params.runtime_base - map.epm_from + map.epm_to;
+  enclaves[eid].pa_params.user_base = image.pa_params.user_base - map.epm_from + map.epm_to;
+  enclaves[eid].pa_params.free_base = image.pa_params.free_base - map.epm_from + map.epm_to;
+
+  enclaves[eid].n_thread = 0;
+  enclaves[eid].n_released = 0;
+  enclaves[eid].migrate_authorized = false;
+  enclaves[eid].thread_entries = image.thread_entries;
+  enclaves[eid].threads = image.threads;
+  for tid in 0..MAX_ENCL_THREADS {
+    if enclaves[eid].threads[tid].status != thread_status::THREAD_STOPPED {
+      continue;
+    }
+    // the source was checked by check_copyable
+    let satp: usize = map.relocate_satp(thread::get_prev_satp(&image.threads[tid])).unwrap_or(0);
+    thread::set_prev_satp(&mut enclaves[eid].threads[tid], satp);
+  }
+
+  SmError::from_code(platform::platform_create_enclave(&mut enclaves[eid]))?;
+
+  if let Err(err) = commit() {
+    platform::platform_destroy_enclave(&mut enclaves[eid]);
+    return Err(err);
+  }
+
+  opensbi::spin_lock(&mut encl_lock);
+  let ret: Result<(), SmError> = set_enclave_state(eid, state);
+  if ret.is_ok() {
+    *eidptr = eid;
+  }
+  opensbi::spin_unlock(&mut encl_lock);
+
+  if ret.is_err() {
+    platform::platform_destroy_enclave(&mut enclaves[eid]);
+  }
+  return ret;
+}
+
+/*
+ * Clones enclave `src`, FRESH or STOPPED, into the memory given by
+ * `clone_args` and writes the new id to `eidptr`.
+ * The EPM is copied page by page and its page table moved to the new
+ * base, so the clone keeps the measurement of the source without being
+ * hashed again. A clone of a STOPPED enclave is STOPPED too, its threads
+ * saved where the source's were, and shares whatever state the source
+ * had, nonces included.
+ * The source is pinned while it is copied, encl_lock is not held.
+ * The UTM of the clone starts zeroed.
+ */
+pub fn clone_enclave(eidptr: *mut usize, src: enclave_id, clone_args: keystone_sbi_clone) -> Result<(), SmError> {
+  let base: usize = clone_args.epm_region.paddr as usize;
+  let size: usize = clone_args.epm_region.size as usize;
+  let utbase: usize = clone_args.utm_region.paddr as usize;
+  let utsize: usize = clone_args.utm_region.size as usize;
+
+  let eid: enclave_id;
+  let image: enclave_image;
+
+  eid = alloc_copy_target(base, size, utbase, utsize)?;
+  clean_enclave_memory(utbase, utsize);
+
+  // the source must not run or change shape while it is copied
+  opensbi::spin_lock(&mut encl_lock);
+  let pinned: Result<enclave_image, SmError> = pin_copy(src, false);
+  opensbi::spin_unlock(&mut encl_lock);
+  image = match pinned {
+    Ok(image) => image,
+    Err(err) => return Err(discard_copy(eid, base, size, err))
+  };
+
+  if image.epm_size == size && image.utm_size == utsize {
+    opensbi::sbi_memcpy(base, image.epm_base, size);
+  }
+  opensbi::spin_lock(&mut encl_lock);
+  unpin_copy(src);
+  opensbi::spin_unlock(&mut encl_lock);
+  if image.epm_size != size || image.utm_size != utsize {
+    return Err(discard_copy(eid, base, size, SmError::IllegalArgument));
+  }
+
+  let map: clone_map = clone_map {
+    epm_from: image.epm_base,
+    epm_to: base,
+    epm_size: size,
+    utm_from: image.utm_base,
+    utm_to: utbase,
+    utm_size: utsize
+  };
+  let ret: Result<(), SmError> = install_image(eidptr, eid, &image, &map, &|| Ok(()));
+  if let Err(err) = ret {
+    return Err(discard_copy(eid, base, size, err));
+  }
+  return Ok(());
+}
+
+/*
+ * Seals enclave `eid`, FRESH or STOPPED, into a checkpoint written to the
+ * start of its UTM, which must have room for the whole EPM. Anything the
+ * UTM held is overwritten. The enclave is pinned while it is sealed and
+ * consumed once the blob is out: it is DESTROYING before this returns,
+ * and the blob restores once (see checkpoint.rs), so only one instance
+ * of it is ever live. DestroyPending means the blob is out and the host
+ * finishes the destruction with destroy_enclave.
+ * The blob only opens on this device under this SM, and it restores the
+ * enclave with its measurement, so sealed data and counters stay its own.
+ */
+pub fn checkpoint_enclave(eid: enclave_id) -> Result<(), SmError> {
+  let image: enclave_image;
+
+  opensbi::spin_lock(&mut encl_lock);
+  let pinned: Result<enclave_image, SmError> = pin_copy(eid, true);
+  opensbi::spin_unlock(&mut encl_lock);
+  image = pinned?;
+
+  let ret: Result<(), SmError> = checkpoint::ticket_issue(&image.hash).and_then(|ticket| {
+    image.ticket = ticket;
+    let sealed: Result<usize, SmError> = unsafe {
+      checkpoint::seal(image.utm_base, image.utm_size, any_as_u8_slice(&image), image.epm_base, image.epm_size)
+    };
+    if sealed.is_err() {
+      let _ = checkpoint::ticket_withdraw(&ticket, &image.hash);
+    }
+    return sealed.map(|_| ());
+  });
+
+  opensbi::spin_lock(&mut encl_lock);
+  unpin_copy(eid);
+  if ret.is_ok() {
+    // pinned until now, nothing moved it out of FRESH or STOPPED
+    assert::sm_assert(start_destroying(eid).is_ok() as usize);
+  }
+  opensbi::spin_unlock(&mut encl_lock);
+
+  // the image holds the thread registers
+  opensbi::sbi_memset(&image as *const enclave_image as usize, 0, mem::size_of::<enclave_image>());
+
+  ret?;
+  return teardown_enclave(eid);
+}
+
+/*
+ * Re-creates an enclave from the checkpoint at the start of the UTM in
+ * `restore_args` and writes its id to `eidptr`. The EPM and UTM must be
+ * as large as those of the enclave that was checkpointed; they may be
+ * anywhere. The UTM is zeroed once the checkpoint is read. The ticket of
+ * the checkpoint is redeemed once the enclave is back, a blob that was
+ * restored before is refused.
+ */
+pub fn restore_enclave(eidptr: *mut usize, restore_args: keystone_sbi_clone) -> Result<(), SmError> {
+  let base: usize = restore_args.epm_region.paddr as usize;
+  let size: usize = restore_args.epm_region.size as usize;
+  let utbase: usize = restore_args.utm_region.paddr as usize;
+  let utsize: usize = restore_args.utm_region.size as usize;
+
+  let eid: enclave_id;
+  let image: enclave_image = enclave_image::new();
+  let ticket: checkpoint::checkpoint_ticket;
+
+  eid = alloc_copy_target(base, size, utbase, utsize)?;
+
+  // the EPM is locked away from the host, it may hold plaintext early
+  let opened: Result<(), SmError> = unsafe {
+    checkpoint::open(utbase, utsize, any_as_u8_slice_mut(&mut image), base, size)
+  }.and_then(|_| checkpoint::ticket_reserve(&image.ticket, &image.hash));
+  ticket = image.ticket;
+  let reserved: bool = opened.is_ok();
+
+  let ret: Result<(), SmError> = install_opened_image(eidptr, eid, &image, opened, &restore_args,
+    &|| checkpoint::ticket_redeem(&ticket));
+  if reserved && ret.is_err() {
+    checkpoint::ticket_release(&ticket);
+  }
+  return ret;
+}
+
+/*
+ * Finishes restore_enclave and import_enclave once the image of enclave
+ * `eid` was opened from its UTM with result `opened`. `commit` is
+ * passed on to install_image.
+ */
+fn install_opened_image(eidptr: *mut usize, eid: enclave_id, image: &enclave_image, opened: Result<(), SmError>, args: &keystone_sbi_clone, commit: &dyn Fn() -> Result<(), SmError>) -> Result<(), SmError> {
+  let base: usize = args.epm_region.paddr as usize;
+  let size: usize = args.epm_region.size as usize;
+  let utbase: usize = args.utm_region.paddr as usize;
+  let utsize: usize = args.utm_region.size as usize;
+
+  clean_enclave_memory(utbase, utsize);
+  let ret: Result<(), SmError> = opened.and_then(|_| {
+    if image.utm_size != utsize {
+      return Err(SmError::IllegalArgument);
+    }
+    let map: clone_map = clone_map {
+      epm_from: image.epm_base,
+      epm_to: base,
+      epm_size: size,
+      utm_from: image.utm_base,
+      utm_to: utbase,
+      utm_size: utsize
+    };
+    return install_image(eidptr, eid, image, &map, commit);
+  });
+  opensbi::sbi_memset(image as *const enclave_image as usize, 0, mem::size_of::<enclave_image>());
+
+  if let Err(err) = ret {
+    return Err(discard_copy(eid, base, size, err));
+  }
+  return Ok(());
+}
+
+/*
+ * Called by enclave `eid` to agree to be migrated to the device whose
+ * public key is at `dev_key_ptr`. Only that device can receive it.
+ */
+pub fn authorize_migration(eid: enclave_id, dev_key_ptr: usize) -> Result<(), SmError> {
+  let dev_key: [u8; crypto::PUBLIC_KEY_SIZE] = [0; crypto::PUBLIC_KEY_SIZE];
+
+  if mprv::copy_to_sm(dev_key.as_ptr() as usize, dev_key_ptr, crypto::PUBLIC_KEY_SIZE) != 0 {
+    return Err(SmError::IllegalArgument);
+  }
+
+  opensbi::spin_lock(&mut encl_lock);
+  enclaves[eid].migrate_to = dev_key;
+  enclaves[eid].migrate_authorized = true;
+  opensbi::spin_unlock(&mut encl_lock);
+  return Ok(());
+}
+
+/*
+ * true if an enclave other than `eid` has its measurement and is not
+ * being destroyed, a clone for instance. encl_lock must be held.
+ */
+fn identity_live_elsewhere(eid: enclave_id) -> bool {
+  for other in 0..ENCL_MAX {
+    if other != eid && enclave_exists(other) && enclaves[other].state != enclave_state::DESTROYING
+      && enclaves[other].hash == enclaves[eid].hash {
+      return true;
+    }
+  }
+  return false;
+}
+
+/*
+ * Exports enclave `eid`, FRESH or STOPPED, to the destination that made
+ * the offer at `offer_ptr` (see migrate.rs). The blob is written to the
+ * start of the enclave's UTM, and the enclave is DESTROYING before this
+ * returns, so it can never run here again. Nor can a copy of it: the
+ * export is refused while another enclave with its measurement exists
+ * here, such as a clone, or a checkpoint of that measurement was not
+ * restored yet. The enclave is pinned while it is sealed.
+ * DestroyPending means the blob is out and the host finishes the
+ * destruction with destroy_enclave.
+ */
+pub fn export_enclave(eid: enclave_id, offer_ptr: usize) -> Result<(), SmError> {
+  let keys: [u8; migrate::MIGRATE_KEYS_SIZE] = [0; migrate::MIGRATE_KEYS_SIZE];
+  let image: enclave_image;
+
+  opensbi::spin_lock(&mut encl_lock);
+  if !enclave_exists(eid) {
+    opensbi::spin_unlock(&mut encl_lock);
+    return Err(SmError::InvalidId);
+  }
+  if !enclaves[eid].migrate_authorized {
+    opensbi::spin_unlock(&mut encl_lock);
+    return Err(SmError::PeerNotTrusted);
+  }
+  // its shared memory goes away with it
+  if shm::shared_with_running(eid) || identity_live_elsewhere(eid) {
+    opensbi::spin_unlock(&mut encl_lock);
+    return Err(SmError::NotDestroyable);
+  }
+  let pinned: Result<enclave_image, SmError> = pin_copy(eid, true);
+  opensbi::spin_unlock(&mut encl_lock);
+  image = pinned?;
+
+  let ret: Result<(), SmError> = if checkpoint::tickets_outstanding(&image.hash) {
+    Err(SmError::NotDestroyable)
+  }
+  else {
+    migrate::begin_export(offer_ptr, &enclaves[eid].migrate_to, image.utm_base, &mut keys)
+      .and_then(|hdr_size| unsafe {
+        checkpoint::seal_with_keys(&keys, image.utm_base + hdr_size, image.utm_size.saturating_sub(hdr_size),
+          any_as_u8_slice(&image), image.epm_base, image.epm_size)
+      })
+      .map(|_| ())
+  };
+
+  opensbi::spin_lock(&mut encl_lock);
+  unpin_copy(eid);
+  if ret.is_ok() {
+    // pinned until now, nothing moved it out of FRESH or STOPPED
+    assert::sm_assert(start_destroying(eid).is_ok() as usize);
+  }
+  opensbi::spin_unlock(&mut encl_lock);
+
+  opensbi::sbi_memset(keys.as_ptr() as usize, 0, keys.len());
+  opensbi::sbi_memset(&image as *const enclave_image as usize, 0, mem::size_of::<enclave_image>());
+
+  ret?;
+  return teardown_enclave(eid);
+}
+
+/*
+ * Re-creates an enclave exported to this device from the blob at the
+ * start of the UTM in `import_args` and writes its id to `eidptr`.
+ * The blob must answer an offer of this SM that was not used yet; the
+ * offer is used up once the enclave is installed.
+ */
+pub fn import_enclave(eidptr: *mut usize, import_args: keystone_sbi_clone) -> Result<(), SmError> {
+  let base: usize = import_args.epm_region.paddr as usize;
+  let size: usize = import_args.epm_region.size as usize;
+  let utbase: usize = import_args.utm_region.paddr as usize;
+  let utsize: usize = import_args.utm_region.size as usize;
+
+  let keys: [u8; migrate::MIGRATE_KEYS_SIZE] = [0; migrate::MIGRATE_KEYS_SIZE];
+  let eid: enclave_id;
+  let image: enclave_image = enclave_image::new();
+  let slot: usize = 0;
+
+  eid = alloc_copy_target(base, size, utbase, utsize)?;
+
+  let begun: Result<usize, SmError> = migrate::begin_import(utbase, &mut keys, &mut slot);
+  let opened: Result<(), SmError> = begun.and_then(|hdr_size| unsafe {
+    checkpoint::open_with_keys(&keys, utbase + hdr_size, utsize.saturating_sub(hdr_size),
+      any_as_u8_slice_mut(&mut image), base, size)
+  });
+  opensbi::sbi_memset(keys.as_ptr() as usize, 0, keys.len());
+
+  let ret: Result<(), SmError> = install_opened_image(eidptr, eid, &image, opened, &import_args,
+    &|| migrate::commit_import(slot));
+  if begun.is_ok() && ret.is_err() {
+    migrate::abort_import(slot);
+  }
+  return ret;
+}
+
+fn thread_exists(eid: enclave_id, tid: usize) -> bool {
+  tid < MAX_ENCL_THREADS && enclaves[eid].threads[tid].status != thread_status::THREAD_INVALID
+}
+
+/* puts thread `tid` on this hart, the enclave runs with its first thread */
+fn thread_enter(eid: enclave_id, tid: usize) {
+  enclaves[eid].threads[tid].status = thread_status::THREAD_RUNNING;
+  enclaves[eid].n_thread += 1;
+  enclaves[eid].state = enclave_state::RUNNING;
+  enclaves[eid].stats.entries += 1;
+  enclaves[eid].entered_at[tid] = opensbi::timer_value();
+}
+
+/* takes thread `tid` off its hart, the enclave stops with its last thread */
+fn thread_leave(eid: enclave_id, tid: usize, status: isize) {
+  let spent: u64 = opensbi::timer_value().wrapping_sub(enclaves[eid].entered_at[tid]);
+
+  enclaves[eid].stats.ticks = enclaves[eid].stats.ticks.saturating_add(spent);
+  enclaves[eid].stats.thread_ticks[tid] = enclaves[eid].stats.thread_ticks[tid].saturating_add(spent);
+  enclaves[eid].stats.thread_last_ticks[tid] = spent;
+  enclaves[eid].threads[tid].status = status;
+  enclaves[eid].n_thread -= 1;
+  if enclaves[eid].n_thread == 0 {
+    // RUNNING -> STOPPED, checked by the caller
+    assert::sm_assert(set_enclave_state(eid, enclave_state::STOPPED).is_ok() as usize);
+  }
+}
+
+// opensbi
+pub fn run_enclave(regs: &mut sbi_trap::sbi_trap_regs, eid: enclave_id, tid: usize) -> Result<(), SmError> {
+  opensbi::spin_lock(&mut encl_lock);
+  if let Err(err) = check_enclave_transition(eid, enclave_state::RUNNING) {
+    opensbi::spin_unlock(&mut encl_lock);
+    return Err(err);
+  }
+  if !thread_exists(eid, tid) || enclaves[eid].threads[tid].status != thread_status::THREAD_FRESH {
+    opensbi::spin_unlock(&mut encl_lock);
+    return Err(SmError::NotFresh);
+  }
+  if enclaves[eid].killed {
+    opensbi::spin_unlock(&mut encl_lock);
+    return Err(SmError::Killed);
+  }
+  if budget_spent(eid) {
+    opensbi::spin_unlock(&mut encl_lock);
+    return Err(SmError::BudgetExceeded);
+  }
+  thread_enter(eid, tid);
+  opensbi::spin_unlock(&mut encl_lock);
+
+  // Enclave is OK to run, context switch to it
+  context_switch_to_enclave(regs, eid, tid, 1);
+
+  return Ok(());
+}
+
+pub fn exit_enclave(regs: &mut sbi_trap::sbi_trap_regs, eid: enclave_id, tid: usize) -> Result<(), SmError> {
+
+  opensbi::spin_lock(&mut encl_lock);
+  let exitable = check_enclave_transition(eid, enclave_state::STOPPED).is_ok()
+    && enclaves[eid].threads[tid].status == thread_status::THREAD_RUNNING;
+  if exitable {
+    thread_leave(eid, tid, thread_status::THREAD_EXITED);
+  }
+  opensbi::spin_unlock(&mut encl_lock);
+
+  if !exitable {
+    return Err(SmError::NotRunning);
+  }
+  context_switch_to_host(regs, eid, tid, false);
+
+  return Ok(());
+
+}
+// opensbi函数
+pub fn stop_enclave(regs: &mut sbi_trap::sbi_trap_regs, request: usize, eid: enclave_id, tid: usize) -> Result<(), SmError> {
+  let stoppable: bool;
+  let over_budget: bool = false;
+  let killed: bool = false;
+
+  opensbi::spin_lock(&mut encl_lock);
+  stoppable = check_enclave_transition(eid, enclave_state::STOPPED).is_ok()
+    && enclaves[eid].threads[tid].status == thread_status::THREAD_RUNNING;
+  if stoppable {
+    // whatever stopped it, a thread of a killed enclave is done
+    killed = enclaves[eid].killed;
+    thread_leave(eid, tid, if killed { thread_status::THREAD_EXITED } else { thread_status::THREAD_STOPPED });
+    // the watchdog comes in as a timer interrupt
+    over_budget = !killed && request == STOP_TIMER_INTERRUPT && thread_over_budget(eid, tid);
+    if over_budget {
+      enclaves[eid].stats.budget_stops += 1;
+    }
+  }
+  opensbi::spin_unlock(&mut encl_lock);
+
+  if !stoppable {
+    return Err(SmError::NotRunning);
+  }
+
+  context_switch_to_host(regs, eid, tid, request == STOP_EDGE_CALL_HOST);
+
+  /* the host learns why the enclave stopped from the error */
+  if killed {
+    return Err(SmError::Killed);
+  }
+  if over_budget {
+    return Err(SmError::BudgetExceeded);
+  }
+  match request {
+    STOP_TIMER_INTERRUPT => return Err(SmError::Interrupted),
+    STOP_EDGE_CALL_HOST => return Err(SmError::EdgeCallHost),
+    STOP_DEBUG_TRAP => return Err(SmError::DebugTrap),
+    _ => return Err(SmError::UnknownError)
+  }
+}
+
+/* what the host may learn of a fault in enclave `eid` */
+fn sanitize_fault(eid: enclave_id, fault: &keystone_sbi_fault) -> keystone_sbi_fault {
+  let ret: keystone_sbi_fault = *fault;
+
+  if enclaves[eid].config.debug != 0 {
+    return ret;
+  }
+  ret.epc &= !(page::RISCV_PGSIZE - 1);
+  ret.tval = match fault.cause {
+    opensbi::CAUSE_MISALIGNED_FETCH | opensbi::CAUSE_FETCH_ACCESS
+      | opensbi::CAUSE_MISALIGNED_LOAD | opensbi::CAUSE_LOAD_ACCESS
+      | opensbi::CAUSE_MISALIGNED_STORE | opensbi::CAUSE_STORE_ACCESS
+      | opensbi::CAUSE_FETCH_PAGE_FAULT | opensbi::CAUSE_LOAD_PAGE_FAULT
+      | opensbi::CAUSE_STORE_PAGE_FAULT => fault.tval & !(page::RISCV_PGSIZE - 1),
+    // the bits of an illegal instruction and the like
+    _ => 0
+  };
+  return ret;
+}
+
+/*
+ * Stops thread `tid` of enclave `eid` on a fault the runtime cannot
+ * handle. The thread is over unless the enclave is a debug one, where it
+ * stays stopped for the debugger.
+ */
+pub fn fault_enclave(regs: &mut sbi_trap::sbi_trap_regs, fault: &keystone_sbi_fault, eid: enclave_id, tid: usize) -> Result<(), SmError> {
+  let stoppable: bool;
+
+  opensbi::spin_lock(&mut encl_lock);
+  stoppable = check_enclave_transition(eid, enclave_state::STOPPED).is_ok()
+    && enclaves[eid].threads[tid].status == thread_status::THREAD_RUNNING;
+  if stoppable {
+    let debug: bool = enclaves[eid].config.debug != 0;
+    thread_leave(eid, tid, if debug { thread_status::THREAD_STOPPED } else { thread_status::THREAD_EXITED });
+    enclaves[eid].faults[tid] = sanitize_fault(eid, fault);
+    enclaves[eid].faulted[tid] = true;
+  }
+  opensbi::spin_unlock(&mut encl_lock);
+
+  if !stoppable {
+    return Err(SmError::NotRunning);
+  }
+  context_switch_to_host(regs, eid, tid, false);
+
+  return Err(SmError::EnclaveFault);
+}
+
+/* the fault thread `tid` of enclave `eid` last stopped on, if it did */
+pub fn thread_fault(eid: enclave_id, tid: usize) -> Option<keystone_sbi_fault> {
+  let ret: Option<keystone_sbi_fault> = None;
+
+  opensbi::spin_lock(&mut encl_lock);
+  if enclave_exists(eid) && tid < MAX_ENCL_THREADS && enclaves[eid].faulted[tid] {
+    ret = Some(enclaves[eid].faults[tid]);
+  }
+  opensbi::spin_unlock(&mut encl_lock);
+  return ret;
+}
+
+/* hands the fault record of thread `tid` of enclave `eid` to `fault_ptr` */
+pub fn get_fault(eid: enclave_id, tid: usize, fault_ptr: usize) -> Result<(), SmError> {
+  let fault: keystone_sbi_fault = match thread_fault(eid, tid) {
+    Some(fault) => fault,
+    None => return Err(SmError::IllegalArgument)
+  };
+
+  if mprv::copy_from_sm(fault_ptr, &fault as *const keystone_sbi_fault as usize, mem::size_of::<keystone_sbi_fault>()) != 0 {
+    return Err(SmError::IllegalArgument);
+  }
+  return Ok(());
+}
+
+/*
+ * Execution budget.
+ * The SM counts the time each thread spends on a hart in timer ticks,
+ * which unlike mcycle agree across harts. An
+ * enclave created with max_ticks cannot be run or resumed once its
+ * threads used that many together; one created with resume_ticks is
+ * stopped when a thread runs longer than that without leaving. Both are
+ * checked when the enclave is stopped by a timer interrupt, and the
+ * watchdog armed on entry makes sure one comes within WATCHDOG_TICKS,
+ * so a thread may overshoot by one watchdog period.
+ */
+
+fn has_budget(eid: enclave_id) -> bool {
+  enclaves[eid].stats.max_ticks != 0 || enclaves[eid].stats.resume_ticks != 0
+}
+
+/* enclave `eid` used up its max_ticks, encl_lock must be held */
+fn budget_spent(eid: enclave_id) -> bool {
+  enclaves[eid].stats.max_ticks != 0 && enclaves[eid].stats.ticks >= enclaves[eid].stats.max_ticks
+}
+
+/* thread `tid`, just off its hart, went over a budget, encl_lock must be held */
+fn thread_over_budget(eid: enclave_id, tid: usize) -> bool {
+  budget_spent(eid)
+    || (enclaves[eid].stats.resume_ticks != 0 && enclaves[eid].stats.thread_last_ticks[tid] >= enclaves[eid].stats.resume_ticks)
+}
+
+/* hands the execution statistics of enclave `eid` to `stats_ptr` */
+pub fn get_stats(eid: enclave_id, stats_ptr: usize) -> Result<(), SmError> {
+  let stats: keystone_sbi_stats;
+  let now: u64 = opensbi::timer_value();
+
+  opensbi::spin_lock(&mut encl_lock);
+  if !enclave_exists(eid) {
+    opensbi::spin_unlock(&mut encl_lock);
+    return Err(SmError::InvalidId);
+  }
+  stats = enclaves[eid].stats;
+  for tid in 0..MAX_ENCL_THREADS {
+    if enclaves[eid].threads[tid].status == thread_status::THREAD_RUNNING {
+      let spent: u64 = now.wrapping_sub(enclaves[eid].entered_at[tid]);
+      stats.ticks = stats.ticks.saturating_add(spent);
+      stats.thread_ticks[tid] = stats.thread_ticks[tid].saturating_add(spent);
+    }
+  }
+  opensbi::spin_unlock(&mut encl_lock);
+
+  if mprv::copy_from_sm(stats_ptr, &stats as *const keystone_sbi_stats as usize, mem::size_of::<keystone_sbi_stats>()) != 0 {
+    return Err(SmError::IllegalArgument);
+  }
+  return Ok(());
+}
+
+/* what the host may know of enclave `eid`, encl_lock must be held */
+fn enclave_info(eid: enclave_id) -> keystone_sbi_enclave_info {
+  let info: keystone_sbi_enclave_info = keystone_sbi_enclave_info::new();
+
+  info.eid = eid as u64;
+  info.state = enclaves[eid].state as u64;
+  for region in enclaves[eid].regions.iter() {
+    let size: u64 = match region.region_type {
+      enclave_region_type::REGION_INVALID => continue,
+      _ => pmp::pmp_region_get_size(region.pmp_rid) as u64
+    };
+    match region.region_type {
+      enclave_region_type::REGION_EPM => info.epm_size += size,
+      enclave_region_type::REGION_UTM => info.utm_size += size,
+      _ => info.donated_size += size
+    }
+  }
+  info.max_threads = enclaves[eid].config.max_threads;
+  for tid in 0..MAX_ENCL_THREADS {
+    match enclaves[eid].threads[tid].status {
+      thread_status::THREAD_RUNNING => info.running_threads += 1,
+      thread_status::THREAD_STOPPED => info.stopped_threads += 1,
+      _ => ()
+    }
+  }
+  if enclaves[eid].state.is_initialized() {
+    info.hash = enclaves[eid].hash;
+  }
+  return info;
+}
+
+/*
+ * Writes to `info_ptr` the first enclave whose id is `from` or above, in
+ * whatever state short of INVALID. The host lists every enclave by asking
+ * again from info.eid + 1 until it gets InvalidId, and learns the state of
+ * a single one by checking info.eid.
+ */
+pub fn query_enclave(from: enclave_id, info_ptr: usize) -> Result<(), SmError> {
+  let info: Option<keystone_sbi_enclave_info> = None;
+
+  opensbi::spin_lock(&mut encl_lock);
+  for eid in from..ENCL_MAX {
+    if enclave_exists(eid) {
+      info = Some(enclave_info(eid));
+      break;
+    }
+  }
+  opensbi::spin_unlock(&mut encl_lock);
+
+  let info: keystone_sbi_enclave_info = match info {
+    Some(info) => info,
+    None => return Err(SmError::InvalidId)
+  };
+  if mprv::copy_from_sm(info_ptr, &info as *const keystone_sbi_enclave_info as usize, mem::size_of::<keystone_sbi_enclave_info>()) != 0 {
+    return Err(SmError::IllegalArgument);
+  }
+  return Ok(());
+}
+
+pub fn resume_enclave(regs: &mut sbi_trap::sbi_trap_regs, eid: enclave_id, tid: usize) -> Result<(), SmError> {
+  return resume_thread(regs, eid, tid, RESUME_NORMAL);
+}
+
+/* resumes stopped thread `tid` as `mode` says */
+fn resume_thread(regs: &mut sbi_trap::sbi_trap_regs, eid: enclave_id, tid: usize, mode: usize) -> Result<(), SmError> {
+  opensbi::spin_lock(&mut encl_lock);
+  if let Err(err) = check_enclave_transition(eid, enclave_state::RUNNING) {
+    opensbi::spin_unlock(&mut encl_lock);
+    return Err(err);
+  }
+  if !thread_exists(eid, tid) || enclaves[eid].threads[tid].status != thread_status::THREAD_STOPPED {
+    opensbi::spin_unlock(&mut encl_lock);
+    return Err(SmError::NotResumable);
+  }
+  if mode != RESUME_NORMAL && enclaves[eid].config.debug == 0 {
+    opensbi::spin_unlock(&mut encl_lock);
+    return Err(SmError::NotAccessible);
+  }
+  if enclaves[eid].killed {
+    opensbi::spin_unlock(&mut encl_lock);
+    return Err(SmError::Killed);
+  }
+  if budget_spent(eid) {
+    opensbi::spin_unlock(&mut encl_lock);
+    return Err(SmError::BudgetExceeded);
+  }
+  thread_enter(eid, tid);
+  enclaves[eid].debug_trap[tid] = mode != RESUME_NORMAL;
+  enclaves[eid].faulted[tid] = false;
+  opensbi::spin_unlock(&mut encl_lock);
+
+  // Enclave is OK to resume, context switch to it
+  context_switch_to_enclave(regs, eid, tid, 0);
+  match mode {
+    RESUME_DEBUG => thread::trap_breakpoints(),
+    RESUME_STEP => thread::arm_single_step(),
+    _ => ()
+  }
+  // the debugger may have patched code
+  if mode != RESUME_NORMAL {
+    opensbi::fence_i();
+  }
+
+  return Ok(());
+}
+
+/*
+ * Debugging.
+ * Enclaves created with ENCLAVE_ATTR_DEBUG let the host read and write
+ * their EPM and the registers of their stopped threads, and single-step
+ * them. The attribute is part of the hashed configuration, so a debug
+ * enclave has a measurement, and with it sealing keys, of its own, and
+ * verifiers see it in every report.
+ */
+
+/* the host may inspect enclave `eid` right now, encl_lock must be held */
+fn check_debuggable(eid: enclave_id) -> Result<(), SmError> {
+  if !enclave_exists(eid) {
+    return Err(SmError::InvalidId);
+  }
+  if enclaves[eid].config.debug == 0 {
+    return Err(SmError::NotAccessible);
+  }
+  // no thread may be changing what the host looks at
+  if enclaves[eid].state != enclave_state::FRESH && enclaves[eid].state != enclave_state::STOPPED {
+    return Err(SmError::NotFresh);
+  }
+  // nor the host what is being copied
+  if enclaves[eid].copies != 0 {
+    return Err(SmError::NotAccessible);
+  }
+  return Ok(());
+}
+
+/* copies [paddr, paddr + size) of the EPM of enclave `eid` to the host buffer `buf` */
+pub fn debug_read(eid: enclave_id, paddr: usize, buf: usize, size: usize) -> Result<(), SmError> {
+  let ret: Result<(), SmError> = Ok(());
+
+  opensbi::spin_lock(&mut encl_lock);
+  if let Err(err) = check_debuggable(eid) {
+    ret = Err(err);
+  }
+  else if !region_contains(eid, enclave_region_type::REGION_EPM, paddr, size) {
+    ret = Err(SmError::NotAccessible);
+  }
+  else if mprv::copy_from_sm(buf, paddr, size) != 0 {
+    ret = Err(SmError::IllegalArgument);
+  }
+  opensbi::spin_unlock(&mut encl_lock);
+  return ret;
+}
+
+/* copies the host buffer `buf` over [paddr, paddr + size) of the EPM of enclave `eid` */
+pub fn debug_write(eid: enclave_id, paddr: usize, buf: usize, size: usize) -> Result<(), SmError> {
+  let ret: Result<(), SmError> = Ok(());
+
+  opensbi::spin_lock(&mut encl_lock);
+  if let Err(err) = check_debuggable(eid) {
+    ret = Err(err);
+  }
+  else if !region_contains(eid, enclave_region_type::REGION_EPM, paddr, size) {
+    ret = Err(SmError::NotAccessible);
+  }
+  else if copy_to_sm(paddr, buf, size) != 0 {
+    ret = Err(SmError::IllegalArgument);
+  }
+  opensbi::spin_unlock(&mut encl_lock);
+  return ret;
+}
+
+/* thread `tid` of enclave `eid` is stopped and may be inspected, encl_lock must be held */
+fn check_debuggable_thread(eid: enclave_id, tid: usize) -> Result<(), SmError> {
+  check_debuggable(eid)?;
+  if !thread_exists(eid, tid) || enclaves[eid].threads[tid].status != thread_status::THREAD_STOPPED {
+    return Err(SmError::NotResumable);
+  }
+  return Ok(());
+}
+
+/* registers of stopped thread `tid` of enclave `eid` */
+fn debug_load_regs(eid: enclave_id, tid: usize, regs: &mut keystone_sbi_regs) -> Result<(), SmError> {
+  opensbi::spin_lock(&mut encl_lock);
+  if let Err(err) = check_debuggable_thread(eid, tid) {
+    opensbi::spin_unlock(&mut encl_lock);
+    return Err(err);
+  }
+  thread::get_prev_regs(&enclaves[eid].threads[tid], &mut regs.x);
+  regs.pc = thread::get_prev_pc(&enclaves[eid].threads[tid]);
+  opensbi::spin_unlock(&mut encl_lock);
+
+  return Ok(());
+}
+
+fn debug_store_regs(eid: enclave_id, tid: usize, regs: &keystone_sbi_regs) -> Result<(), SmError> {
+  opensbi::spin_lock(&mut encl_lock);
+  if let Err(err) = check_debuggable_thread(eid, tid) {
+    opensbi::spin_unlock(&mut encl_lock);
+    return Err(err);
+  }
+  thread::set_prev_regs(&mut enclaves[eid].threads[tid], &regs.x);
+  thread::set_prev_pc(&mut enclaves[eid].threads[tid], regs.pc);
+  opensbi::spin_unlock(&mut encl_lock);
+
+  return Ok(());
+}
+
+/* hands the registers of stopped thread `tid` of enclave `eid` to `regs_ptr` */
+pub fn debug_get_regs(eid: enclave_id, tid: usize, regs_ptr: usize) -> Result<(), SmError> {
+  let regs: keystone_sbi_regs = keystone_sbi_regs::new();
+
+  debug_load_regs(eid, tid, &mut regs)?;
+  if mprv::copy_from_sm(regs_ptr, &regs as *const keystone_sbi_regs as usize, mem::size_of::<keystone_sbi_regs>()) != 0 {
+    return Err(SmError::IllegalArgument);
+  }
+  return Ok(());
+}
+
+/* sets the registers of stopped thread `tid` of enclave `eid` from `regs_ptr` */
+pub fn debug_set_regs(eid: enclave_id, tid: usize, regs_ptr: usize) -> Result<(), SmError> {
+  let regs: keystone_sbi_regs = keystone_sbi_regs::new();
+
+  if copy_to_sm(&regs as *const keystone_sbi_regs as usize, regs_ptr, mem::size_of::<keystone_sbi_regs>()) != 0 {
+    return Err(SmError::IllegalArgument);
+  }
+  return debug_store_regs(eid, tid, &regs);
+}
+
+/*
+ * Physical address of `va` as stopped thread `tid` of enclave `eid` sees
+ * it, None if unmapped or outside its memory. encl_lock must be held.
+ * Walked by hand: MPRV from the host's context cannot get past the PMP
+ * entries guarding the EPM.
+ */
+fn debug_translate(eid: enclave_id, tid: usize, va: usize) -> Option<usize> {
+  let satp: usize = thread::get_prev_satp(&enclaves[eid].threads[tid]);
+  let table: usize = (satp & opensbi::Const::SATP_PPN) << page::RISCV_PGSHIFT;
+  let level: usize;
+
+  if satp == 0 {
+    table = (enclaves[eid].encl_satp & opensbi::Const::SATP_PPN) << page::RISCV_PGSHIFT;
+  }
+  for level in (1..=page::RISCV_PGLEVEL_TOP).rev() {
+    if !region_contains(eid, enclave_region_type::REGION_EPM, table, page::RISCV_PGSIZE) {
+      return None;
+    }
+    let shift: usize = page::RISCV_PGSHIFT + page::page::RISCV_PGLEVEL_BITS * (level - 1);
+    let idx: usize = (va >> shift) & page::page::RISCV_PGLEVEL_MASK;
+    let pte: usize = unsafe { *((table + idx * mem::size_of::<usize>()) as *const usize) };
+    if pte & page::PTE_V == 0 {
+      return None;
+    }
+    let pa: usize = (pte >> page::PTE_PPN_SHIFT) << page::RISCV_PGSHIFT;
+    if pte & (page::PTE_R | page::PTE_W | page::PTE_X) != 0 {
+      return Some(pa + (va & ((1 << shift) - 1)));
+    }
+    table = pa;
+  }
+  return None;
+}
+
+/*
+ * Hands the physical address of `va`, as stopped thread `tid` of debug
+ * enclave `eid` sees it, to `pa_ptr`. Lets a host-side debugger such as
+ * a gdb stub go through the EPM with debug_read and debug_write.
+ */
+pub fn debug_translate_va(eid: enclave_id, tid: usize, va: usize, pa_ptr: usize) -> Result<(), SmError> {
+  let pa: usize;
+
+  opensbi::spin_lock(&mut encl_lock);
+  if let Err(err) = check_debuggable_thread(eid, tid) {
+    opensbi::spin_unlock(&mut encl_lock);
+    return Err(err);
+  }
+  pa = match debug_translate(eid, tid, va) {
+    Some(pa) => pa,
+    None => {
+      opensbi::spin_unlock(&mut encl_lock);
+      return Err(SmError::NotAccessible);
+    }
+  };
+  opensbi::spin_unlock(&mut encl_lock);
+
+  if mprv::copy_from_sm(pa_ptr, &pa as *const usize as usize, mem::size_of::<usize>()) != 0 {
+    return Err(SmError::IllegalArgument);
+  }
+  return Ok(());
+}
+
+/*
+ * Resumes stopped thread `tid` of debug enclave `eid` for a single
+ * instruction. The thread comes back stopped with DebugTrap, or with
+ * whatever stopped it first.
+ */
+pub fn debug_step_enclave(regs: &mut sbi_trap::sbi_trap_regs, eid: enclave_id, tid: usize) -> Result<(), SmError> {
+  return resume_thread(regs, eid, tid, RESUME_STEP);
+}
+
+/* resumes stopped thread `tid` of debug enclave `eid` until it stops or hits a breakpoint */
+pub fn debug_continue_enclave(regs: &mut sbi_trap::sbi_trap_regs, eid: enclave_id, tid: usize) -> Result<(), SmError> {
+  return resume_thread(regs, eid, tid, RESUME_DEBUG);
+}
+
+pub fn attest_enclave(report_ptr: usize, data: usize, size: usize, eid: enclave_id) -> Result<(), SmError> {
+  let attestable: bool;
+  let report: report;
+  let ret: Result<(), SmError>;
+
+  if size > ATTEST_DATA_MAXLEN {
+    return Err(SmError::IllegalArgument);
+  }
+
+  opensbi::spin_lock(&mut encl_lock);
+  attestable = enclave_exists(eid) && enclaves[eid].state.is_initialized();
+
+  if !attestable {
+    opensbi::spin_unlock(&mut encl_lock);
+    return Err(SmError::NotInitialized);
+  }
+
+  unsafe {
+    let enclave_data: usize = (&report.enclave.data as *const u8) as usize;
     
     /* copy data to be signed */
-    ret = copy_enclave_data(enclave_data, data, size);
-    
-    if ret != 0 {
-      ret = ERROR::SBI_ERR_SM_ENCLAVE_NOT_ACCESSIBLE;
+    if copy_enclave_data(enclave_data, data, size).is_err() {
       opensbi::spin_unlock(&mut encl_lock);
-      return ret;
+      return Err(SmError::NotAccessible);
     }
   }
   
@@ -556,7 +2358,9 @@ pub fn attest_enclave(report_ptr: usize, data: usize, size: usize, eid: enclave_
   opensbi::sbi_memcpy(report.sm.hash.as_ptr() as usize, sm::sm_hash.as_ptr() as usize, crypto::MDSIZE);
   opensbi::sbi_memcpy(report.sm.public_key.as_ptr() as usize, sm::sm_public_key.as_ptr() as usize, crypto::PUBLIC_KEY_SIZE);
   opensbi::sbi_memcpy(report.sm.signature.as_ptr() as usize, sm::sm_signature.as_ptr() as usize, crypto::SIGNATURE_SIZE);
+  report.sm.features = sm::SM_FEATURES;
   opensbi::sbi_memcpy(report.enclave.hash.as_ptr() as usize, enclaves[eid].hash.as_ptr() as usize, crypto::MDSIZE);
+  opensbi::sbi_memcpy(&report.enclave.config as *const enclave_config as usize, &enclaves[eid].config as *const enclave_config as usize, mem::size_of::<enclave_config>());
   unsafe {
     let enclave_ptr: &[u8] = any_as_u8_slice(&report.enclave);
     sm::sm_sign(&report.enclave.signature, enclave_ptr, mem::size_of::<enclave_report>() - crypto::SIGNATURE_SIZE - ATTEST_DATA_MAXLEN + size);
@@ -566,101 +2370,305 @@ pub fn attest_enclave(report_ptr: usize, data: usize, size: usize, eid: enclave_
   /* copy report to the enclave */
   ret = copy_enclave_report(&mut enclaves[eid], report_ptr, &mut report);
 
-  if ret != 0 {
-    ret = ERROR::SBI_ERR_SM_ENCLAVE_ILLEGAL_ARGUMENT;
+  opensbi::spin_unlock(&mut encl_lock);
+  return ret;
+}
+
+/* derives the key used to MAC local reports targeting `target_hash` */
+fn derive_report_key(key: &mut [u8], target_hash: &[u8]) -> Result<(), SmError> {
+  let info: [u8; REPORT_KEY_INFO.len() + crypto::MDSIZE] = [0; REPORT_KEY_INFO.len() + crypto::MDSIZE];
+
+  info[..REPORT_KEY_INFO.len()].copy_from_slice(REPORT_KEY_INFO);
+  info[REPORT_KEY_INFO.len()..].copy_from_slice(&target_hash[..crypto::MDSIZE]);
+  if sm::sm_derive_key(key, &info) != 0 {
+    return Err(SmError::UnknownError);
+  }
+  return Ok(());
+}
+
+/*
+ * Produces a local report of enclave `eid` for the enclave measured as
+ * `target_ptr`. Much cheaper than attest_enclave: no signature involved.
+ */
+pub fn local_attest_enclave(report_ptr: usize, target_ptr: usize, data: usize, eid: enclave_id) -> Result<(), SmError> {
+  let report: local_report;
+  let key: [u8; REPORT_KEY_SIZE] = [0; REPORT_KEY_SIZE];
+
+  opensbi::spin_lock(&mut encl_lock);
+  if !enclave_exists(eid) || !enclaves[eid].state.is_initialized() {
     opensbi::spin_unlock(&mut encl_lock);
-    return ret;
+    return Err(SmError::NotInitialized);
   }
 
-  ret = ERROR::SBI_ERR_SM_ENCLAVE_SUCCESS;
+  if copy_enclave_data(report.target.as_ptr() as usize, target_ptr, crypto::MDSIZE).is_err()
+    || copy_enclave_data(report.data.as_ptr() as usize, data, LOCAL_REPORT_DATA_LEN).is_err() {
+    opensbi::spin_unlock(&mut encl_lock);
+    return Err(SmError::NotAccessible);
+  }
 
+  opensbi::sbi_memcpy(report.hash.as_ptr() as usize, enclaves[eid].hash.as_ptr() as usize, crypto::MDSIZE);
+  opensbi::sbi_memcpy(&report.config as *const enclave_config as usize, &enclaves[eid].config as *const enclave_config as usize, mem::size_of::<enclave_config>());
   opensbi::spin_unlock(&mut encl_lock);
+
+  derive_report_key(&mut key, &report.target)?;
+  unsafe {
+    let report_bytes: &[u8] = any_as_u8_slice(&report);
+    crypto::mac(&mut key, &report_bytes[..mem::size_of::<local_report>() - crypto::MDSIZE], &mut report.mac);
+  }
+  opensbi::sbi_memset(key.as_ptr() as usize, 0, REPORT_KEY_SIZE);
+
+  if mprv::copy_from_sm(report_ptr, &report as *const local_report as usize, mem::size_of::<local_report>()) != 0 {
+    return Err(SmError::IllegalArgument);
+  }
+  return Ok(());
+}
+
+/*
+ * Hands enclave `eid` the key to verify local reports targeting it.
+ * The key only depends on the caller's own measurement.
+ */
+pub fn get_report_key(key_ptr: usize, eid: enclave_id) -> Result<(), SmError> {
+  let key: [u8; REPORT_KEY_SIZE] = [0; REPORT_KEY_SIZE];
+  let ret: Result<(), SmError>;
+
+  derive_report_key(&mut key, &enclaves[eid].hash)?;
+
+  if mprv::copy_from_sm(key_ptr, key.as_ptr() as usize, REPORT_KEY_SIZE) != 0 {
+    ret = Err(SmError::IllegalArgument);
+  }
+  else {
+    ret = Ok(());
+  }
+  opensbi::sbi_memset(key.as_ptr() as usize, 0, REPORT_KEY_SIZE);
   return ret;
 }
 
 /* copies data from enclave, source must be inside EPM */
-fn copy_enclave_data(dest: usize, source: usize, size: usize) -> usize {
+fn copy_enclave_data(dest: usize, source: usize, size: usize) -> Result<(), SmError> {
 
   let illegal: i32 = mprv::copy_to_sm(dest, source, size);
 
   if illegal != 0 {
-    return ERROR::SBI_ERR_SM_ENCLAVE_ILLEGAL_ARGUMENT;
+    return Err(SmError::IllegalArgument);
   }
   else {
-    return ERROR::SBI_ERR_SM_ENCLAVE_SUCCESS;
+    return Ok(());
   }
 }
 
 /* copies data into enclave, destination must be inside EPM */
-fn copy_enclave_report(enclave: &mut enclave, dest: usize, source: &mut report) -> usize {
+fn copy_enclave_report(enclave: &mut enclave, dest: usize, source: &mut report) -> Result<(), SmError> {
 
   let illegal: i32 = mprv::copy_from_sm(dest, source as *const report as usize, mem::size_of::<report>());
 
   if illegal != 0 {
-    return ERROR::SBI_ERR_SM_ENCLAVE_ILLEGAL_ARGUMENT;
+    return Err(SmError::IllegalArgument);
+  }
+  else {
+    return Ok(());
+  }
+
+}
+
+pub fn copy_sealing_key_request(src: usize, dest: &mut sealing_key_request) -> Result<(), SmError> {
+  let illegal: i32 = mprv::copy_to_sm(dest as *const sealing_key_request as usize, src, mem::size_of::<sealing_key_request>()); // mprv.rs
+
+  if illegal != 0 {
+    return Err(SmError::IllegalArgument);
   }
   else {
-    return ERROR::SBI_ERR_SM_ENCLAVE_SUCCESS;
+    return Ok(());
   }
+}
 
+fn is_sealing_key_request_valid(request: &sealing_key_request, encl: &enclave) -> bool {
+  if request.policy == 0 || request.policy & !seal_key_policy::SEAL_POLICY_MASK != 0 {
+    return false;
+  }
+  // signer-bound keys need a verified signer
+  if request.policy & seal_key_policy::SEAL_POLICY_SIGNER != 0 && encl.signer == [0; crypto::MDSIZE] {
+    return false;
+  }
+  // an enclave may derive keys of older versions, never of newer ones
+  if request.svn as u64 > encl.config.svn {
+    return false;
+  }
+  if request.key_len == 0 || request.key_len as usize > SEALING_KEY_MAXLEN {
+    return false;
+  }
+  if request.key_ident_size as usize > KEY_IDENT_MAXLEN {
+    return false;
+  }
+  return true;
 }
 
-pub fn get_sealing_key(seal_key: *mut sealing_key, key_ident: &[u8], key_ident_size: usize, eid: enclave_id) -> usize {
-  let key_struct: *mut sealing_key = seal_key;
-  let ret: i32;
+/*
+ * Derives a key bound to the identities selected in the request policy.
+ * info = "keystone-seal" || policy || key_len || [measurement] || [signer]
+ *        || purpose || svn || key_ident
+ * The policy bits and the length keep keys of different requests apart
+ * even where the rest of the info lines up.
+ */
+pub fn get_sealing_key(seal_key: usize, request: &sealing_key_request, eid: enclave_id) -> Result<(), SmError> {
+  let info: [u8; SEALING_KEY_INFO.len() + 8 + 2 * crypto::MDSIZE + 8 + KEY_IDENT_MAXLEN] = [0; SEALING_KEY_INFO.len() + 8 + 2 * crypto::MDSIZE + 8 + KEY_IDENT_MAXLEN];
+  let info_len: usize = 0;
+  let key_len: usize = request.key_len as usize;
+  let ret: Result<(), SmError>;
+
+  if !is_sealing_key_request_valid(request, &enclaves[eid]) {
+    return Err(SmError::IllegalArgument);
+  }
+
+  info[..SEALING_KEY_INFO.len()].copy_from_slice(SEALING_KEY_INFO);
+  info_len += SEALING_KEY_INFO.len();
+  info[info_len..info_len + 4].copy_from_slice(&request.policy.to_le_bytes());
+  info_len += 4;
+  info[info_len..info_len + 4].copy_from_slice(&request.key_len.to_le_bytes());
+  info_len += 4;
+
+  if request.policy & seal_key_policy::SEAL_POLICY_MEASUREMENT != 0 {
+    info[info_len..info_len + crypto::MDSIZE].copy_from_slice(&enclaves[eid].hash);
+    info_len += crypto::MDSIZE;
+  }
+  if request.policy & seal_key_policy::SEAL_POLICY_SIGNER != 0 {
+    info[info_len..info_len + crypto::MDSIZE].copy_from_slice(&enclaves[eid].signer);
+    info_len += crypto::MDSIZE;
+  }
+  info[info_len..info_len + 4].copy_from_slice(&request.purpose.to_le_bytes());
+  info_len += 4;
+  info[info_len..info_len + 4].copy_from_slice(&request.svn.to_le_bytes());
+  info_len += 4;
+  info[info_len..info_len + request.key_ident_size as usize].copy_from_slice(&request.key_ident[..request.key_ident_size as usize]);
+  info_len += request.key_ident_size as usize;
+
+  opensbi::spin_lock(&mut encl_lock);
 
   /* derive key */
-  ret = sm::sm_derive_sealing_key(&mut (*seal_key).key, key_ident, key_ident_size, &enclaves[eid].hash);
-  if ret != 0 {
-    return ERROR::SBI_ERR_SM_ENCLAVE_UNKNOWN_ERROR;
+  if sm::sm_derive_key(&mut sealing_key_buf[..key_len], &info[..info_len]) != 0 {
+    opensbi::spin_unlock(&mut encl_lock);
+    return Err(SmError::UnknownError);
+  }
+
+  /* copy key to the enclave */
+  if mprv::copy_from_sm(seal_key, sealing_key_buf.as_ptr() as usize, key_len) != 0 {
+    ret = Err(SmError::IllegalArgument);
+  }
+  else {
+    ret = Ok(());
   }
 
-  /* sign derived key */
-  sm::sm_sign(&(*key_struct).signature, &(*key_struct).key,
-  SEALING_KEY_SIZE);
+  opensbi::sbi_memset(sealing_key_buf.as_ptr() as usize, 0, key_len);
+  opensbi::spin_unlock(&mut encl_lock);
+  return ret;
+}
 
-  return ERROR::SBI_ERR_SM_ENCLAVE_SUCCESS;
+pub fn get_enclave_hash(eid: enclave_id) -> &'static [u8] {
+  return &enclaves[eid].hash;
 }
 
 fn enclave_exists(eid: usize) -> bool {
-  eid >= 0 && eid < ENCL_MAX && enclaves[eid].state >= 0
+  eid < ENCL_MAX && enclaves[eid].state != enclave_state::INVALID
 }
 
-fn encl_alloc_eid(_eid: &mut enclave_id) -> usize {
-  let eid: enclave_id;
+pub fn enclave_is_initialized(eid: enclave_id) -> bool {
+  enclave_exists(eid) && enclaves[eid].state.is_initialized()
+}
+
+pub fn enclave_is_running(eid: enclave_id) -> bool {
+  eid < ENCL_MAX && enclaves[eid].state == enclave_state::RUNNING
+}
+
+/*
+ * Runs `access` on the EPM page at `paddr` and the UTM range [buf, buf + size)
+ * of enclave `eid`. encl_lock is held from the check to the end of
+ * `access`, so neither can be released in between.
+ */
+pub fn with_epm_page_and_utm(eid: enclave_id, paddr: usize, buf: usize, size: usize, access: &dyn Fn() -> Result<(), SmError>) -> Result<(), SmError> {
+  let ret: Result<(), SmError>;
+
+  if eid >= ENCL_MAX {
+    return Err(SmError::InvalidId);
+  }
+  opensbi::spin_lock(&mut encl_lock);
+  if !region_contains(eid, enclave_region_type::REGION_EPM, paddr, page::RISCV_PGSIZE)
+    || !region_contains(eid, enclave_region_type::REGION_UTM, buf, size) {
+    opensbi::spin_unlock(&mut encl_lock);
+    return Err(SmError::NotAccessible);
+  }
+  ret = access();
+  opensbi::spin_unlock(&mut encl_lock);
+  return ret;
+}
+
+/* true if [paddr, paddr + size) lies within one region of type `region_type` of enclave `eid`, encl_lock must be held */
+fn region_contains(eid: enclave_id, region_type: isize, paddr: usize, size: usize) -> bool {
+  if paddr.checked_add(size).is_none() {
+    return false;
+  }
+  for region in enclaves[eid].regions.iter() {
+    if region.region_type != region_type {
+      continue;
+    }
+    let start: usize = pmp::pmp_region_get_addr(region.pmp_rid) as usize;
+    let end: usize = start + pmp::pmp_region_get_size(region.pmp_rid) as usize;
+    if paddr >= start && paddr + size <= end {
+      return true;
+    }
+  }
+  return false;
+}
+
+/* checks the transition of enclave `eid` to `to`, encl_lock must be held */
+fn check_enclave_transition(eid: enclave_id, to: enclave_state) -> Result<(), SmError> {
+  if eid >= ENCL_MAX {
+    return Err(SmError::InvalidId);
+  }
+  // its memory is being read without encl_lock, see pin_copy
+  if enclaves[eid].copies != 0 {
+    if to == enclave_state::RUNNING {
+      return Err(SmError::NotRunnable);
+    }
+    if to == enclave_state::DESTROYING {
+      return Err(SmError::NotDestroyable);
+    }
+  }
+  return enclaves[eid].state.transition(to);
+}
+
+/* moves enclave `eid` to `to` if the transition is legal, encl_lock must be held */
+fn set_enclave_state(eid: enclave_id, to: enclave_state) -> Result<(), SmError> {
+  check_enclave_transition(eid, to)?;
+  enclaves[eid].state = to;
+  return Ok(());
+}
+
+fn encl_alloc_eid() -> Result<enclave_id, SmError> {
+  let ret: Result<enclave_id, SmError> = Err(SmError::NoFreeResource);
 
   opensbi::spin_lock(&mut encl_lock); // opensbi
 
-  for i in 0..ENCL_MAX {
-    eid = i;
+  for eid in 0..ENCL_MAX {
     if enclaves[eid].state == enclave_state::INVALID {
+      ret = set_enclave_state(eid, enclave_state::ALLOCATED).map(|_| eid);
       break;
     }
   }
 
-  if eid != ENCL_MAX {
-    enclaves[eid].state = enclave_state::ALLOCATED;
-  }
-
   opensbi::spin_unlock(&mut encl_lock); // opensbi 函数
 
-  if eid != ENCL_MAX {
-    *_eid = eid;
-    return ERROR::SBI_ERR_SM_ENCLAVE_SUCCESS;
-  }
-  else {
-    return ERROR::SBI_ERR_SM_ENCLAVE_NO_FREE_RESOURCE;
-  }
+  return ret;
 }
 
-fn encl_free_eid(eid: enclave_id) -> usize {
+fn encl_free_eid(eid: enclave_id) -> Result<(), SmError> {
+  let ret: Result<(), SmError>;
+
   opensbi::spin_lock(&mut encl_lock);
-  enclaves[eid].state = enclave_state::INVALID;
+  ret = set_enclave_state(eid, enclave_state::INVALID);
   opensbi::spin_unlock(&mut encl_lock);
-  return ERROR::SBI_ERR_SM_ENCLAVE_SUCCESS as usize;
+  return ret;
 }
 
-fn clean_enclave_memory(utbase: usize, utsize: usize) -> usize {
+fn clean_enclave_memory(utbase: usize, utsize: usize) {
 
   // This function is quite temporary. See issue #38
 
@@ -668,15 +2676,13 @@ fn clean_enclave_memory(utbase: usize, utsize: usize) -> usize {
   // indeterminate state.
   // opensbi
   opensbi::sbi_memset(utbase, 0, utsize); // opensbi
-
-  return ERROR::SBI_ERR_SM_ENCLAVE_SUCCESS;
 }
 
-fn context_switch_to_enclave(regs: &mut sbi_trap::sbi_trap_regs, eid: enclave_id, load_parameters: i32) {
+fn context_switch_to_enclave(regs: &mut sbi_trap::sbi_trap_regs, eid: enclave_id, tid: usize, load_parameters: i32) {
   /* save host context */
-  thread::swap_prev_state(&mut enclaves[eid].threads[0], regs, 1);
-  thread::swap_prev_mepc(&mut enclaves[eid].threads[0], regs, regs.mepc);
-  thread::swap_prev_mstatus(&mut enclaves[eid].threads[0], regs, regs.mstatus);
+  thread::swap_prev_state(&mut enclaves[eid].threads[tid], regs, 1);
+  thread::swap_prev_mepc(&mut enclaves[eid].threads[tid], regs, regs.mepc);
+  thread::swap_prev_mstatus(&mut enclaves[eid].threads[tid], regs, regs.mstatus);
 
   let interrupts: usize = 0;
   // opensbi
@@ -684,7 +2690,7 @@ fn context_switch_to_enclave(regs: &mut sbi_trap::sbi_trap_regs, eid: enclave_id
 
   if load_parameters != 0 {
     // passing parameters for a first run
-    opensbi::csr_write("sepc", enclaves[eid].params.user_entry as usize);
+    opensbi::csr_write("sepc", enclaves[eid].thread_entries[tid]);
     regs.mepc = (enclaves[eid].params.runtime_entry - 4) as usize; // regs->mepc will be +4 before sbi_ecall_handler return
     // opensbi
     regs.mstatus = 1 << opensbi::MSTATUS_MPP_SHIFT; // opensbi
@@ -702,6 +2708,8 @@ fn context_switch_to_enclave(regs: &mut sbi_trap::sbi_trap_regs, eid: enclave_id
     regs.a6 = enclaves[eid].params.untrusted_ptr as usize;
     // $a7: (usize) utm size
     regs.a7 = enclaves[eid].params.untrusted_size as usize;
+    // $t0: thread id
+    regs.t0 = tid;
 
     // switch to the initial enclave page table
     opensbi::csr_write("satp", enclaves[eid].encl_satp);
@@ -710,40 +2718,81 @@ fn context_switch_to_enclave(regs: &mut sbi_trap::sbi_trap_regs, eid: enclave_id
   thread::switch_vector_enclave();
 
   // set PMP
-  sm::osm_pmp_set(pmp::PMP_NO_PERM as u8);
+  assert::sm_assert(sm::osm_pmp_set(pmp::PMP_NO_PERM as u8).is_ok() as usize);
   let memid: i32;
   for memid in 0..ENCLAVE_REGIONS_MAX {
-    if enclaves[eid].regions[memid].region_type != enclave_region_type::REGION_INVALID {
-      pmp::pmp_set_keystone(enclaves[eid].regions[memid].pmp_rid, pmp::PMP_ALL_PERM as u8);
+    // donated memory stays locked until the enclave accepts it
+    if enclaves[eid].regions[memid].region_type != enclave_region_type::REGION_INVALID
+      && enclaves[eid].regions[memid].region_type != enclave_region_type::REGION_OTHER {
+      // the regions were validated at create time, failing here means corrupt metadata
+      assert::sm_assert(pmp::pmp_set_keystone(enclaves[eid].regions[memid].pmp_rid, enclaves[eid].regions[memid].perm).is_ok() as usize);
+    }
+  }
+
+  shm::set_shared_perm(eid, true);
+
+  // the SM gets the hart back in time to check the budget
+  if has_budget(eid) {
+    let host_deadline: u64 = HOST_DEADLINE_UNKNOWN;
+    if SmError::from_code(platform::platform_timer_deadline(&mut host_deadline)).is_err() {
+      host_deadline = HOST_DEADLINE_UNKNOWN;
+    }
+    let watchdog: u64 = opensbi::timer_value().saturating_add(config::WATCHDOG_TICKS);
+    // a host event due first stops the thread anyway
+    if watchdog < host_deadline {
+      cpu::cpu_save_host_deadline(host_deadline);
+      opensbi::timer_event_start(watchdog);
     }
   }
 
   // Setup any platform specific defenses
   platform::platform_switch_to_enclave(&mut enclaves[eid]);
-  cpu::cpu_enter_enclave_context(eid);
+  cpu::cpu_enter_enclave_context(eid, tid);
 }
 
-fn context_switch_to_host(regs: &mut sbi_trap::sbi_trap_regs, eid: enclave_id, return_on_resume: bool) {
+fn context_switch_to_host(regs: &mut sbi_trap::sbi_trap_regs, eid: enclave_id, tid: usize, return_on_resume: bool) {
 
   // set PMP
   let memid: i32;
   for memid in 0..ENCLAVE_REGIONS_MAX {
-    if enclaves[eid].regions[memid].region_type != enclave_region_type::REGION_INVALID {
-      pmp::pmp_set_keystone(enclaves[eid].regions[memid].pmp_rid, pmp::PMP_NO_PERM as u8);
-    }
+    let perm: u8 = match enclaves[eid].regions[memid].region_type {
+      enclave_region_type::REGION_INVALID => continue,
+      // shared memory stays open to the host
+      enclave_region_type::REGION_UTM => pmp::PMP_ALL_PERM,
+      _ => pmp::PMP_NO_PERM
+    };
+    assert::sm_assert(pmp::pmp_set_keystone(enclaves[eid].regions[memid].pmp_rid, perm).is_ok() as usize);
   }
-  sm::osm_pmp_set(pmp::PMP_ALL_PERM as u8);
+  shm::set_shared_perm(eid, false);
+  // programmed last, the main UTM shares its register with the OS region
+  assert::sm_assert(sm::osm_pmp_set(pmp::PMP_ALL_PERM as u8).is_ok() as usize);
 
   let interrupts: usize = opensbi::MIP_SSIP | opensbi::MIP_STIP | opensbi::MIP_SEIP; // opensbi
   opensbi::csr_write("mideleg", interrupts);
 
+  // a step cut short by another trap must not fire in the host
+  if enclaves[eid].debug_trap[tid] {
+    thread::release_breakpoints();
+    enclaves[eid].debug_trap[tid] = false;
+  }
+
   /* restore host context */
-  thread::swap_prev_state(&mut enclaves[eid].threads[0], regs, return_on_resume as usize);
-  thread::swap_prev_mepc(&mut enclaves[eid].threads[0], regs, regs.mepc);
-  thread::swap_prev_mstatus(&mut enclaves[eid].threads[0], regs, regs.mstatus);
+  thread::swap_prev_state(&mut enclaves[eid].threads[tid], regs, return_on_resume as usize);
+  thread::swap_prev_mepc(&mut enclaves[eid].threads[tid], regs, regs.mepc);
+  thread::swap_prev_mstatus(&mut enclaves[eid].threads[tid], regs, regs.mstatus);
 
   thread::switch_vector_host();
 
+  // give the host back the timer event the watchdog put off, a watchdog
+  // interrupt must not reach it while one that came due still does
+  let host_deadline: u64 = cpu::cpu_take_host_deadline();
+  if host_deadline == HOST_DEADLINE_UNKNOWN {
+    // early, but the host sets its next event from the interrupt
+    opensbi::csr_set("mip", opensbi::MIP_STIP);
+  } else if host_deadline != 0 {
+    opensbi::timer_event_start(host_deadline);
+  }
+
   let pending: usize = opensbi::csr_read("mip");
 
   if pending & opensbi::MIP_MTIP != 0 {
@@ -790,3 +2839,82 @@ pub fn enclave_init_metadata(){
     platform::platform_init_enclave(&mut enclaves[eid]);
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const STATES: [enclave_state; 6] = [
+    enclave_state::INVALID,
+    enclave_state::DESTROYING,
+    enclave_state::ALLOCATED,
+    enclave_state::FRESH,
+    enclave_state::STOPPED,
+    enclave_state::RUNNING,
+  ];
+
+  #[test]
+  fn every_transition() {
+    let ok: Result<(), SmError> = Ok(());
+    let invalid_id: Result<(), SmError> = Err(SmError::InvalidId);
+    let no_free: Result<(), SmError> = Err(SmError::NoFreeResource);
+    let not_fresh: Result<(), SmError> = Err(SmError::NotFresh);
+    let not_init: Result<(), SmError> = Err(SmError::NotInitialized);
+    let not_runnable: Result<(), SmError> = Err(SmError::NotRunnable);
+    let not_running: Result<(), SmError> = Err(SmError::NotRunning);
+    let not_destroyable: Result<(), SmError> = Err(SmError::NotDestroyable);
+    let unknown: Result<(), SmError> = Err(SmError::UnknownError);
+
+    // rows are from, columns to, both in the order of STATES
+    let expected: [[Result<(), SmError>; 6]; 6] = [
+      // INVALID     DESTROYING       ALLOCATED  FRESH      STOPPED      RUNNING
+      [invalid_id, invalid_id,      ok,        invalid_id, invalid_id,  invalid_id],   // INVALID
+      [ok,         not_destroyable, no_free,   not_fresh,  not_running, not_runnable], // DESTROYING
+      [ok,         not_destroyable, no_free,   ok,         ok,          not_init],     // ALLOCATED
+      [unknown,    ok,              no_free,   not_fresh,  not_running, ok],           // FRESH
+      [unknown,    ok,              no_free,   not_fresh,  not_running, ok],           // STOPPED
+      [unknown,    not_destroyable, no_free,   not_fresh,  ok,          ok],           // RUNNING
+    ];
+
+    for (i, from) in STATES.iter().enumerate() {
+      for (j, to) in STATES.iter().enumerate() {
+        assert_eq!(from.transition(*to), expected[i][j], "transition {} -> {}", i, j);
+      }
+    }
+  }
+
+  #[test]
+  fn initialized_states() {
+    for state in STATES.iter() {
+      let expected: bool = *state == enclave_state::FRESH
+        || *state == enclave_state::STOPPED
+        || *state == enclave_state::RUNNING;
+      assert_eq!(state.is_initialized(), expected);
+    }
+  }
+
+  #[test]
+  fn state_values() {
+    // reported to the host as is, see keystone_sbi_enclave_info
+    for (value, state) in STATES.iter().enumerate() {
+      assert_eq!(*state as u64, value as u64);
+    }
+  }
+
+  fn utm(size: u32) -> keystone_sbi_utm_region {
+    return keystone_sbi_utm_region { paddr: 0, size, perm: 0 };
+  }
+
+  #[test]
+  fn extra_utm_limit() {
+    assert!(EXTRA_UTM_USABLE + 2 <= ENCLAVE_REGIONS_MAX);
+    assert!(extra_utms_fit(&[utm(0), utm(0), utm(0)]));
+    for used in 0..=EXTRA_UTM_MAX {
+      let mut regions: [keystone_sbi_utm_region; EXTRA_UTM_MAX] = [utm(0), utm(0), utm(0)];
+      for region in regions.iter_mut().take(used) {
+        region.size = 0x1000;
+      }
+      assert_eq!(extra_utms_fit(&regions), used <= EXTRA_UTM_USABLE, "{} extra UTMs", used);
+    }
+  }
+}
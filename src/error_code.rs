@@ -18,6 +18,18 @@ pub mod ERROR {
     pub const SBI_ERR_SM_ENCLAVE_SBI_PROHIBITED: usize = 100014;
     pub const SBI_ERR_SM_ENCLAVE_ILLEGAL_PTE: usize = 100015;
     pub const SBI_ERR_SM_ENCLAVE_NOT_FRESH: usize = 100016;
+    pub const SBI_ERR_SM_DEVICE_PROVISIONED: usize = 100017;
+    pub const SBI_ERR_SM_DEVICE_NOT_PROVISIONED: usize = 100018;
+    pub const SBI_ERR_SM_COUNTER_INVALID: usize = 100019;
+    pub const SBI_ERR_SM_COUNTER_OVERFLOW: usize = 100030;
+    pub const SBI_ERR_SM_MAILBOX_EMPTY: usize = 100031;
+    pub const SBI_ERR_SM_CHECKPOINT_INVALID: usize = 100032;
+    pub const SBI_ERR_SM_PEER_NOT_TRUSTED: usize = 100033;
+    pub const SBI_ERR_SM_ENCLAVE_DEBUG_TRAP: usize = 100034;
+    pub const SBI_ERR_SM_ENCLAVE_FAULT: usize = 100035;
+    pub const SBI_ERR_SM_ENCLAVE_BUDGET_EXCEEDED: usize = 100036;
+    pub const SBI_ERR_SM_ENCLAVE_KILLED: usize = 100037;
+    pub const SBI_ERR_SM_ENCLAVE_DESTROY_PENDING: usize = 100038;
     pub const SBI_ERR_SM_DEPRECATED: usize = 100099;
     pub const SBI_ERR_SM_NOT_IMPLEMENTED: usize = 100100;
 
@@ -31,3 +43,166 @@ pub mod ERROR {
     pub const SBI_ERR_SM_PMP_REGION_IMPOSSIBLE_TOR: usize = 100026;
 }
 
+
+
+/*
+ * Errors of the security monitor.
+ * Internally every fallible operation returns Result<T, SmError>; the
+ * numeric SBI codes above are only produced at the SBI boundary.
+ */
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum SmError {
+    UnknownError,
+    InvalidId,
+    Interrupted,
+    PmpFailure,
+    NotRunnable,
+    NotDestroyable,
+    RegionOverlaps,
+    NotAccessible,
+    IllegalArgument,
+    NotRunning,
+    NotResumable,
+    EdgeCallHost,
+    NotInitialized,
+    NoFreeResource,
+    SbiProhibited,
+    IllegalPte,
+    NotFresh,
+    DeviceProvisioned,
+    DeviceNotProvisioned,
+    CounterInvalid,
+    CounterOverflow,
+    MailboxEmpty,
+    CheckpointInvalid,
+    PeerNotTrusted,
+    DebugTrap,
+    EnclaveFault,
+    BudgetExceeded,
+    Killed,
+    DestroyPending,
+    Deprecated,
+    NotImplemented,
+
+    PmpRegionSizeInvalid,
+    PmpRegionNotPageGranularity,
+    PmpRegionNotAligned,
+    PmpRegionMaxReached,
+    PmpRegionInvalid,
+    PmpRegionOverlap,
+    PmpRegionImpossibleTor,
+}
+
+const SM_ERRORS: [(SmError, usize); 38] = [
+    (SmError::UnknownError, ERROR::SBI_ERR_SM_ENCLAVE_UNKNOWN_ERROR),
+    (SmError::InvalidId, ERROR::SBI_ERR_SM_ENCLAVE_INVALID_ID),
+    (SmError::Interrupted, ERROR::SBI_ERR_SM_ENCLAVE_INTERRUPTED),
+    (SmError::PmpFailure, ERROR::SBI_ERR_SM_ENCLAVE_PMP_FAILURE),
+    (SmError::NotRunnable, ERROR::SBI_ERR_SM_ENCLAVE_NOT_RUNNABLE),
+    (SmError::NotDestroyable, ERROR::SBI_ERR_SM_ENCLAVE_NOT_DESTROYABLE),
+    (SmError::RegionOverlaps, ERROR::SBI_ERR_SM_ENCLAVE_REGION_OVERLAPS),
+    (SmError::NotAccessible, ERROR::SBI_ERR_SM_ENCLAVE_NOT_ACCESSIBLE),
+    (SmError::IllegalArgument, ERROR::SBI_ERR_SM_ENCLAVE_ILLEGAL_ARGUMENT),
+    (SmError::NotRunning, ERROR::SBI_ERR_SM_ENCLAVE_NOT_RUNNING),
+    (SmError::NotResumable, ERROR::SBI_ERR_SM_ENCLAVE_NOT_RESUMABLE),
+    (SmError::EdgeCallHost, ERROR::SBI_ERR_SM_ENCLAVE_EDGE_CALL_HOST),
+    (SmError::NotInitialized, ERROR::SBI_ERR_SM_ENCLAVE_NOT_INITIALIZED),
+    (SmError::NoFreeResource, ERROR::SBI_ERR_SM_ENCLAVE_NO_FREE_RESOURCE),
+    (SmError::SbiProhibited, ERROR::SBI_ERR_SM_ENCLAVE_SBI_PROHIBITED),
+    (SmError::IllegalPte, ERROR::SBI_ERR_SM_ENCLAVE_ILLEGAL_PTE),
+    (SmError::NotFresh, ERROR::SBI_ERR_SM_ENCLAVE_NOT_FRESH),
+    (SmError::DeviceProvisioned, ERROR::SBI_ERR_SM_DEVICE_PROVISIONED),
+    (SmError::DeviceNotProvisioned, ERROR::SBI_ERR_SM_DEVICE_NOT_PROVISIONED),
+    (SmError::CounterInvalid, ERROR::SBI_ERR_SM_COUNTER_INVALID),
+    (SmError::CounterOverflow, ERROR::SBI_ERR_SM_COUNTER_OVERFLOW),
+    (SmError::MailboxEmpty, ERROR::SBI_ERR_SM_MAILBOX_EMPTY),
+    (SmError::CheckpointInvalid, ERROR::SBI_ERR_SM_CHECKPOINT_INVALID),
+    (SmError::PeerNotTrusted, ERROR::SBI_ERR_SM_PEER_NOT_TRUSTED),
+    (SmError::DebugTrap, ERROR::SBI_ERR_SM_ENCLAVE_DEBUG_TRAP),
+    (SmError::EnclaveFault, ERROR::SBI_ERR_SM_ENCLAVE_FAULT),
+    (SmError::BudgetExceeded, ERROR::SBI_ERR_SM_ENCLAVE_BUDGET_EXCEEDED),
+    (SmError::Killed, ERROR::SBI_ERR_SM_ENCLAVE_KILLED),
+    (SmError::DestroyPending, ERROR::SBI_ERR_SM_ENCLAVE_DESTROY_PENDING),
+    (SmError::Deprecated, ERROR::SBI_ERR_SM_DEPRECATED),
+    (SmError::NotImplemented, ERROR::SBI_ERR_SM_NOT_IMPLEMENTED),
+    (SmError::PmpRegionSizeInvalid, ERROR::SBI_ERR_SM_PMP_REGION_SIZE_INVALID),
+    (SmError::PmpRegionNotPageGranularity, ERROR::SBI_ERR_SM_PMP_REGION_NOT_PAGE_GRANULARITY),
+    (SmError::PmpRegionNotAligned, ERROR::SBI_ERR_SM_PMP_REGION_NOT_ALIGNED),
+    (SmError::PmpRegionMaxReached, ERROR::SBI_ERR_SM_PMP_REGION_MAX_REACHED),
+    (SmError::PmpRegionInvalid, ERROR::SBI_ERR_SM_PMP_REGION_INVALID),
+    (SmError::PmpRegionOverlap, ERROR::SBI_ERR_SM_PMP_REGION_OVERLAP),
+    (SmError::PmpRegionImpossibleTor, ERROR::SBI_ERR_SM_PMP_REGION_IMPOSSIBLE_TOR),
+];
+
+impl SmError {
+    /* numeric SBI error code of this error */
+    pub fn code(&self) -> usize {
+        for (err, code) in SM_ERRORS.iter() {
+            if err == self {
+                return *code;
+            }
+        }
+        return ERROR::SBI_ERR_SM_ENCLAVE_UNKNOWN_ERROR;
+    }
+
+    /* Ok(()) for the success code, the matching error otherwise */
+    pub fn from_code(code: usize) -> Result<(), SmError> {
+        if code == ERROR::SBI_ERR_SM_ENCLAVE_SUCCESS {
+            return Ok(());
+        }
+        for (err, c) in SM_ERRORS.iter() {
+            if *c == code {
+                return Err(*err);
+            }
+        }
+        return Err(SmError::UnknownError);
+    }
+}
+
+impl From<SmError> for usize {
+    fn from(err: SmError) -> usize {
+        err.code()
+    }
+}
+
+/* turns a result into the value returned to the SBI caller in a0 */
+pub fn sbi_ret<T>(result: Result<T, SmError>) -> usize {
+    match result {
+        Ok(_) => ERROR::SBI_ERR_SM_ENCLAVE_SUCCESS,
+        Err(err) => err.code()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_round_trip() {
+        for (err, code) in SM_ERRORS.iter() {
+            assert_eq!(err.code(), *code, "{:?}", err);
+            assert_eq!(SmError::from_code(*code), Err(*err), "{:?}", err);
+            assert_eq!(usize::from(*err), *code);
+            assert_eq!(sbi_ret::<()>(Err(*err)), *code);
+        }
+        assert_eq!(SmError::from_code(ERROR::SBI_ERR_SM_ENCLAVE_SUCCESS), Ok(()));
+        assert_eq!(sbi_ret(Ok(())), ERROR::SBI_ERR_SM_ENCLAVE_SUCCESS);
+    }
+
+    #[test]
+    fn codes_are_unique() {
+        for (i, (err, code)) in SM_ERRORS.iter().enumerate() {
+            assert_ne!(*code, ERROR::SBI_ERR_SM_ENCLAVE_SUCCESS, "{:?}", err);
+            for (other_err, other_code) in SM_ERRORS[i + 1..].iter() {
+                assert_ne!(err, other_err);
+                assert_ne!(code, other_code, "{:?} and {:?}", err, other_err);
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_codes() {
+        assert_eq!(SmError::from_code(1), Err(SmError::UnknownError));
+        assert_eq!(SmError::from_code(usize::MAX), Err(SmError::UnknownError));
+    }
+}
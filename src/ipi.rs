@@ -1,45 +1,58 @@
 use crate::pmp;
+use crate::assert;
+use crate::cpu;
+use crate::config;
 use crate::opensbi;
-use crate::sbi_trap;
 
 pub const SBI_PMP_IPI_TYPE_SET: usize = 0;
 pub const SBI_PMP_IPI_TYPE_UNSET: usize = 1;
+pub const SBI_PMP_IPI_TYPE_SET_OWNED: usize = 2; // perm on harts of the owners only
 
+/* owners are passed as a bitmask of enclave ids */
+const _: () = assert!(config::ENCL_MAX <= opensbi::Const::BITS_PER_LONG, "ENCL_MAX does not fit the PMP IPI owner mask");
+
+/* laid over the first fields of sbi_tlb_info */
+#[repr(C)]
 struct sbi_pmp_ipi_info {
   info_type: usize,
-  __dummy: usize,
+  owners: usize,
   rid: usize,
   perm: usize
 }
 
 // opensbi
-pub fn sbi_pmp_ipi_local_update(__info: *mut opensbi::sbi_tlb_info) {
+pub extern "C" fn sbi_pmp_ipi_local_update(__info: *mut opensbi::sbi_tlb_info) {
   unsafe {
     let info: *mut sbi_pmp_ipi_info = __info as *mut sbi_pmp_ipi_info;
-    if (*info).info_type == SBI_PMP_IPI_TYPE_SET {
-      pmp::pmp_set_keystone((*info).rid as i32, (*info).perm as u8);
-    }
-    else {
-      pmp::pmp_unset((*info).rid as i32);
-    }
+    let ret = match (*info).info_type {
+      SBI_PMP_IPI_TYPE_SET => pmp::pmp_set_keystone((*info).rid as i32, (*info).perm as u8),
+      SBI_PMP_IPI_TYPE_SET_OWNED => {
+        // a hart running one of the owners keeps using the region
+        let owned: bool = cpu::cpu_is_enclave_context() != 0
+          && (*info).owners & (1 << cpu::cpu_get_enclave_id()) != 0;
+        pmp::pmp_set_keystone((*info).rid as i32, if owned { (*info).perm as u8 } else { pmp::PMP_NO_PERM })
+      },
+      _ => pmp::pmp_unset((*info).rid as i32)
+    };
+    // the sender validated the region, harts must not disagree on PMP state
+    assert::sm_assert(ret.is_ok() as usize);
   }
 }
 
-// pub fn send_and_sync_pmp_ipi(region_idx: i32, ipi_type: i32, perm: u8) {
-//   let mask: usize = 0;
-//   let source_hart: usize = opensbi::current_hartid(); // opensbi
-//   let tlb_info: opensbi::sbi_tlb_info; // opensbi
-//   opensbi::sbi_hsm_hart_started_mask(0, &mut mask); // opensbi
-
-//   tlb_info.start = ipi_type as usize;
-//   tlb_info.size = 0;
-//   tlb_info.asid = region_idx as usize;
-//   tlb_info.vmid = perm as usize;
-//   tlb_info.local_fn = sbi_pmp_ipi_local_update;
-//   opensbi::SBI_HARTMASK_INIT_EXCEPT(tlb_info.smask, source_hart);
-
-//   SBI_TLB_INFO_INIT(&tlb_info, ipi_type, 0, region_idx, perm, // opensbi
-//       sbi_pmp_ipi_local_update, source_hart);
-//   opensbi::sbi_tlb_request(mask, 0, &mut tlb_info);
-// }
+/* reprograms region `region_idx` on every started hart, this one included, and waits for all */
+pub fn send_and_sync_pmp_ipi(region_idx: i32, ipi_type: usize, perm: u8, owners: usize) {
+  let mask: usize = 0;
+  let source_hart: usize = opensbi::current_hartid(); // opensbi
+  let tlb_info: opensbi::sbi_tlb_info = opensbi::sbi_tlb_info {
+    start: ipi_type,
+    size: owners,
+    asid: region_idx as usize,
+    vmid: perm as usize,
+    local_fn: sbi_pmp_ipi_local_update,
+    smask: opensbi::sbi_hartmask::new()
+  };
 
+  opensbi::sbi_hsm_hart_started_mask(0, &mut mask); // opensbi
+  opensbi::SBI_HARTMASK_INIT_EXCEPT(&mut tlb_info.smask, source_hart);
+  assert::sm_assert((opensbi::tlb_request(mask, 0, &mut tlb_info) == 0) as usize);
+}
@@ -0,0 +1,149 @@
+use crate::error_code::SmError;
+use crate::enclave;
+use crate::enclave::enclave_id;
+use crate::config;
+use crate::crypto;
+use crate::mprv;
+use crate::opensbi;
+
+use std::mem;
+
+/*
+ * SM-mediated mailboxes between enclaves, reached through
+ * SBI_SM_CALL_PLUGIN. Every enclave has a bounded inbox; messages are
+ * copied through the SM and tagged with the sender's measurement, so the
+ * receiver knows who sent them without trusting the host.
+ */
+
+pub const PLUGIN_ID_MAILBOX: usize = 1;
+
+/* call ids of the mailbox plugin */
+pub const MAILBOX_SEND: usize = 0;
+pub const MAILBOX_RECV: usize = 1;
+
+const MAILBOX_SLOTS: usize = 4;
+pub const MAILBOX_MSG_MAXLEN: usize = 256;
+
+/* message as passed to send */
+pub struct mailbox_message {
+  pub size: usize,
+  pub data: [u8; MAILBOX_MSG_MAXLEN]
+}
+
+/* message as handed out by recv */
+#[derive(Clone, Copy)]
+pub struct mailbox_delivery {
+  pub sender_eid: enclave_id,
+  pub sender_hash: [u8; crypto::MDSIZE],
+  pub size: usize,
+  pub data: [u8; MAILBOX_MSG_MAXLEN]
+}
+
+impl mailbox_delivery {
+  pub fn new() -> Self {
+    Self {
+      sender_eid: 0,
+      sender_hash: [0; crypto::MDSIZE],
+      size: 0,
+      data: [0; MAILBOX_MSG_MAXLEN]
+    }
+  }
+}
+
+#[derive(Clone, Copy)]
+struct inbox {
+  head: usize,
+  count: usize,
+  slots: [mailbox_delivery; MAILBOX_SLOTS]
+}
+
+impl inbox {
+  pub fn new() -> Self {
+    Self {
+      head: 0,
+      count: 0,
+      slots: [mailbox_delivery::new(); MAILBOX_SLOTS]
+    }
+  }
+}
+
+static mailbox_lock: opensbi::spinlock_t = opensbi::spinlock_t::new();
+
+static mut inboxes: [inbox; config::ENCL_MAX] = [inbox::new(); config::ENCL_MAX];
+
+/* copies the message at `msg_ptr` of enclave `sender` into the inbox of `dest` */
+pub fn mailbox_send(sender: enclave_id, dest: enclave_id, msg_ptr: usize) -> Result<(), SmError> {
+  let msg: mailbox_message;
+
+  if dest >= config::ENCL_MAX {
+    return Err(SmError::InvalidId);
+  }
+  if mprv::copy_to_sm(&msg as *const mailbox_message as usize, msg_ptr, mem::size_of::<mailbox_message>()) != 0 {
+    return Err(SmError::IllegalArgument);
+  }
+  if msg.size > MAILBOX_MSG_MAXLEN {
+    return Err(SmError::IllegalArgument);
+  }
+
+  opensbi::spin_lock(&mut mailbox_lock);
+  // checked under mailbox_lock: teardown marks `dest` DESTROYING before
+  // mailbox_clear, so nothing gets in after the clear
+  if !enclave::enclave_is_initialized(dest) {
+    opensbi::spin_unlock(&mut mailbox_lock);
+    return Err(SmError::InvalidId);
+  }
+  let ib: &mut inbox = &mut inboxes[dest];
+  if ib.count == MAILBOX_SLOTS {
+    opensbi::spin_unlock(&mut mailbox_lock);
+    return Err(SmError::NoFreeResource);
+  }
+  let slot: &mut mailbox_delivery = &mut ib.slots[(ib.head + ib.count) % MAILBOX_SLOTS];
+  slot.sender_eid = sender;
+  slot.sender_hash.copy_from_slice(enclave::get_enclave_hash(sender));
+  slot.size = msg.size;
+  slot.data[..msg.size].copy_from_slice(&msg.data[..msg.size]);
+  ib.count += 1;
+  opensbi::spin_unlock(&mut mailbox_lock);
+
+  return Ok(());
+}
+
+/* hands the oldest message in the inbox of `eid` to `msg_ptr` */
+pub fn mailbox_recv(eid: enclave_id, msg_ptr: usize) -> Result<(), SmError> {
+  opensbi::spin_lock(&mut mailbox_lock);
+  let ib: &mut inbox = &mut inboxes[eid];
+  if ib.count == 0 {
+    opensbi::spin_unlock(&mut mailbox_lock);
+    return Err(SmError::MailboxEmpty);
+  }
+
+  // only dequeue once the receiver actually got it
+  let delivery: &mailbox_delivery = &ib.slots[ib.head];
+  if mprv::copy_from_sm(msg_ptr, delivery as *const mailbox_delivery as usize, mem::size_of::<mailbox_delivery>()) != 0 {
+    opensbi::spin_unlock(&mut mailbox_lock);
+    return Err(SmError::IllegalArgument);
+  }
+  ib.slots[ib.head] = mailbox_delivery::new();
+  ib.head = (ib.head + 1) % MAILBOX_SLOTS;
+  ib.count -= 1;
+  opensbi::spin_unlock(&mut mailbox_lock);
+
+  return Ok(());
+}
+
+/* drops the inbox of `eid`, its next owner must not see old messages.
+ * `eid` must no longer be initialized, see mailbox_send */
+pub fn mailbox_clear(eid: enclave_id) {
+  opensbi::spin_lock(&mut mailbox_lock);
+  inboxes[eid] = inbox::new();
+  opensbi::spin_unlock(&mut mailbox_lock);
+}
+
+/* entry point of the plugin, called for enclave `eid` */
+pub fn mailbox_call(eid: enclave_id, call_id: usize, arg0: usize, arg1: usize) -> Result<(), SmError> {
+  match call_id {
+    MAILBOX_SEND => mailbox_send(eid, arg0, arg1),
+    MAILBOX_RECV => mailbox_recv(eid, arg0),
+    _ => Err(SmError::NotImplemented)
+  }
+}
@@ -16,6 +16,15 @@ mod assert;
 mod opensbi;
 mod sbi_trap;
 mod sbi_trap_hack;
+mod provision;
+mod storage;
+mod counter;
+mod config;
+mod shm;
+mod mailbox;
+mod checkpoint;
+mod migrate;
+mod swap;
 
 fn main() {
     println!("hello world");
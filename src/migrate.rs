@@ -0,0 +1,283 @@
+use crate::error_code::SmError;
+use crate::checkpoint;
+use crate::crypto;
+use crate::mprv;
+use crate::opensbi;
+use crate::sm;
+
+use std::mem;
+
+/*
+ * Moving an enclave to another device.
+ *
+ *   destination SM                      source SM
+ *   prepare()      -- offer -->         export(): checks the offer, seals
+ *                                       the enclave to it, destroys it
+ *   import()       <-- blob --
+ *
+ * The offer carries the sm_report of the destination (its SM measurement
+ * and key, certified by its device key) and a fresh challenge signed by
+ * the SM key. Both sides must run the same SM and agree on a key by
+ * X25519 between their SM keys, salted with the challenge. The source
+ * only exports to the device key the enclave authorized, and tears the
+ * enclave down before the blob leaves, and refuses while another
+ * instance of its measurement lives on the source (see export_enclave).
+ * The destination accepts each challenge once, so a blob cannot be
+ * imported twice: an import holds the challenge while it runs and uses
+ * it up only once the enclave is installed, a failed one gives it back.
+ *
+ * There is no root certifying device keys here. The source pins the
+ * destination through the enclave's authorization; the destination
+ * accepts any device that certifies this SM and must be vouched for by
+ * the verifier of the enclave's next attestation.
+ */
+
+const MIGRATIONS_PENDING_MAX: usize = 4;
+
+/* keys handed out by begin_export and begin_import */
+pub const MIGRATE_KEYS_SIZE: usize = checkpoint::CHECKPOINT_KEYS_SIZE;
+
+const MIGRATE_KEY_INFO: &[u8] = b"keystone-migrate";
+const MIGRATE_CHALLENGE_INFO: &[u8] = b"keystone-migrate-challenge";
+
+/* sm_report of a device as sent over the wire */
+#[derive(Clone, Copy)]
+pub struct sm_identity {
+  pub sm_hash: [u8; crypto::MDSIZE],
+  pub sm_public_key: [u8; crypto::PUBLIC_KEY_SIZE],
+  pub sm_signature: [u8; crypto::SIGNATURE_SIZE], // device key over (sm_hash || sm_public_key)
+  pub dev_public_key: [u8; crypto::PUBLIC_KEY_SIZE]
+}
+
+impl sm_identity {
+  pub fn new() -> Self {
+    Self {
+      sm_hash: [0; crypto::MDSIZE],
+      sm_public_key: [0; crypto::PUBLIC_KEY_SIZE],
+      sm_signature: [0; crypto::SIGNATURE_SIZE],
+      dev_public_key: [0; crypto::PUBLIC_KEY_SIZE]
+    }
+  }
+}
+
+/* made by the destination, handed to the source */
+pub struct migration_offer {
+  pub dest: sm_identity,
+  pub challenge: [u8; crypto::MDSIZE],
+  pub signature: [u8; crypto::SIGNATURE_SIZE] // destination SM key over the challenge
+}
+
+impl migration_offer {
+  pub fn new() -> Self {
+    Self {
+      dest: sm_identity::new(),
+      challenge: [0; crypto::MDSIZE],
+      signature: [0; crypto::SIGNATURE_SIZE]
+    }
+  }
+}
+
+/* in front of the sealed enclave in an exported blob */
+pub struct migration_header {
+  pub source: sm_identity,
+  pub challenge: [u8; crypto::MDSIZE]
+}
+
+impl migration_header {
+  pub fn new() -> Self {
+    Self {
+      source: sm_identity::new(),
+      challenge: [0; crypto::MDSIZE]
+    }
+  }
+}
+
+#[derive(Clone, Copy)]
+struct pending_migration {
+  in_use: bool,
+  reserved: bool, // held by an import in progress
+  challenge: [u8; crypto::MDSIZE]
+}
+
+impl pending_migration {
+  pub fn new() -> Self {
+    Self {
+      in_use: false,
+      reserved: false,
+      challenge: [0; crypto::MDSIZE]
+    }
+  }
+}
+
+static migrate_lock: opensbi::spinlock_t = opensbi::spinlock_t::new();
+
+static mut pending: [pending_migration; MIGRATIONS_PENDING_MAX] = [pending_migration::new(); MIGRATIONS_PENDING_MAX];
+static mut next_pending: usize = 0;
+static mut challenge_seq: u64 = 0;
+
+fn own_identity() -> sm_identity {
+  let id: sm_identity = sm_identity::new();
+
+  id.sm_hash = sm::sm_hash;
+  id.sm_public_key = sm::sm_public_key;
+  id.sm_signature = sm::sm_signature;
+  id.dev_public_key = sm::dev_public_key;
+  return id;
+}
+
+/* the peer is certified by its device key and runs this very SM */
+fn verify_identity(id: &sm_identity) -> Result<(), SmError> {
+  let cert: [u8; crypto::MDSIZE + crypto::PUBLIC_KEY_SIZE] = [0; crypto::MDSIZE + crypto::PUBLIC_KEY_SIZE];
+
+  if id.sm_hash != sm::sm_hash {
+    return Err(SmError::PeerNotTrusted);
+  }
+  cert[..crypto::MDSIZE].copy_from_slice(&id.sm_hash);
+  cert[crypto::MDSIZE..].copy_from_slice(&id.sm_public_key);
+  if !crypto::verify(&id.sm_signature, &cert, &id.dev_public_key) {
+    return Err(SmError::PeerNotTrusted);
+  }
+  return Ok(());
+}
+
+/* the keys a blob is sealed under between us and the SM owning `peer_sm_public_key` */
+fn derive_keys(peer_sm_public_key: &[u8], challenge: &[u8], keys: &mut [u8]) -> Result<(), SmError> {
+  let shared: [u8; crypto::PUBLIC_KEY_SIZE] = [0; crypto::PUBLIC_KEY_SIZE];
+  let salt: [u8; crypto::MDSIZE] = [0; crypto::MDSIZE];
+  let ret: i32;
+
+  crypto::key_exchange(&mut shared, peer_sm_public_key, &sm::sm_private_key);
+  salt.copy_from_slice(challenge);
+  ret = crypto::kdf(&mut salt, &shared, MIGRATE_KEY_INFO, keys);
+  opensbi::sbi_memset(shared.as_ptr() as usize, 0, shared.len());
+  if ret != 0 {
+    return Err(SmError::UnknownError);
+  }
+  return Ok(());
+}
+
+/*
+ * Destination: writes a new offer to `offer_ptr`.
+ * With no entropy source the challenge is derived from the SM key, a
+ * sequence number and mcycle, which differs across reboots. The oldest
+ * pending offer is dropped once MIGRATIONS_PENDING_MAX are outstanding,
+ * unless an import holds it.
+ */
+pub fn prepare(offer_ptr: usize) -> Result<(), SmError> {
+  let offer: migration_offer = migration_offer::new();
+  let info: [u8; 64] = [0; 64];
+  let info_len: usize = MIGRATE_CHALLENGE_INFO.len() + 16;
+
+  opensbi::spin_lock(&mut migrate_lock);
+  info[..MIGRATE_CHALLENGE_INFO.len()].copy_from_slice(MIGRATE_CHALLENGE_INFO);
+  info[MIGRATE_CHALLENGE_INFO.len()..MIGRATE_CHALLENGE_INFO.len() + 8].copy_from_slice(&challenge_seq.to_le_bytes());
+  info[MIGRATE_CHALLENGE_INFO.len() + 8..info_len].copy_from_slice(&(opensbi::csr_read("mcycle") as u64).to_le_bytes());
+  challenge_seq += 1;
+  if sm::sm_derive_key(&mut offer.challenge, &info[..info_len]) != 0 {
+    opensbi::spin_unlock(&mut migrate_lock);
+    return Err(SmError::UnknownError);
+  }
+  for _ in 0..MIGRATIONS_PENDING_MAX {
+    if !pending[next_pending].reserved {
+      break;
+    }
+    next_pending = (next_pending + 1) % MIGRATIONS_PENDING_MAX;
+  }
+  if pending[next_pending].reserved {
+    opensbi::spin_unlock(&mut migrate_lock);
+    return Err(SmError::NoFreeResource);
+  }
+  pending[next_pending].in_use = true;
+  pending[next_pending].challenge = offer.challenge;
+  next_pending = (next_pending + 1) % MIGRATIONS_PENDING_MAX;
+  opensbi::spin_unlock(&mut migrate_lock);
+
+  offer.dest = own_identity();
+  offer.signature = crypto::sign(&offer.challenge, &sm::sm_public_key, &sm::sm_private_key);
+
+  if mprv::copy_from_sm(offer_ptr, &offer as *const migration_offer as usize, mem::size_of::<migration_offer>()) != 0 {
+    return Err(SmError::IllegalArgument);
+  }
+  return Ok(());
+}
+
+/*
+ * Source: checks the offer at `offer_ptr` against the destination device
+ * key the enclave authorized, then writes the blob header to `blob` and
+ * the keys to seal the enclave with to `keys`.
+ * Returns the size of the header.
+ */
+pub fn begin_export(offer_ptr: usize, authorized_dev_key: &[u8], blob: usize, keys: &mut [u8]) -> Result<usize, SmError> {
+  let offer: migration_offer = migration_offer::new();
+  let header: migration_header = migration_header::new();
+
+  if mprv::copy_to_sm(&offer as *const migration_offer as usize, offer_ptr, mem::size_of::<migration_offer>()) != 0 {
+    return Err(SmError::IllegalArgument);
+  }
+  if offer.dest.dev_public_key[..] != authorized_dev_key[..] {
+    return Err(SmError::PeerNotTrusted);
+  }
+  verify_identity(&offer.dest)?;
+  if !crypto::verify(&offer.signature, &offer.challenge, &offer.dest.sm_public_key) {
+    return Err(SmError::PeerNotTrusted);
+  }
+
+  derive_keys(&offer.dest.sm_public_key, &offer.challenge, keys)?;
+
+  header.source = own_identity();
+  header.challenge = offer.challenge;
+  opensbi::sbi_memcpy(blob, &header as *const migration_header as usize, mem::size_of::<migration_header>());
+  return Ok(mem::size_of::<migration_header>());
+}
+
+/*
+ * Destination: checks the header of the blob at `blob` and reserves its
+ * challenge in `slot`, then writes the keys to open the enclave with to
+ * `keys`. The caller ends the import with commit_import or abort_import.
+ * Returns the size of the header.
+ */
+pub fn begin_import(blob: usize, keys: &mut [u8], slot: &mut usize) -> Result<usize, SmError> {
+  let header: migration_header = migration_header::new();
+  let found: bool = false;
+
+  // the blob is in host memory, read it exactly once
+  opensbi::sbi_memcpy(&header as *const migration_header as usize, blob, mem::size_of::<migration_header>());
+  verify_identity(&header.source)?;
+
+  opensbi::spin_lock(&mut migrate_lock);
+  for i in 0..MIGRATIONS_PENDING_MAX {
+    // an import in progress holds it, a second one is a replay
+    if pending[i].in_use && !pending[i].reserved && pending[i].challenge == header.challenge {
+      pending[i].reserved = true;
+      *slot = i;
+      found = true;
+      break;
+    }
+  }
+  opensbi::spin_unlock(&mut migrate_lock);
+
+  if !found {
+    return Err(SmError::PeerNotTrusted);
+  }
+
+  if let Err(err) = derive_keys(&header.source.sm_public_key, &header.challenge, keys) {
+    abort_import(*slot);
+    return Err(err);
+  }
+  return Ok(mem::size_of::<migration_header>());
+}
+
+/* the enclave of the import holding `slot` is installed, its challenge is used up */
+pub fn commit_import(slot: usize) -> Result<(), SmError> {
+  opensbi::spin_lock(&mut migrate_lock);
+  pending[slot] = pending_migration::new();
+  opensbi::spin_unlock(&mut migrate_lock);
+  return Ok(());
+}
+
+/* the import holding `slot` failed, its challenge may be used again */
+pub fn abort_import(slot: usize) {
+  opensbi::spin_lock(&mut migrate_lock);
+  pending[slot].reserved = false;
+  opensbi::spin_unlock(&mut migrate_lock);
+}
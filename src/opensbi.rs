@@ -71,12 +71,39 @@ pub const MSTATUS_TW: usize = 0x00200000;
 pub const MSTATUS_TSR: usize = 0x00400000;
 pub const MSTATUS32_SD: usize = 0x80000000;
 
+pub const PRV_U: usize = 0;
+pub const PRV_S: usize = 1;
+pub const PRV_M: usize = 3;
+
+pub const CAUSE_MISALIGNED_FETCH: usize = 0x0;
+pub const CAUSE_FETCH_ACCESS: usize = 0x1;
+pub const CAUSE_ILLEGAL_INSTRUCTION: usize = 0x2;
+pub const CAUSE_BREAKPOINT: usize = 0x3;
+pub const CAUSE_MISALIGNED_LOAD: usize = 0x4;
+pub const CAUSE_LOAD_ACCESS: usize = 0x5;
+pub const CAUSE_MISALIGNED_STORE: usize = 0x6;
+pub const CAUSE_STORE_ACCESS: usize = 0x7;
+pub const CAUSE_USER_ECALL: usize = 0x8;
+pub const CAUSE_SUPERVISOR_ECALL: usize = 0x9;
+pub const CAUSE_MACHINE_ECALL: usize = 0xb;
+pub const CAUSE_FETCH_PAGE_FAULT: usize = 0xc;
+pub const CAUSE_LOAD_PAGE_FAULT: usize = 0xd;
+pub const CAUSE_STORE_PAGE_FAULT: usize = 0xf;
+
+/* Sdtrig instruction count trigger, tdata1 fields */
+pub const TDATA1_TYPE_ICOUNT: usize = 3;
+pub const ICOUNT_COUNT_SHIFT: usize = 10;
+pub const ICOUNT_M: usize = 1 << 9;
+pub const ICOUNT_S: usize = 1 << 7;
+pub const ICOUNT_U: usize = 1 << 6;
+
 
 #[cfg(target_pointer_width = "64")]
 pub mod Const {
 	pub const MSTATUS64_SD: usize = 0x8000000000000000;
 	pub const SSTATUS64_SD: usize = MSTATUS64_SD;
 	pub const SATP64_MODE: usize = 0xF000000000000000;
+	pub const SATP64_PPN: usize = 0x00000FFFFFFFFFFF;
 	pub const HGATP64_PPN: usize = 0x00000FFFFFFFFFFF;
 	pub const HGATP64_VMID_SHIFT: usize = 44;
 	pub const HGATP64_VMID_MASK: usize = 0x03FFF00000000000;
@@ -85,6 +112,7 @@ pub mod Const {
     pub const MSTATUS_SD: usize = MSTATUS64_SD;
 	pub const SSTATUS_SD: usize = SSTATUS64_SD;
 	pub const SATP_MODE: usize = SATP64_MODE;
+	pub const SATP_PPN: usize = SATP64_PPN;
 
 	pub const HGATP_PPN: usize = HGATP64_PPN;
 	pub const HGATP_VMID_SHIFT: usize = HGATP64_VMID_SHIFT;
@@ -99,6 +127,7 @@ pub mod Const {
 	pub const MSTATUS32_SD: usize = 0x80000000;
 	pub const SSTATUS32_SD: usize = MSTATUS32_SD;
 	pub const SATP32_MODE: usize = 0x80000000;
+	pub const SATP32_PPN: usize = 0x003FFFFF;
 	pub const HGATP32_PPN: usize = 0x003FFFFF;
 	pub const HGATP32_VMID_SHIFT: usize = 22;
 	pub const HGATP32_VMID_MASK: usize = 0x1FC00000;
@@ -107,6 +136,7 @@ pub mod Const {
     pub const MSTATUS_SD: usize = MSTATUS32_SD;
 	pub const SSTATUS_SD: usize = SSTATUS32_SD;
 	pub const SATP_MODE: usize = SATP32_MODE;
+	pub const SATP_PPN: usize = SATP32_PPN;
 
 	pub const HGATP_PPN: usize = HGATP32_PPN;
 	pub const HGATP_VMID_SHIFT: usize = HGATP32_VMID_SHIFT;
@@ -170,21 +200,31 @@ fn BITS_TO_LONGS(nbits: usize) -> usize {
 }
 
 /** Representation of hartmask */
-struct sbi_hartmask {
+#[repr(C)]
+pub struct sbi_hartmask {
 	pub bits: [usize;((SBI_HARTMASK_MAX_BITS) + Const::BITS_PER_LONG - 1) / Const::BITS_PER_LONG]
 }
 
+impl sbi_hartmask {
+	pub fn new() -> Self {
+		Self {
+			bits: [0;((SBI_HARTMASK_MAX_BITS) + Const::BITS_PER_LONG - 1) / Const::BITS_PER_LONG]
+		}
+	}
+}
+
 pub struct atomic_t {
 	counter: usize
 }
 
 
+#[repr(C)]
 pub struct sbi_tlb_info {
 	pub start: usize,
 	pub size: usize,
 	pub asid: usize,
 	pub vmid: usize,
-	pub local_fn: fn(*mut sbi_tlb_info),
+	pub local_fn: extern "C" fn(*mut sbi_tlb_info),
 	pub smask: sbi_hartmask
 }
 
@@ -363,6 +403,35 @@ pub fn mb() {
 	RISCV_FENCE("iorw", "iorw");
 }
 
+/* Instruction fetches on this hart see earlier stores */
+pub fn fence_i() {
+	unsafe {
+		asm!("fence.i");
+	}
+}
+
+extern "C" {
+	fn sbi_timer_value() -> u64;
+	fn sbi_timer_event_start(next_event: u64);
+	fn sbi_ipi_raw_send(hartindex: u32) -> i32;
+	fn sbi_tlb_request(hmask: usize, hbase: usize, tinfo: *mut sbi_tlb_info) -> i32;
+}
+
+/* Current value of the platform timer, in timer ticks */
+pub fn timer_value() -> u64 {
+	unsafe { sbi_timer_value() }
+}
+
+/* Fires the machine timer of this HART at `next_event`, replacing the pending event */
+pub fn timer_event_start(next_event: u64) {
+	unsafe { sbi_timer_event_start(next_event) }
+}
+
+/* Raises a machine software interrupt on HART `hartid`, without an IPI event */
+pub fn ipi_raw_send(hartid: usize) -> i32 {
+	unsafe { sbi_ipi_raw_send(hartid as u32) }
+}
+
 /** Get pointer to sbi_scratch for current HART */
 fn sbi_scratch_thishart_ptr() -> *mut sbi_scratch {
 	return csr_read("mscratch") as *mut sbi_scratch;
@@ -447,18 +516,15 @@ pub fn sbi_trap_exit(regs: *mut sbi_trap::sbi_trap_regs) {
 	unreachable!("Unreachable!");
 }
 
-// pub fn sbi_tlb_request(hmask: usize, hbase: usize, tinfo: *mut sbi_tlb_info) -> i32
-// {
-// 	if !(*tinfo).local_fn {
-// 		return -3; // SBI_ERR_INVALID_PARAM
-// 	}
-
-// 	tlb_pmu_incr_fw_ctr(tinfo);
-
-// 	return sbi_ipi_send_many(hmask, hbase, tlb_event, tinfo);
-// }
+/**
+ * Runs tinfo->local_fn on every HART of `hmask` above `hbase`, this one
+ * included, and returns once all of them are done.
+ */
+pub fn tlb_request(hmask: usize, hbase: usize, tinfo: &mut sbi_tlb_info) -> i32 {
+	unsafe { sbi_tlb_request(hmask, hbase, tinfo) }
+}
 
-fn bitmap_zero_except(dst: &[usize], exception: usize, nbits: usize) {
+fn bitmap_zero_except(dst: &mut [usize], exception: usize, nbits: usize) {
 	if nbits < Const::BITS_PER_LONG {
 		dst[0] = 0;
 	}
@@ -476,8 +542,8 @@ fn bitmap_zero_except(dst: &[usize], exception: usize, nbits: usize) {
 }
 
 // /** Initialize hartmask to zero except a particular HART id */
-pub fn SBI_HARTMASK_INIT_EXCEPT(__m: sbi_hartmask, __h: usize)	{
-	bitmap_zero_except(&(__m).bits, __h, SBI_HARTMASK_MAX_BITS)
+pub fn SBI_HARTMASK_INIT_EXCEPT(__m: &mut sbi_hartmask, __h: usize)	{
+	bitmap_zero_except(&mut (__m).bits, __h, SBI_HARTMASK_MAX_BITS)
 }
 
 	
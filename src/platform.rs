@@ -1,6 +1,19 @@
 use crate::error_code::ERROR;
 use crate::enclave;
 
+/* Slots of the platform persistent storage.
+ * SLOT_PROVISIONED behaves like a fuse: once written it must not be
+ * cleared by the platform.
+ */
+pub mod storage_slot {
+    pub const SLOT_PROVISIONED: usize = 0;
+    pub const SLOT_DEVICE_SECRET: usize = 1;
+    /* one slot per monotonic counter, see counter.rs */
+    pub const SLOT_COUNTERS_BASE: usize = 16;
+    /* one slot per checkpoint ticket, see checkpoint.rs */
+    pub const SLOT_CHECKPOINTS_BASE: usize = 32;
+}
+
 pub struct platform_enclave_data {
     
 }
@@ -33,10 +46,26 @@ pub fn platform_switch_to_enclave(enclave: &mut enclave::enclave){
     return;
 }
 
+/* Deadline the machine timer of this hart is armed with.
+ * OpenSBI cannot read a timer event back, so this is left to platforms
+ * that can; without it the host timer is raised when an enclave leaves.
+ */
+pub fn platform_timer_deadline(deadline: &mut u64) -> usize {
+    return ERROR::SBI_ERR_SM_NOT_IMPLEMENTED;
+}
+
 pub fn platform_init_global() -> usize {
     return ERROR::SBI_ERR_SM_ENCLAVE_SUCCESS;
 }
 
 pub fn platform_init_global_once() -> usize {
     return ERROR::SBI_ERR_SM_ENCLAVE_SUCCESS;
-}
\ No newline at end of file
+}
+
+pub fn platform_storage_read(slot: usize, buf: &mut [u8]) -> usize {
+    return ERROR::SBI_ERR_SM_NOT_IMPLEMENTED;
+}
+
+pub fn platform_storage_write(slot: usize, buf: &[u8]) -> usize {
+    return ERROR::SBI_ERR_SM_NOT_IMPLEMENTED;
+}
@@ -1,9 +1,10 @@
-use crate::error_code::ERROR;
+use crate::error_code::SmError;
 use crate::ipi;
 use core::arch::asm;
 use crate::assert;
 use crate::opensbi;
 use crate::page;
+use crate::config;
 
 pub type region_id = i32;
 pub type pmpreg_id = i32;
@@ -40,8 +41,8 @@ impl pmp_region {
   }
 }
 
-pub const PMP_N_REG: usize = 8; //number of PMP registers
-pub const PMP_MAX_N_REGION: usize = 16; //maximum number of PMP regions
+pub const PMP_N_REG: usize = config::PMP_N_REG; //number of PMP registers
+pub const PMP_MAX_N_REGION: usize = config::PMP_MAX_N_REGION; //maximum number of PMP regions
 
 pub const PMP_ALL_PERM: u8 = opensbi::PMP_W | opensbi::PMP_X | opensbi::PMP_R; // opensbi
 pub const PMP_NO_PERM: u8 = 0;
@@ -137,7 +138,7 @@ fn PMP_UNSET(n: usize, g: usize) {
   }
 }
 
-fn PMP_ERROR(error: usize, msg: String) -> usize {
+fn PMP_ERROR(error: SmError, msg: String) -> SmError {
   println!("{}:\n", msg); // opensbi
   return error;
 }
@@ -188,36 +189,36 @@ pub fn pmp_init() {
     let mut i = 0;
 }
 
-pub fn pmp_region_init(start: usize, size: usize, priority: pmp_priority, rid: &mut i32, allow_overlap: i32) -> i32 {
+pub fn pmp_region_init(start: usize, size: usize, priority: pmp_priority, allow_overlap: i32) -> Result<region_id, SmError> {
   if size == 0 {
-    PMP_ERROR(ERROR::SBI_ERR_SM_PMP_REGION_SIZE_INVALID, "Invalid PMP size".to_string());
+    return Err(PMP_ERROR(SmError::PmpRegionSizeInvalid, "Invalid PMP size".to_string()));
   }
 
   /* overlap detection */
   if allow_overlap == 0 {
     if detect_region_overlap(start, size) != 0 {
-      return ERROR::SBI_ERR_SM_PMP_REGION_OVERLAP as i32;
+      return Err(SmError::PmpRegionOverlap);
     }
   }
 
   /* PMP granularity check */
   if (size != usize::MAX) && (size & (page::RISCV_PGSIZE - 1) != 0) {
-    PMP_ERROR(ERROR::SBI_ERR_SM_PMP_REGION_NOT_PAGE_GRANULARITY, "PMP granularity is RISCV_PGSIZE".to_string());
+    return Err(PMP_ERROR(SmError::PmpRegionNotPageGranularity, "PMP granularity is RISCV_PGSIZE".to_string()));
   }
   if (start & (page::RISCV_PGSIZE - 1)) != 0 {
-    PMP_ERROR(ERROR::SBI_ERR_SM_PMP_REGION_NOT_PAGE_GRANULARITY, "PMP granularity is RISCV_PGSIZE".to_string());
+    return Err(PMP_ERROR(SmError::PmpRegionNotPageGranularity, "PMP granularity is RISCV_PGSIZE".to_string()));
   }
 
   /* if the address covers the entire RAM or it's NAPOT */
   if (size == usize::MAX && start == 0) || ((size & (size - 1)) == 0 && (start & (size - 1)) == 0) {
-    return napot_region_init(start, size, priority, rid, allow_overlap) as i32;
+    return napot_region_init(start, size, priority, allow_overlap);
   }
   else {
     if (priority != pmp_priority::PMP_PRI_ANY) && (priority != pmp_priority::PMP_PRI_TOP || start != 0) {
-      PMP_ERROR(ERROR::SBI_ERR_SM_PMP_REGION_IMPOSSIBLE_TOR, "The top-priority TOR PMP entry must start from address 0".to_string());
+      return Err(PMP_ERROR(SmError::PmpRegionImpossibleTor, "The top-priority TOR PMP entry must start from address 0".to_string()));
     }
 
-    return tor_region_init(start, size, priority, rid, allow_overlap);
+    return tor_region_init(start, size, priority, allow_overlap);
   }
 }
 
@@ -265,37 +266,35 @@ fn detect_region_overlap(addr: usize, size: usize) -> i32 {
   return region_overlap;
 }
 
-fn tor_region_init(start: usize, size: usize, priority: pmp_priority, rid: &mut region_id, allow_overlap: i32) -> i32 {
+fn tor_region_init(start: usize, size: usize, priority: pmp_priority, allow_overlap: i32) -> Result<region_id, SmError> {
   let reg_idx: pmpreg_id = -1;
   let region_idx: region_id = -1;
 
   assert::sm_assert(size as usize);
   assert::sm_assert(!(size as usize & (page::RISCV_PGSIZE - 1)));
   assert::sm_assert(!(start & (page::RISCV_PGSIZE - 1)));
-  assert::sm_assert(*rid as usize);
   assert::sm_assert((priority != pmp_priority::PMP_PRI_BOTTOM) as usize);
 
   region_idx = get_free_region_idx();
   if region_idx < 0 || region_idx > PMP_MAX_N_REGION as i32 {
-    PMP_ERROR(ERROR::SBI_ERR_SM_PMP_REGION_MAX_REACHED, "Reached the maximum number of PMP regions".to_string());
+    return Err(PMP_ERROR(SmError::PmpRegionMaxReached, "Reached the maximum number of PMP regions".to_string()));
   }
 
-  *rid = region_idx;
   match priority {
     pmp_priority::PMP_PRI_ANY => {
       reg_idx = get_conseq_free_reg_idx();
       if reg_idx < 0 {
-        PMP_ERROR(ERROR::SBI_ERR_SM_PMP_REGION_MAX_REACHED, "No available PMP register".to_string());
+        return Err(PMP_ERROR(SmError::PmpRegionMaxReached, "No available PMP register".to_string()));
       }
       if test_bit(reg_bitmap as usize, reg_idx as usize) != 0 || test_bit(reg_bitmap as usize, reg_idx as usize + 1) != 0 || reg_idx + 1 >= PMP_N_REG as i32 {
-        PMP_ERROR(ERROR::SBI_ERR_SM_PMP_REGION_MAX_REACHED, "PMP register unavailable".to_string());
+        return Err(PMP_ERROR(SmError::PmpRegionMaxReached, "PMP register unavailable".to_string()));
       }
     }
     pmp_priority::PMP_PRI_TOP => {
       assert::sm_assert((start == 0) as usize);
       reg_idx = 0;
       if test_bit(reg_bitmap as usize, reg_idx as usize) != 0 {
-        PMP_ERROR(ERROR::SBI_ERR_SM_PMP_REGION_MAX_REACHED, "PMP register unavailable".to_string());
+        return Err(PMP_ERROR(SmError::PmpRegionMaxReached, "PMP register unavailable".to_string()));
       }
     }
     _ => {
@@ -312,7 +311,7 @@ fn tor_region_init(start: usize, size: usize, priority: pmp_priority, rid: &mut
     set_bit(reg_bitmap as usize, reg_idx as usize + 1);
   }
 
-  return ERROR::SBI_ERR_SM_PMP_SUCCESS as i32;
+  return Ok(region_idx);
 }
 
 fn region_init(i: region_id, addr: usize, size: usize, addrmode: u8, allow_overlap: i32, reg_idx: pmpreg_id) {
@@ -328,12 +327,11 @@ fn region_init(i: region_id, addr: usize, size: usize, addrmode: u8, allow_overl
   }
 }
 
-fn napot_region_init(start: usize, size: usize, priority: pmp_priority, rid: &mut region_id, allow_overlap: i32) -> usize {
+fn napot_region_init(start: usize, size: usize, priority: pmp_priority, allow_overlap: i32) -> Result<region_id, SmError> {
   let reg_idx: pmpreg_id = -1;
   let region_idx: region_id = -1;
 
   assert::sm_assert(size as usize); // assert.rs
-  assert::sm_assert(*rid as usize);
 
   if !(size == (2 ^ 32 - 1) && start == 0) {
     assert::sm_assert(!(size & (size - 1)) as usize);
@@ -345,25 +343,23 @@ fn napot_region_init(start: usize, size: usize, priority: pmp_priority, rid: &mu
   //find avaiable pmp region idx
   region_idx = get_free_region_idx();
   if region_idx < 0 || region_idx > PMP_MAX_N_REGION as i32 {
-    PMP_ERROR(ERROR::SBI_ERR_SM_PMP_REGION_MAX_REACHED, "Reached the maximum number of PMP regions".to_string());
+    return Err(PMP_ERROR(SmError::PmpRegionMaxReached, "Reached the maximum number of PMP regions".to_string()));
   }
 
-  *rid = region_idx;
-
   match priority {
     pmp_priority::PMP_PRI_ANY => {
       reg_idx = get_free_reg_idx();
       if reg_idx < 0 {
-        PMP_ERROR(ERROR::SBI_ERR_SM_PMP_REGION_MAX_REACHED, "No available PMP register".to_string());
+        return Err(PMP_ERROR(SmError::PmpRegionMaxReached, "No available PMP register".to_string()));
       }
       if test_bit(reg_bitmap as usize, reg_idx as usize) != 0 || reg_idx >= PMP_N_REG as i32 {
-        PMP_ERROR(ERROR::SBI_ERR_SM_PMP_REGION_MAX_REACHED, "PMP register unavailable".to_string());
+        return Err(PMP_ERROR(SmError::PmpRegionMaxReached, "PMP register unavailable".to_string()));
       }
     },
     pmp_priority::PMP_PRI_TOP => {
       reg_idx = 0;
       if test_bit(reg_bitmap as usize, reg_idx as usize) != 0 {
-        PMP_ERROR(ERROR::SBI_ERR_SM_PMP_REGION_MAX_REACHED, "PMP register unavailable".to_string());
+        return Err(PMP_ERROR(SmError::PmpRegionMaxReached, "PMP register unavailable".to_string()));
       }
     },
     pmp_priority::PMP_PRI_BOTTOM => {
@@ -381,7 +377,7 @@ fn napot_region_init(start: usize, size: usize, priority: pmp_priority, rid: &mu
   set_bit(region_def_bitmap as usize, region_idx as usize);
   set_bit(reg_bitmap as usize, reg_idx as usize);
 
-  return ERROR::SBI_ERR_SM_PMP_SUCCESS;
+  return Ok(region_idx);
 }
 
 fn region_is_tor(i: region_id) -> bool {
@@ -415,21 +411,21 @@ fn region_pmpaddr_val(i: region_id) -> usize {
   }
 }
 
-pub fn pmp_region_init_atomic(start: usize, size: usize, priority: pmp_priority, rid: &mut region_id, allow_overlap: i32) -> i32 {
-  let ret: i32;
+pub fn pmp_region_init_atomic(start: usize, size: usize, priority: pmp_priority, allow_overlap: i32) -> Result<region_id, SmError> {
+  let ret: Result<region_id, SmError>;
   opensbi::spin_lock(&mut pmp_lock);
-  ret = pmp_region_init(start, size, priority, rid, allow_overlap); // pmp.rs
+  ret = pmp_region_init(start, size, priority, allow_overlap); // pmp.rs
   opensbi::spin_unlock(&mut pmp_lock);
   return ret;
 }
 
-pub fn pmp_region_free_atomic(region_idx: i32) -> i32{
+pub fn pmp_region_free_atomic(region_idx: i32) -> Result<(), SmError> {
   
   opensbi::spin_lock(&mut pmp_lock);
 
   if is_pmp_region_valid(region_idx) == 0 {
     opensbi::spin_unlock(&mut pmp_lock);
-    PMP_ERROR(ERROR::SBI_ERR_SM_PMP_REGION_INVALID, "Invalid PMP region index".to_string());
+    return Err(PMP_ERROR(SmError::PmpRegionInvalid, "Invalid PMP region index".to_string()));
   }
 
   let reg_idx = region_register_idx(region_idx);
@@ -443,12 +439,80 @@ pub fn pmp_region_free_atomic(region_idx: i32) -> i32{
 
   opensbi::spin_unlock(&mut pmp_lock);
 
-  return ERROR::SBI_ERR_SM_PMP_SUCCESS as i32;
+  return Ok(());
+}
+
+/*
+ * Cuts region `region_idx` down to its first `new_size` bytes.
+ * TOR regions shrink to any page multiple, NAPOT regions only to a
+ * smaller power of two. The caller reprograms the PMP registers.
+ */
+pub fn pmp_region_shrink_atomic(region_idx: i32, new_size: usize) -> Result<(), SmError> {
+  let ret: Result<(), SmError>;
+
+  opensbi::spin_lock(&mut pmp_lock);
+
+  if is_pmp_region_valid(region_idx) == 0 {
+    ret = Err(PMP_ERROR(SmError::PmpRegionInvalid, "Invalid PMP region index".to_string()));
+  }
+  else if new_size == 0 || new_size as u64 >= region_get_size(region_idx) || region_is_napot_all(region_idx) {
+    ret = Err(PMP_ERROR(SmError::PmpRegionSizeInvalid, "Invalid PMP size".to_string()));
+  }
+  else if new_size & (page::RISCV_PGSIZE - 1) != 0 {
+    ret = Err(PMP_ERROR(SmError::PmpRegionNotPageGranularity, "PMP granularity is RISCV_PGSIZE".to_string()));
+  }
+  else if region_is_napot(region_idx) && new_size & (new_size - 1) != 0 {
+    ret = Err(PMP_ERROR(SmError::PmpRegionNotAligned, "NAPOT regions must stay a power of two".to_string()));
+  }
+  else {
+    // the base is unchanged, so a NAPOT region stays naturally aligned
+    regions[region_idx as usize].size = new_size as u64;
+    ret = Ok(());
+  }
+
+  opensbi::spin_unlock(&mut pmp_lock);
+  return ret;
+}
+
+/*
+ * Splits region `region_idx` at `offset`: the region keeps the head and
+ * the tail becomes a new region, returned. The region is left untouched
+ * if the tail cannot get a region of its own.
+ */
+pub fn pmp_region_split_atomic(region_idx: i32, offset: usize) -> Result<region_id, SmError> {
+  let ret: Result<region_id, SmError>;
+
+  opensbi::spin_lock(&mut pmp_lock);
+
+  if is_pmp_region_valid(region_idx) == 0 {
+    opensbi::spin_unlock(&mut pmp_lock);
+    return Err(PMP_ERROR(SmError::PmpRegionInvalid, "Invalid PMP region index".to_string()));
+  }
+  let start: usize = region_get_addr(region_idx) as usize;
+  let size: usize = region_get_size(region_idx) as usize;
+  if offset == 0 || offset >= size || region_is_napot_all(region_idx) {
+    opensbi::spin_unlock(&mut pmp_lock);
+    return Err(PMP_ERROR(SmError::PmpRegionSizeInvalid, "Invalid PMP size".to_string()));
+  }
+  if region_is_napot(region_idx) && offset & (offset - 1) != 0 {
+    opensbi::spin_unlock(&mut pmp_lock);
+    return Err(PMP_ERROR(SmError::PmpRegionNotAligned, "NAPOT regions must stay a power of two".to_string()));
+  }
+
+  // shrink first so the tail does not overlap its old region
+  regions[region_idx as usize].size = offset as u64;
+  ret = pmp_region_init(start + offset, size - offset, pmp_priority::PMP_PRI_ANY, 0);
+  if ret.is_err() {
+    regions[region_idx as usize].size = size as u64;
+  }
+
+  opensbi::spin_unlock(&mut pmp_lock);
+  return ret;
 }
 
-pub fn pmp_set_keystone(region_idx: i32, perm: u8) -> i32 {
+pub fn pmp_set_keystone(region_idx: i32, perm: u8) -> Result<(), SmError> {
   if is_pmp_region_valid(region_idx) == 0 {
-    PMP_ERROR(ERROR::SBI_ERR_SM_PMP_REGION_INVALID, "Invalid PMP region index".to_string());
+    return Err(PMP_ERROR(SmError::PmpRegionInvalid, "Invalid PMP region index".to_string()));
   }
 
   let perm_bits: u8 = perm & PMP_ALL_PERM;
@@ -482,7 +546,7 @@ pub fn pmp_set_keystone(region_idx: i32, perm: u8) -> i32 {
       assert::sm_assert(0);
     }
   }
-  return ERROR::SBI_ERR_SM_PMP_SUCCESS as i32;
+  return Ok(());
 }
 
 fn region_get_addr(i: region_id) -> u32 {
@@ -515,23 +579,38 @@ fn region_allows_overlap(i: region_id) -> i32 {
   return regions[i as usize].allow_overlap;
 }
 
-pub fn pmp_set_global(region_idx: i32, perm: u8) -> i32 {
+pub fn pmp_set_global(region_idx: i32, perm: u8) -> Result<(), SmError> {
+  if is_pmp_region_valid(region_idx) == 0 {
+    return Err(PMP_ERROR(SmError::PmpRegionInvalid, "Invalid PMP region index".to_string()));
+  }
+
+  ipi::send_and_sync_pmp_ipi(region_idx, ipi::SBI_PMP_IPI_TYPE_SET, perm, 0); // ipi.rs
+
+  return Ok(());
+}
+
+/*
+ * Reprograms region `region_idx` on every hart: harts running one of the
+ * enclaves in the `owners` bitmask get `perm`, all others no access.
+ * For regions whose bounds change while their enclave runs elsewhere.
+ */
+pub fn pmp_set_global_owned(region_idx: i32, perm: u8, owners: usize) -> Result<(), SmError> {
   if is_pmp_region_valid(region_idx) == 0 {
-    PMP_ERROR(ERROR::SBI_ERR_SM_PMP_REGION_INVALID, "Invalid PMP region index".to_string());
+    return Err(PMP_ERROR(SmError::PmpRegionInvalid, "Invalid PMP region index".to_string()));
   }
 
-  // ipi::send_and_sync_pmp_ipi(region_idx, ipi::SBI_PMP_IPI_TYPE_SET as i32, perm); // ipi.rs
+  ipi::send_and_sync_pmp_ipi(region_idx, ipi::SBI_PMP_IPI_TYPE_SET_OWNED, perm, owners); // ipi.rs
 
-  return ERROR::SBI_ERR_SM_PMP_SUCCESS as i32;
+  return Ok(());
 }
 
 fn region_register_idx(i: region_id) -> i32 {
   return regions[i as usize].reg_idx;
 }
 
-pub fn pmp_unset(region_idx: i32) -> usize {
+pub fn pmp_unset(region_idx: i32) -> Result<(), SmError> {
   if is_pmp_region_valid(region_idx) == 0 {
-    PMP_ERROR(ERROR::SBI_ERR_SM_PMP_REGION_INVALID, "Invalid PMP region index".to_string());
+    return Err(PMP_ERROR(SmError::PmpRegionInvalid, "Invalid PMP region index".to_string()));
   }
   let reg_idx: pmpreg_id = region_register_idx(region_idx);
   let n: i32 = reg_idx;
@@ -553,17 +632,17 @@ pub fn pmp_unset(region_idx: i32) -> usize {
     }
   }
 
-  return ERROR::SBI_ERR_SM_PMP_SUCCESS;
+  return Ok(());
 }
 
-pub fn pmp_unset_global(region_idx: i32) -> i32 {
+pub fn pmp_unset_global(region_idx: i32) -> Result<(), SmError> {
   if is_pmp_region_valid(region_idx) == 0 {
-    PMP_ERROR(ERROR::SBI_ERR_SM_PMP_REGION_INVALID, "Invalid PMP region index".to_string());
+    return Err(PMP_ERROR(SmError::PmpRegionInvalid, "Invalid PMP region index".to_string()));
   }
   
-  // ipi::send_and_sync_pmp_ipi(region_idx, ipi::SBI_PMP_IPI_TYPE_UNSET as i32, PMP_NO_PERM);
+  ipi::send_and_sync_pmp_ipi(region_idx, ipi::SBI_PMP_IPI_TYPE_UNSET, PMP_NO_PERM, 0); // ipi.rs
 
-  return ERROR::SBI_ERR_SM_PMP_SUCCESS as i32;
+  return Ok(());
 }
 
 pub fn pmp_detect_region_overlap_atomic(addr: usize, size: usize) -> i32 {
@@ -0,0 +1,187 @@
+use crate::error_code::SmError;
+use crate::platform::storage_slot;
+use crate::storage::storage_backend;
+use crate::storage;
+use crate::crypto;
+use crate::mprv;
+use crate::opensbi;
+use crate::sm;
+
+use std::mem;
+
+pub const DEVICE_SECRET_SIZE: usize = 32;
+const PROVISIONED_MAGIC: [u8; 4] = *b"PROV";
+
+const DEVICE_KEY_INFO: &[u8] = b"keystone-device-key";
+const SM_KEY_INFO: &[u8] = b"keystone-sm-key";
+
+static provision_lock: opensbi::spinlock_t = opensbi::spinlock_t::new();
+
+static mut provisioned: bool = false;
+
+static mut backend: storage::sm_storage = storage::sm_storage::new();
+
+/* keys derived from the device secret, installed in sm.rs once they may be used */
+struct device_keys {
+  dev_public_key: [u8; crypto::PUBLIC_KEY_SIZE],
+  sm_public_key: [u8; crypto::PUBLIC_KEY_SIZE],
+  sm_private_key: [u8; crypto::PRIVATE_KEY_SIZE],
+  sm_signature: [u8; crypto::SIGNATURE_SIZE]
+}
+
+impl device_keys {
+  pub fn new() -> Self {
+    Self {
+      dev_public_key: [0; crypto::PUBLIC_KEY_SIZE],
+      sm_public_key: [0; crypto::PUBLIC_KEY_SIZE],
+      sm_private_key: [0; crypto::PRIVATE_KEY_SIZE],
+      sm_signature: [0; crypto::SIGNATURE_SIZE]
+    }
+  }
+}
+
+/*
+ * Reads the fuse. Anything but a blank slot counts as blown, and a read
+ * error is an error: the caller must not take the device as fresh.
+ * NotImplemented means the platform has no storage to provision into.
+ */
+fn is_provisioned() -> Result<bool, SmError> {
+  let flag: [u8; 4] = [0; 4];
+
+  backend.read(storage_slot::SLOT_PROVISIONED, &mut flag)?;
+  return Ok(flag != [0; 4]);
+}
+
+/*
+ * Derives the device keypair from the device secret, then derives a
+ * keypair for this SM (bound to its hash) and certifies it with the
+ * device key, as the boot ROM would. The device private key is gone
+ * once the certificate is signed.
+ */
+fn derive_device_keys(secret: &[u8], keys: &mut device_keys) -> Result<(), SmError> {
+  let seed: [u8; crypto::PUBLIC_KEY_SIZE] = [0; crypto::PUBLIC_KEY_SIZE];
+  let dev_private_key: [u8; crypto::PRIVATE_KEY_SIZE] = [0; crypto::PRIVATE_KEY_SIZE];
+  let info: [u8; 32 + crypto::MDSIZE] = [0; 32 + crypto::MDSIZE];
+  let ret: Result<(), SmError> = Err(SmError::UnknownError);
+
+  let info_len: usize = SM_KEY_INFO.len() + crypto::MDSIZE;
+  info[..SM_KEY_INFO.len()].copy_from_slice(SM_KEY_INFO);
+  info[SM_KEY_INFO.len()..info_len].copy_from_slice(&sm::sm_hash);
+
+  if crypto::kdf(&mut [0], secret, DEVICE_KEY_INFO, &mut seed) == 0 {
+    keys.dev_public_key = crypto::create_keypair(&mut dev_private_key, &seed);
+    if crypto::kdf(&mut [0], secret, &info[..info_len], &mut seed) == 0 {
+      keys.sm_public_key = crypto::create_keypair(&mut keys.sm_private_key, &seed);
+
+      /* the device key signs (sm_hash || sm_public_key) */
+      let cert: [u8; crypto::MDSIZE + crypto::PUBLIC_KEY_SIZE] = [0; crypto::MDSIZE + crypto::PUBLIC_KEY_SIZE];
+      cert[..crypto::MDSIZE].copy_from_slice(&sm::sm_hash);
+      cert[crypto::MDSIZE..].copy_from_slice(&keys.sm_public_key);
+      keys.sm_signature = crypto::sign(&cert, &keys.dev_public_key, &dev_private_key);
+      ret = Ok(());
+    }
+  }
+
+  opensbi::sbi_memset(dev_private_key.as_ptr() as usize, 0, dev_private_key.len());
+  opensbi::sbi_memset(seed.as_ptr() as usize, 0, seed.len());
+  return ret;
+}
+
+/* makes `keys` the keys of this SM and wipes them */
+fn install_device_keys(keys: &mut device_keys) {
+  sm::dev_public_key = keys.dev_public_key;
+  sm::sm_public_key = keys.sm_public_key;
+  sm::sm_private_key = keys.sm_private_key;
+  sm::sm_signature = keys.sm_signature;
+  opensbi::sbi_memset(keys as *const device_keys as usize, 0, mem::size_of::<device_keys>());
+}
+
+/*
+ * One-time installation of the device secret.
+ * Fails once the platform reports the device as provisioned, so the
+ * device keys cannot be rotated by a later (possibly malicious) image.
+ * The fuse is blown last, once the secret is stored and the keys are
+ * derived; until then a failed attempt can be retried and the SM keeps
+ * its boot ROM keys.
+ */
+pub fn provision_device_key(secret_ptr: usize, size: usize) -> Result<(), SmError> {
+  let secret: [u8; DEVICE_SECRET_SIZE] = [0; DEVICE_SECRET_SIZE];
+  let keys: device_keys = device_keys::new();
+  let ret: Result<(), SmError>;
+
+  if size != DEVICE_SECRET_SIZE {
+    return Err(SmError::IllegalArgument);
+  }
+
+  opensbi::spin_lock(&mut provision_lock);
+
+  if !provisioned {
+    match is_provisioned() {
+      Ok(false) => {},
+      Ok(true) => provisioned = true,
+      Err(err) => {
+        opensbi::spin_unlock(&mut provision_lock);
+        return Err(err);
+      }
+    }
+  }
+  if provisioned {
+    opensbi::spin_unlock(&mut provision_lock);
+    return Err(SmError::DeviceProvisioned);
+  }
+
+  if mprv::copy_to_sm(secret.as_ptr() as usize, secret_ptr, DEVICE_SECRET_SIZE) != 0 {
+    opensbi::spin_unlock(&mut provision_lock);
+    return Err(SmError::IllegalArgument);
+  }
+
+  ret = backend.write(storage_slot::SLOT_DEVICE_SECRET, &secret)
+    .and_then(|_| derive_device_keys(&secret, &mut keys))
+    .and_then(|_| backend.write(storage_slot::SLOT_PROVISIONED, &PROVISIONED_MAGIC));
+  if ret.is_ok() {
+    provisioned = true;
+    install_device_keys(&mut keys);
+  }
+
+  opensbi::sbi_memset(&keys as *const device_keys as usize, 0, mem::size_of::<device_keys>());
+  opensbi::sbi_memset(secret.as_ptr() as usize, 0, DEVICE_SECRET_SIZE);
+  opensbi::spin_unlock(&mut provision_lock);
+  return ret;
+}
+
+/*
+ * Called once by the SM on startup.
+ * Loads the provisioned device secret (if any) and re-derives the keys.
+ */
+pub fn provision_init() {
+  let secret: [u8; DEVICE_SECRET_SIZE] = [0; DEVICE_SECRET_SIZE];
+  let keys: device_keys = device_keys::new();
+
+  match is_provisioned() {
+    Ok(true) => {},
+    Ok(false) => {
+      println!("[SM] device is not provisioned, using boot ROM keys\n");
+      return;
+    },
+    // no storage hooks, the device can only have its boot ROM keys
+    Err(SmError::NotImplemented) => {
+      println!("[SM] provisioning is not supported, using boot ROM keys\n");
+      return;
+    },
+    Err(err) => {
+      // the device may be provisioned, the boot ROM keys must not stand in for its keys
+      println!("[SM] intolerable error - failed to read the provisioning state ({:?})", err);
+      opensbi::sbi_hart_hang();
+    }
+  }
+  provisioned = true;
+
+  if backend.read(storage_slot::SLOT_DEVICE_SECRET, &mut secret)
+    .and_then(|_| derive_device_keys(&secret, &mut keys)).is_err() {
+    println!("[SM] intolerable error - failed to load the device secret");
+    opensbi::sbi_hart_hang();
+  }
+  install_device_keys(&mut keys);
+
+  opensbi::sbi_memset(secret.as_ptr() as usize, 0, DEVICE_SECRET_SIZE);
+}
@@ -2,7 +2,7 @@ use crate::enclave;
 use crate::sbi_trap;
 use crate::opensbi;
 use crate::sm_sbi;
-use crate::error_code::ERROR;
+use crate::sm;
 // #include <sbi/riscv_asm.h>
 // #include <sbi/riscv_encoding.h>
 // #include <sbi/sbi_console.h>
@@ -66,45 +66,63 @@ pub fn sbi_trap_handler_keystone_enclave(regs: &mut sbi_trap::sbi_trap_regs) {
 			IRQ_M_TIMER => {
 				regs.mepc -= 4;
 				sm_sbi::sbi_sm_stop_enclave(regs, enclave::STOP_TIMER_INTERRUPT);
-				regs.a0 = ERROR::SBI_ERR_SM_ENCLAVE_INTERRUPTED;
 				regs.mepc += 4;
 			},
 			IRQ_M_SOFT => {
 				regs.mepc -= 4;
 				sm_sbi::sbi_sm_stop_enclave(regs, enclave::STOP_TIMER_INTERRUPT);
-				regs.a0 = ERROR::SBI_ERR_SM_ENCLAVE_INTERRUPTED;
 				regs.mepc += 4;
 			}
 		}
 		return;
 	}
 
-	// match mcause {
-	// 	CAUSE_ILLEGAL_INSTRUCTION => {
-	// 		rc  = sbi_illegal_insn_handler(mtval, regs);
-	// 		msg = "illegal instruction handler failed";
-	// 	}
-	// case CAUSE_MISALIGNED_LOAD:
-	// 	rc = sbi_misaligned_load_handler(mtval, mtval2, mtinst, regs);
-	// 	msg = "misaligned load handler failed";
-	// 	break;
-	// case CAUSE_MISALIGNED_STORE:
-	// 	rc  = sbi_misaligned_store_handler(mtval, mtval2, mtinst, regs);
-	// 	msg = "misaligned store handler failed";
-	// 	break;
-	// case CAUSE_SUPERVISOR_ECALL:
-	// case CAUSE_MACHINE_ECALL:
-	// 	rc  = sbi_ecall_handler(regs);
-	// 	msg = "ecall handler failed";
-	// 	break;
-	// default:
-	// 	/* If the trap came from S or U mode, redirect it there */
-	// 	trap.epc = regs->mepc;
-	// 	trap.cause = mcause;
-	// 	trap.tval = mtval;
-	// 	trap.tval2 = mtval2;
-	// 	trap.tinst = mtinst;
-	// 	rc = sbi_trap_redirect(regs, &trap);
-	// 	break;
-	// };
+	// only kept from the runtime while a debug enclave is stepped or continued
+	if mcause == opensbi::CAUSE_BREAKPOINT {
+		regs.mepc -= 4;
+		sm_sbi::sbi_sm_stop_enclave(regs, enclave::STOP_DEBUG_TRAP);
+		regs.mepc += 4;
+		return;
+	}
+
+	// SBI calls of the runtime take the ecall path
+	if mcause == opensbi::CAUSE_SUPERVISOR_ECALL || mcause == opensbi::CAUSE_MACHINE_ECALL {
+		return;
+	}
+
+	let fault: sm::keystone_sbi_fault = sm::keystone_sbi_fault::new();
+	fault.cause = mcause;
+	fault.tval = mtval;
+	fault.epc = regs.mepc;
+	fault.mode = (regs.mstatus & opensbi::MSTATUS_MPP) >> opensbi::MSTATUS_MPP_SHIFT;
+
+	// what the user code did is the runtime's to handle, if it set up a handler
+	if fault.mode == opensbi::PRV_U && opensbi::csr_read("stvec") != 0 {
+		redirect_to_runtime(regs, &fault);
+		return;
+	}
+
+	// the runtime faulted itself, the thread cannot go on
+	regs.mepc -= 4;
+	sm_sbi::sbi_sm_fault_enclave(regs, &fault);
+	regs.mepc += 4;
+}
+
+/*
+ * Hands a trap from U-mode to the S-mode handler of the runtime, as if it
+ * had been delegated.
+ */
+fn redirect_to_runtime(regs: &mut sbi_trap::sbi_trap_regs, fault: &sm::keystone_sbi_fault) {
+	opensbi::csr_write("stval", fault.tval);
+	opensbi::csr_write("sepc", fault.epc);
+	opensbi::csr_write("scause", fault.cause);
+	regs.mepc = opensbi::csr_read("stvec");
+
+	// back to S-mode, with SPP = U and interrupts off as a real trap leaves them
+	let sie: bool = regs.mstatus & opensbi::MSTATUS_SIE != 0;
+	regs.mstatus &= !(opensbi::MSTATUS_MPP | opensbi::MSTATUS_SPP | opensbi::MSTATUS_SPIE | opensbi::MSTATUS_SIE);
+	regs.mstatus |= opensbi::PRV_S << opensbi::MSTATUS_MPP_SHIFT;
+	if sie {
+		regs.mstatus |= opensbi::MSTATUS_SPIE;
+	}
 }
@@ -0,0 +1,242 @@
+use crate::error_code::SmError;
+use crate::enclave;
+use crate::enclave::enclave_id;
+use crate::pmp;
+use crate::pmp::region_id;
+use crate::crypto;
+use crate::mprv;
+use crate::opensbi;
+use crate::assert;
+
+use std::mem;
+
+/*
+ * Memory shared between two enclaves.
+ * The owner carves a tail range off one of its EPM regions and offers it
+ * to a peer named by measurement. The range gets its own PMP region that
+ * is only opened while the owner or the accepted peer runs, so the host
+ * never sees it. The region lives as long as both parties: once either
+ * is destroyed it is scrubbed and goes back to the host, so no later
+ * enclave with the peer's measurement can accept what was left in it.
+ */
+
+const SHARED_REGIONS_MAX: usize = 8;
+const NO_PEER: enclave_id = usize::MAX;
+
+/* shared memory is data only */
+const SHARED_PERM: u8 = opensbi::PMP_R | opensbi::PMP_W;
+
+#[derive(Clone, Copy)]
+struct shared_region {
+  in_use: bool,
+  rid: region_id, // -1 while the offer is being set up or revoked
+  owner: enclave_id,
+  peer: enclave_id, // NO_PEER until accepted
+  peer_hash: [u8; crypto::MDSIZE]
+}
+
+impl shared_region {
+  pub fn new() -> Self {
+    Self {
+      in_use: false,
+      rid: -1,
+      owner: NO_PEER,
+      peer: NO_PEER,
+      peer_hash: [0; crypto::MDSIZE]
+    }
+  }
+}
+
+/* handed to the peer when it accepts a region */
+pub struct shared_region_info {
+  pub paddr: usize,
+  pub size: usize,
+  pub owner_hash: [u8; crypto::MDSIZE]
+}
+
+static shm_lock: opensbi::spinlock_t = opensbi::spinlock_t::new();
+
+static mut shared_regions: [shared_region; SHARED_REGIONS_MAX] = [shared_region::new(); SHARED_REGIONS_MAX];
+
+/*
+ * Offers [paddr, paddr + size), the tail of one of the EPM regions of
+ * enclave `eid`, to the enclave measured as `peer_hash_ptr`.
+ * The id of the offer is written to `id_ptr`.
+ */
+pub fn share_offer(eid: enclave_id, paddr: usize, size: usize, peer_hash_ptr: usize, id_ptr: usize) -> Result<(), SmError> {
+  let peer_hash: [u8; crypto::MDSIZE] = [0; crypto::MDSIZE];
+  let id: usize = SHARED_REGIONS_MAX;
+  let rid: region_id;
+
+  if mprv::copy_to_sm(peer_hash.as_ptr() as usize, peer_hash_ptr, crypto::MDSIZE) != 0 {
+    return Err(SmError::IllegalArgument);
+  }
+
+  // reserve a slot first, the split cannot be undone
+  opensbi::spin_lock(&mut shm_lock);
+  for i in 0..SHARED_REGIONS_MAX {
+    if !shared_regions[i].in_use {
+      id = i;
+      shared_regions[i] = shared_region::new();
+      shared_regions[i].in_use = true;
+      shared_regions[i].owner = eid;
+      shared_regions[i].peer_hash = peer_hash;
+      break;
+    }
+  }
+  opensbi::spin_unlock(&mut shm_lock);
+
+  if id == SHARED_REGIONS_MAX {
+    return Err(SmError::NoFreeResource);
+  }
+
+  // the owner's harts keep access, everybody else loses it
+  rid = match enclave::split_epm_tail(eid, paddr, size, SHARED_PERM) {
+    Ok(rid) => rid,
+    Err(err) => {
+      release_slot(id);
+      return Err(err);
+    }
+  };
+
+  opensbi::spin_lock(&mut shm_lock);
+  shared_regions[id].rid = rid;
+  opensbi::spin_unlock(&mut shm_lock);
+
+  // only an offer that exists is published, it is revoked with the owner if this fails
+  if mprv::copy_from_sm(id_ptr, &id as *const usize as usize, mem::size_of::<usize>()) != 0 {
+    return Err(SmError::IllegalArgument);
+  }
+  return Ok(());
+}
+
+/*
+ * Called by enclave `eid` to accept offer `id`. Only the enclave whose
+ * measurement the owner named can accept, and only once.
+ */
+pub fn share_accept(eid: enclave_id, id: usize, info_ptr: usize) -> Result<(), SmError> {
+  let info: shared_region_info;
+  let rid: region_id;
+  let owner: enclave_id;
+
+  if id >= SHARED_REGIONS_MAX {
+    return Err(SmError::IllegalArgument);
+  }
+
+  opensbi::spin_lock(&mut shm_lock);
+  let region: &mut shared_region = &mut shared_regions[id];
+  if !region.in_use || region.rid < 0 || region.peer != NO_PEER || region.owner == eid {
+    opensbi::spin_unlock(&mut shm_lock);
+    return Err(SmError::IllegalArgument);
+  }
+  if region.peer_hash[..] != enclave::get_enclave_hash(eid)[..] {
+    opensbi::spin_unlock(&mut shm_lock);
+    return Err(SmError::NotAccessible);
+  }
+  region.peer = eid;
+  rid = region.rid;
+  owner = region.owner;
+  info = shared_region_info {
+    paddr: pmp::pmp_region_get_addr(rid) as usize,
+    size: pmp::pmp_region_get_size(rid) as usize,
+    owner_hash: [0; crypto::MDSIZE]
+  };
+  info.owner_hash.copy_from_slice(enclave::get_enclave_hash(region.owner));
+  opensbi::spin_unlock(&mut shm_lock);
+
+  // harts running either party, this one included, get access
+  pmp::pmp_set_global_owned(rid, SHARED_PERM, (1 << owner) | (1 << eid))?;
+
+  if mprv::copy_from_sm(info_ptr, &info as *const shared_region_info as usize, mem::size_of::<shared_region_info>()) != 0 {
+    return Err(SmError::IllegalArgument);
+  }
+  return Ok(());
+}
+
+/* opens or closes the regions enclave `eid` shares on this hart */
+pub fn set_shared_perm(eid: enclave_id, open: bool) {
+  let perm: u8 = if open { SHARED_PERM } else { pmp::PMP_NO_PERM };
+
+  opensbi::spin_lock(&mut shm_lock);
+  for region in shared_regions.iter() {
+    if region.in_use && region.rid >= 0 && (region.owner == eid || region.peer == eid) {
+      assert::sm_assert(pmp::pmp_set_keystone(region.rid, perm).is_ok() as usize);
+    }
+  }
+  opensbi::spin_unlock(&mut shm_lock);
+}
+
+/* true if enclave `eid` shares a region with an accepted party that is running */
+pub fn shared_with_running(eid: enclave_id) -> bool {
+  let ret: bool = false;
+
+  opensbi::spin_lock(&mut shm_lock);
+  for region in shared_regions.iter() {
+    if !region.in_use || region.peer == NO_PEER {
+      continue;
+    }
+    if (region.owner == eid && enclave::enclave_is_running(region.peer))
+      || (region.peer == eid && enclave::enclave_is_running(region.owner)) {
+      ret = true;
+      break;
+    }
+  }
+  opensbi::spin_unlock(&mut shm_lock);
+  return ret;
+}
+
+/*
+ * Ends all sharing of enclave `eid` as it is destroyed.
+ * Regions it owns or accepted are scrubbed and go back to the host; the
+ * other party, which is not running, loses them as well. The scrub and
+ * the PMP IPIs happen without shm_lock, which every context switch
+ * takes; the slots are hidden meanwhile. A region that fails to go is
+ * put back, for the next destroy call to try again.
+ */
+pub fn revoke_shared(eid: enclave_id) -> Result<(), SmError> {
+  let ret: Result<(), SmError> = Ok(());
+  let rids: [region_id; SHARED_REGIONS_MAX] = [-1; SHARED_REGIONS_MAX];
+
+  opensbi::spin_lock(&mut shm_lock);
+  for i in 0..SHARED_REGIONS_MAX {
+    if shared_regions[i].in_use && (shared_regions[i].owner == eid || shared_regions[i].peer == eid) {
+      // still in use, so the slot is not handed out, but neither opened nor accepted
+      rids[i] = shared_regions[i].rid;
+      shared_regions[i].rid = -1;
+    }
+  }
+  opensbi::spin_unlock(&mut shm_lock);
+
+  for i in 0..SHARED_REGIONS_MAX {
+    if rids[i] < 0 {
+      continue;
+    }
+    let rid: region_id = rids[i];
+    opensbi::sbi_memset(pmp::pmp_region_get_addr(rid) as usize, 0, pmp::pmp_region_get_size(rid) as usize);
+    let step: Result<(), SmError> = pmp::pmp_unset_global(rid).and_then(|_| pmp::pmp_region_free_atomic(rid));
+    if step.is_ok() {
+      rids[i] = -1;
+    }
+    ret = ret.and(step);
+  }
+
+  opensbi::spin_lock(&mut shm_lock);
+  for i in 0..SHARED_REGIONS_MAX {
+    if shared_regions[i].in_use && (shared_regions[i].owner == eid || shared_regions[i].peer == eid) {
+      if rids[i] >= 0 {
+        shared_regions[i].rid = rids[i];
+      }
+      else {
+        shared_regions[i] = shared_region::new();
+      }
+    }
+  }
+  opensbi::spin_unlock(&mut shm_lock);
+  return ret;
+}
+
+fn release_slot(id: usize) {
+  opensbi::spin_lock(&mut shm_lock);
+  shared_regions[id] = shared_region::new();
+  opensbi::spin_unlock(&mut shm_lock);
+}
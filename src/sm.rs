@@ -1,9 +1,11 @@
 use crate::crypto;
 use crate::pmp;
 use crate::error_code::ERROR;
+use crate::error_code::SmError;
 use crate::enclave;
 use crate::opensbi;
 use crate::platform;
+use crate::provision;
 
 const SMM_BASE: usize = 0x80000000;
 const SMM_SIZE: usize = 0x200000;
@@ -15,6 +17,26 @@ const SBI_SM_CREATE_ENCLAVE: usize = 2001;
 const SBI_SM_DESTROY_ENCLAVE: usize = 2002;
 const SBI_SM_RUN_ENCLAVE: usize = 2003;
 const SBI_SM_RESUME_ENCLAVE: usize = 2005;
+const SBI_SM_PROVISION_DEVICE_KEY: usize = 2010;
+const SBI_SM_DONATE_MEMORY: usize = 2011;
+const SBI_SM_COLLECT_RELEASED_MEMORY: usize = 2012;
+const SBI_SM_CLONE_ENCLAVE: usize = 2013;
+const SBI_SM_CHECKPOINT_ENCLAVE: usize = 2014;
+const SBI_SM_RESTORE_ENCLAVE: usize = 2015;
+const SBI_SM_MIGRATE_PREPARE: usize = 2016;
+const SBI_SM_MIGRATE_EXPORT: usize = 2017;
+const SBI_SM_MIGRATE_IMPORT: usize = 2018;
+const SBI_SM_DEBUG_READ: usize = 2019;
+const SBI_SM_DEBUG_WRITE: usize = 2020;
+const SBI_SM_DEBUG_GET_REGS: usize = 2021;
+const SBI_SM_DEBUG_SET_REGS: usize = 2022;
+const SBI_SM_DEBUG_STEP: usize = 2023;
+const SBI_SM_DEBUG_CONTINUE: usize = 2024;
+const SBI_SM_GET_FAULT: usize = 2025;
+const SBI_SM_ENCLAVE_STATS: usize = 2026;
+const SBI_SM_QUERY_ENCLAVE: usize = 2027;
+const SBI_SM_KILL_ENCLAVE: usize = 2028;
+const SBI_SM_DEBUG_TRANSLATE: usize = 2029;
 const FID_RANGE_HOST: usize = 2999;
 /* 3000-3999 are called by enclave */
 const SBI_SM_RANDOM: usize = 3001;
@@ -22,41 +44,58 @@ const SBI_SM_ATTEST_ENCLAVE: usize = 3002;
 const SBI_SM_GET_SEALING_KEY: usize = 3003;
 const SBI_SM_STOP_ENCLAVE: usize = 3004;
 const SBI_SM_EXIT_ENCLAVE: usize = 3006;
+const SBI_SM_COUNTER_CREATE: usize = 3007;
+const SBI_SM_COUNTER_INCREMENT: usize = 3008;
+const SBI_SM_COUNTER_READ: usize = 3009;
+const SBI_SM_COUNTER_DESTROY: usize = 3010;
+const SBI_SM_LOCAL_ATTEST_ENCLAVE: usize = 3011;
+const SBI_SM_GET_REPORT_KEY: usize = 3012;
+const SBI_SM_ACCEPT_MEMORY: usize = 3013;
+const SBI_SM_RELEASE_MEMORY: usize = 3014;
+const SBI_SM_SHARE_OFFER: usize = 3015;
+const SBI_SM_SHARE_ACCEPT: usize = 3016;
+const SBI_SM_MIGRATE_AUTHORIZE: usize = 3017;
+const SBI_SM_PAGE_EVICT: usize = 3018;
+const SBI_SM_PAGE_LOAD: usize = 3019;
 const FID_RANGE_ENCLAVE: usize = 3999;
 /* 4000-4999 are experimental */
 const SBI_SM_CALL_PLUGIN: usize = 4000;
 const FID_RANGE_CUSTOM: usize = 4999;
 
-/* error codes */
-const SBI_ERR_SM_ENCLAVE_SUCCESS: usize = 0;
-const SBI_ERR_SM_ENCLAVE_UNKNOWN_ERROR: usize = 100000;
-const SBI_ERR_SM_ENCLAVE_INVALID_ID: usize = 100001;
-const SBI_ERR_SM_ENCLAVE_INTERRUPTED: usize = 100002;
-const SBI_ERR_SM_ENCLAVE_PMP_FAILURE: usize = 100003;
-const SBI_ERR_SM_ENCLAVE_NOT_RUNNABLE: usize = 100004;
-const SBI_ERR_SM_ENCLAVE_NOT_DESTROYABLE: usize = 100005;
-const SBI_ERR_SM_ENCLAVE_REGION_OVERLAPS: usize = 100006;
-const SBI_ERR_SM_ENCLAVE_NOT_ACCESSIBLE: usize = 100007;
-const SBI_ERR_SM_ENCLAVE_ILLEGAL_ARGUMENT: usize = 100008;
-const SBI_ERR_SM_ENCLAVE_NOT_RUNNING: usize = 100009;
-const SBI_ERR_SM_ENCLAVE_NOT_RESUMABLE: usize = 100010;
-const SBI_ERR_SM_ENCLAVE_EDGE_CALL_HOST: usize = 100011;
-const SBI_ERR_SM_ENCLAVE_NOT_INITIALIZED: usize = 100012;
-const SBI_ERR_SM_ENCLAVE_NO_FREE_RESOURCE: usize = 100013;
-const SBI_ERR_SM_ENCLAVE_SBI_PROHIBITED: usize = 100014;
-const SBI_ERR_SM_ENCLAVE_ILLEGAL_PTE: usize = 100015;
-const SBI_ERR_SM_ENCLAVE_NOT_FRESH: usize = 100016;
-const SBI_ERR_SM_DEPRECATED: usize = 100099;
-const SBI_ERR_SM_NOT_IMPLEMENTED: usize = 100100;
-
-const SBI_ERR_SM_PMP_SUCCESS: usize = 0;
-const SBI_ERR_SM_PMP_REGION_SIZE_INVALID: usize = 100020;
-const SBI_ERR_SM_PMP_REGION_NOT_PAGE_GRANULARITY: usize = 100021;
-const SBI_ERR_SM_PMP_REGION_NOT_ALIGNED: usize = 100022;
-const SBI_ERR_SM_PMP_REGION_MAX_REACHED: usize = 100023;
-const SBI_ERR_SM_PMP_REGION_INVALID: usize = 100024;
-const SBI_ERR_SM_PMP_REGION_OVERLAP: usize = 100025;
-const SBI_ERR_SM_PMP_REGION_IMPOSSIBLE_TOR: usize = 100026;
+/*
+ * SM feature flags, reported in the SM part of every report.
+ * The bit values are part of the report format: never renumber them.
+ * Only what a verifier weighs when it accepts an enclave is set: how its
+ * keys are bound, whether rollback can be detected, and whether its state
+ * can be copied or taken out of this instance.
+ */
+pub mod sm_feature {
+  pub const SM_FEATURE_CONFIG_ATTESTATION: u64 = 1 << 0;
+  pub const SM_FEATURE_MONOTONIC_COUNTERS: u64 = 1 << 1;
+  pub const SM_FEATURE_SEALING_KEY_POLICY: u64 = 1 << 2;
+  pub const SM_FEATURE_LOCAL_ATTESTATION: u64 = 1 << 3;
+  pub const SM_FEATURE_DYNAMIC_MEMORY: u64 = 1 << 4;
+  pub const SM_FEATURE_MEMORY_RELEASE: u64 = 1 << 5;
+  pub const SM_FEATURE_MULTI_UTM: u64 = 1 << 6;
+  pub const SM_FEATURE_SHARED_MEMORY: u64 = 1 << 7;
+  pub const SM_FEATURE_MAILBOX: u64 = 1 << 8;
+  pub const SM_FEATURE_ENCLAVE_CLONE: u64 = 1 << 9;
+  pub const SM_FEATURE_CHECKPOINT: u64 = 1 << 10;
+  pub const SM_FEATURE_MIGRATION: u64 = 1 << 11;
+  pub const SM_FEATURE_PAGE_SWAP: u64 = 1 << 12;
+  pub const SM_FEATURE_DEBUG: u64 = 1 << 13;
+  pub const SM_FEATURE_FAULT_REPORT: u64 = 1 << 14;
+  pub const SM_FEATURE_EXEC_BUDGET: u64 = 1 << 15;
+  pub const SM_FEATURE_ENCLAVE_QUERY: u64 = 1 << 16;
+  pub const SM_FEATURE_FORCED_KILL: u64 = 1 << 17;
+}
+
+pub const SM_FEATURES: u64 = sm_feature::SM_FEATURE_CONFIG_ATTESTATION
+  | sm_feature::SM_FEATURE_MONOTONIC_COUNTERS
+  | sm_feature::SM_FEATURE_SEALING_KEY_POLICY
+  | sm_feature::SM_FEATURE_ENCLAVE_CLONE
+  | sm_feature::SM_FEATURE_CHECKPOINT
+  | sm_feature::SM_FEATURE_MIGRATION;
 
 pub static mut sm_init_done: i32 = 0;
 pub static mut sm_region_id: i32 = 0; 
@@ -75,18 +114,18 @@ pub static mut dev_public_key: [u8;crypto::PUBLIC_KEY_SIZE] = [0;crypto::PUBLIC_
 // extern sanctum_sm_public_key[u8;PUBLIC_KEY_SIZE];
 // extern sanctum_dev_public_key[u8;PUBLIC_KEY_SIZE];
 
-unsafe fn any_as_u8_slice<T: Sized>(p: &T) -> &[u8] {
-  ::std::slice::from_raw_parts(
-      (p as *const T) as *const u8,
-      ::std::mem::size_of::<T>(),
-  )
-}
-
 struct keystone_sbi_pregion { // physical memory
   pub paddr: u32,
   pub size: u32
 }
 
+pub struct keystone_sbi_utm_region { // untrusted shared pages
+  pub paddr: u32,
+  pub size: u32, // 0 if unused
+  pub perm: u32 // see enclave::utm_perm
+}
+
+#[derive(Clone, Copy)]
 pub struct runtime_pa_params {
   pub dram_base: usize,
   pub dram_size: usize, 
@@ -107,6 +146,7 @@ impl runtime_pa_params {
   }
 } 
 
+#[derive(Clone, Copy)]
 pub struct runtime_va_params_t {
   pub runtime_entry: u32,
   pub user_entry: u32,
@@ -128,54 +168,154 @@ impl runtime_va_params_t {
 pub struct keystone_sbi_create {
   pub epm_region: keystone_sbi_pregion, // enclave private memory
   pub utm_region: keystone_sbi_pregion, // untrusted shared pages
+  pub extra_utm_regions: [keystone_sbi_utm_region; enclave::EXTRA_UTM_MAX], // additional shared pages, at most ENCLAVE_REGIONS_MAX - 2 used
 
   pub runtime_paddr: u32,
   pub user_paddr: u32,
   pub free_paddr: u32,
 
   pub params: runtime_va_params_t,
-  pub eid_pptr: *mut u32
+  pub eid_pptr: *mut u32,
+
+  /* enclave configuration, see enclave::enclave_config */
+  pub attributes: u32,
+  pub max_threads: u32,
+  pub svn: u32,
+  pub thread_entries: [u32; enclave::MAX_ENCL_THREADS], // per-thread user entry, 0 for params.user_entry
+
+  /* execution budget in timer ticks, 0 for none, see keystone_sbi_stats */
+  pub max_ticks: u64, // over the life of the enclave
+  pub resume_ticks: u64, // per run or resume of a thread
+
+  /* optional signer, all-zero public key if the enclave is unsigned */
+  pub signer_public_key: [u8; crypto::PUBLIC_KEY_SIZE],
+  pub signer_signature: [u8; crypto::SIGNATURE_SIZE]
 }
 
-pub fn osm_pmp_set(perm: u8) -> i32{
-  /* in case of OSM, PMP cfg is exactly the opposite.*/
-  return pmp::pmp_set_keystone(os_region_id, perm);
+/* memory for a clone or a restored or imported enclave, both regions must be as large as the source's */
+pub struct keystone_sbi_clone {
+  pub epm_region: keystone_sbi_pregion,
+  pub utm_region: keystone_sbi_pregion
+}
+
+/*
+ * Why a thread stopped with SBI_ERR_SM_ENCLAVE_FAULT. Addresses are cut
+ * down to their page and tval is 0 for faults without an address, unless
+ * the enclave is a debug one.
+ */
+#[derive(Clone, Copy)]
+pub struct keystone_sbi_fault {
+  pub cause: usize, // mcause of the fault
+  pub tval: usize,
+  pub epc: usize,
+  pub mode: usize // privilege it happened in, PRV_U for the user code, PRV_S for the runtime
+}
+
+impl keystone_sbi_fault {
+  pub fn new() -> Self {
+    Self {
+      cause: 0,
+      tval: 0,
+      epc: 0,
+      mode: 0
+    }
+  }
+}
+
+/*
+ * Time enclave `eid` spent on harts, in timer ticks, with the budget
+ * it was created with. Threads still running are counted up to the call.
+ */
+#[derive(Clone, Copy)]
+pub struct keystone_sbi_stats {
+  pub ticks: u64, // all threads together
+  pub max_ticks: u64,
+  pub resume_ticks: u64,
+  pub entries: u64, // runs and resumes
+  pub budget_stops: u64, // times it was stopped for going over budget
+  pub thread_ticks: [u64; enclave::MAX_ENCL_THREADS],
+  pub thread_last_ticks: [u64; enclave::MAX_ENCL_THREADS] // of the last run or resume
+}
+
+impl keystone_sbi_stats {
+  pub fn new() -> Self {
+    Self {
+      ticks: 0,
+      max_ticks: 0,
+      resume_ticks: 0,
+      entries: 0,
+      budget_stops: 0,
+      thread_ticks: [0; enclave::MAX_ENCL_THREADS],
+      thread_last_ticks: [0; enclave::MAX_ENCL_THREADS]
+    }
+  }
+}
+
+/* an enclave as the host sees it, see SBI_SM_QUERY_ENCLAVE */
+#[derive(Clone, Copy)]
+pub struct keystone_sbi_enclave_info {
+  pub eid: u64,
+  pub state: u64, // enclave::enclave_state: 1 DESTROYING, 2 ALLOCATED, 3 FRESH, 4 STOPPED, 5 RUNNING
+  pub epm_size: u64, // private memory, accepted donations included
+  pub utm_size: u64, // all shared regions
+  pub donated_size: u64, // donated but not accepted yet
+  pub max_threads: u64,
+  pub running_threads: u64,
+  pub stopped_threads: u64,
+  pub hash: [u8; crypto::MDSIZE] // measurement, zero before the enclave is FRESH
 }
 
-pub fn smm_init() -> i32 {
-  let region: i32 = -1;
-  let ret: i32 = pmp::pmp_region_init_atomic(SMM_BASE, SMM_SIZE, pmp::pmp_priority::PMP_PRI_TOP, &mut region, 0);
-  if ret != 0 {
-    return -1;
+impl keystone_sbi_enclave_info {
+  pub fn new() -> Self {
+    Self {
+      eid: 0,
+      state: 0,
+      epm_size: 0,
+      utm_size: 0,
+      donated_size: 0,
+      max_threads: 0,
+      running_threads: 0,
+      stopped_threads: 0,
+      hash: [0; crypto::MDSIZE]
+    }
   }
+}
 
-  return region;
+/* registers of a stopped thread of a debug enclave, x[0] reads as zero and is ignored on write */
+pub struct keystone_sbi_regs {
+  pub x: [usize; 32],
+  pub pc: usize
 }
 
-pub fn osm_init() -> i32 {
-  let region: i32 = -1;
-  let ret: i32 = pmp::pmp_region_init_atomic(0, usize::MAX, pmp::pmp_priority::PMP_PRI_BOTTOM, &mut region, 1);
-  if ret != 0 {
-    return -1;
+impl keystone_sbi_regs {
+  pub fn new() -> Self {
+    Self {
+      x: [0; 32],
+      pc: 0
+    }
   }
-  return region;
 }
 
-pub fn sm_derive_sealing_key(key: &mut [u8], key_ident: &[u8], key_ident_size: usize, enclave_hash: &[u8]) -> i32 {
+pub fn osm_pmp_set(perm: u8) -> Result<(), SmError> {
+  /* in case of OSM, PMP cfg is exactly the opposite.*/
+  return pmp::pmp_set_keystone(os_region_id, perm);
+}
 
-  let info: usize;
+pub fn smm_init() -> Result<pmp::region_id, SmError> {
+  return pmp::pmp_region_init_atomic(SMM_BASE, SMM_SIZE, pmp::pmp_priority::PMP_PRI_TOP, 0);
+}
 
-  // opensbi 函数
-  opensbi::sbi_memcpy(info, enclave_hash.as_ptr() as usize, crypto::MDSIZE);
-  opensbi::sbi_memcpy(info + crypto::MDSIZE, key_ident.as_ptr() as usize, key_ident_size);
+pub fn osm_init() -> Result<pmp::region_id, SmError> {
+  return pmp::pmp_region_init_atomic(0, usize::MAX, pmp::pmp_priority::PMP_PRI_BOTTOM, 1);
+}
 
+pub fn sm_derive_key(key: &mut [u8], info: &[u8]) -> i32 {
   /*
   * The key is derived without a salt because we have no entropy source
   * available to generate the salt.
   */
   unsafe {
-    let info_ptr: &[u8] = any_as_u8_slice(&info);
-    return crypto::kdf(&mut [0], &sm_private_key, info_ptr, key);
+    return crypto::kdf(&mut [0], &sm_private_key, info, key);
   }
 }
 
@@ -219,27 +359,34 @@ fn sm_init(cold_boot: bool) {
     // Reviewed-by: Atish Patra <atish.patra@wdc.com>
     // opensbi::sbi_ecall_register_extension(&ecall_keystone_enclave);
 
-    sm_region_id = smm_init();
-    if sm_region_id < 0 {
-      // opensbi
-      println!("[SM] intolerable error - failed to initialize SM memory");
-      opensbi::sbi_hart_hang();
+    match smm_init() {
+      Ok(rid) => sm_region_id = rid,
+      Err(err) => {
+        // opensbi
+        println!("[SM] intolerable error - failed to initialize SM memory ({:?})", err);
+        opensbi::sbi_hart_hang();
+      }
     }
 
-    os_region_id = osm_init();
-    if os_region_id < 0 {
-      // opensbi
-      println!("[SM] intolerable error - failed to initialize OS memory");
-      opensbi::sbi_hart_hang();
+    match osm_init() {
+      Ok(rid) => os_region_id = rid,
+      Err(err) => {
+        // opensbi
+        println!("[SM] intolerable error - failed to initialize OS memory ({:?})", err);
+        opensbi::sbi_hart_hang();
+      }
     }
 
-    if platform::platform_init_global_once() != SBI_ERR_SM_ENCLAVE_SUCCESS {
+    if platform::platform_init_global_once() != ERROR::SBI_ERR_SM_ENCLAVE_SUCCESS {
       println!("[SM] platform global init fatal error");
       opensbi::sbi_hart_hang();
     }
     // Copy the keypair from the root of trust
     sm_copy_key();
 
+    // Replace it with the provisioned device keys, if any
+    provision::provision_init(); // provision.rs
+
     // Init the enclave metadata
     enclave::enclave_init_metadata(); // enclave.rs
 
@@ -254,8 +401,12 @@ fn sm_init(cold_boot: bool) {
 
   /* below are executed by all harts */
   pmp::pmp_init();
-  pmp::pmp_set_keystone(sm_region_id, pmp::PMP_NO_PERM);
-  pmp::pmp_set_keystone(os_region_id, pmp::PMP_ALL_PERM);
+  if pmp::pmp_set_keystone(sm_region_id, pmp::PMP_NO_PERM).is_err()
+    || pmp::pmp_set_keystone(os_region_id, pmp::PMP_ALL_PERM).is_err() {
+    // opensbi
+    println!("[SM] intolerable error - failed to set up PMP");
+    opensbi::sbi_hart_hang();
+  }
 
   /* Fire platform specific global init */
   if platform::platform_init_global() != ERROR::SBI_ERR_SM_ENCLAVE_SUCCESS {
@@ -3,40 +3,121 @@ use crate::sm;
 use crate::cpu;
 use crate::opensbi;
 use crate::sbi_trap;
-
-unsafe fn any_as_u8_slice<T: Sized>(p: &T) -> &[u8] {
-    ::std::slice::from_raw_parts(
-        (p as *const T) as *const u8,
-        ::std::mem::size_of::<T>(),
-    )
-}
+use crate::provision;
+use crate::counter;
+use crate::shm;
+use crate::mailbox;
+use crate::migrate;
+use crate::swap;
+use crate::error_code::ERROR;
+use crate::error_code::sbi_ret;
 
 fn sbi_sm_create_enclave(eid: *mut usize, create_args: usize) -> usize {
     let create_args_local: sm::keystone_sbi_create; // sm.rs encalve argument
-    let ret: usize = enclave::copy_enclave_create_args(create_args, &mut create_args_local) as usize; // enclave.rs
+    let ret = enclave::copy_enclave_create_args(create_args, &mut create_args_local) // enclave.rs
+        .and_then(|_| enclave::create_enclave(eid, create_args_local)); // enclave.rs
+    return sbi_ret(ret);
+}
 
-    if ret != 0 { // create fail
-        return ret;
-    }
+fn sbi_sm_clone_enclave(eid: *mut usize, src_eid: usize, clone_args: usize) -> usize {
+    let clone_args_local: sm::keystone_sbi_clone; // sm.rs clone argument
+    let ret = enclave::copy_enclave_clone_args(clone_args, &mut clone_args_local) // enclave.rs
+        .and_then(|_| enclave::clone_enclave(eid, src_eid, clone_args_local)); // enclave.rs
+    return sbi_ret(ret);
+}
+
+fn sbi_sm_checkpoint_enclave(eid: usize) -> usize {
+    let ret: usize = sbi_ret(enclave::checkpoint_enclave(eid)); // enclave.rs
+    return ret;
+}
+
+fn sbi_sm_restore_enclave(eid: *mut usize, restore_args: usize) -> usize {
+    let restore_args_local: sm::keystone_sbi_clone; // sm.rs restore argument
+    let ret = enclave::copy_enclave_clone_args(restore_args, &mut restore_args_local) // enclave.rs
+        .and_then(|_| enclave::restore_enclave(eid, restore_args_local)); // enclave.rs
+    return sbi_ret(ret);
+}
+
+fn sbi_sm_migrate_prepare(offer: usize) -> usize {
+    let ret: usize = sbi_ret(migrate::prepare(offer)); // migrate.rs
+    return ret;
+}
+
+fn sbi_sm_migrate_export(eid: usize, offer: usize) -> usize {
+    let ret: usize = sbi_ret(enclave::export_enclave(eid, offer)); // enclave.rs
+    return ret;
+}
+
+fn sbi_sm_migrate_import(eid: *mut usize, import_args: usize) -> usize {
+    let import_args_local: sm::keystone_sbi_clone; // sm.rs import argument
+    let ret = enclave::copy_enclave_clone_args(import_args, &mut import_args_local) // enclave.rs
+        .and_then(|_| enclave::import_enclave(eid, import_args_local)); // enclave.rs
+    return sbi_ret(ret);
+}
+
+fn sbi_sm_debug_read(eid: usize, paddr: usize, buf: usize, size: usize) -> usize {
+    let ret: usize = sbi_ret(enclave::debug_read(eid, paddr, buf, size)); // enclave.rs
+    return ret;
+}
+
+fn sbi_sm_debug_write(eid: usize, paddr: usize, buf: usize, size: usize) -> usize {
+    let ret: usize = sbi_ret(enclave::debug_write(eid, paddr, buf, size)); // enclave.rs
+    return ret;
+}
+
+fn sbi_sm_debug_get_regs(eid: usize, tid: usize, regs: usize) -> usize {
+    let ret: usize = sbi_ret(enclave::debug_get_regs(eid, tid, regs)); // enclave.rs
+    return ret;
+}
+
+fn sbi_sm_debug_set_regs(eid: usize, tid: usize, regs: usize) -> usize {
+    let ret: usize = sbi_ret(enclave::debug_set_regs(eid, tid, regs)); // enclave.rs
+    return ret;
+}
 
-    ret = enclave::create_enclave(eid, create_args_local) as usize; // enclave.rs
+fn sbi_sm_debug_translate(eid: usize, tid: usize, va: usize, pa: usize) -> usize {
+    let ret: usize = sbi_ret(enclave::debug_translate_va(eid, tid, va, pa)); // enclave.rs
+    return ret;
+}
+
+fn sbi_sm_get_fault(eid: usize, tid: usize, fault: usize) -> usize {
+    let ret: usize = sbi_ret(enclave::get_fault(eid, tid, fault)); // enclave.rs
+    return ret;
+}
+
+fn sbi_sm_enclave_stats(eid: usize, stats: usize) -> usize {
+    let ret: usize = sbi_ret(enclave::get_stats(eid, stats)); // enclave.rs
+    return ret;
+}
+
+fn sbi_sm_query_enclave(eid: usize, info: usize) -> usize {
+    let ret: usize = sbi_ret(enclave::query_enclave(eid, info)); // enclave.rs
     return ret;
 }
 
 fn sbi_sm_destroy_enclave(eid: usize) -> usize {
-    let ret: usize = enclave::destroy_enclave(eid); // enclave.rs
+    let ret: usize = sbi_ret(enclave::destroy_enclave(eid)); // enclave.rs
     return ret;
 }
 
-fn sbi_sm_run_enclave(regs: &mut sbi_trap::sbi_trap_regs /*opensbi*/, eid: usize) -> usize {
-    regs.a0 = enclave::run_enclave(regs, eid); // enclave.rs
+fn sbi_sm_kill_enclave(eid: usize) -> usize {
+    // the caller waits for the enclave to leave every hart, its own included
+    if cpu::cpu_is_enclave_context() != 0 {
+        return ERROR::SBI_ERR_SM_ENCLAVE_SBI_PROHIBITED;
+    }
+    let ret: usize = sbi_ret(enclave::kill_enclave(eid)); // enclave.rs
+    return ret;
+}
+
+fn sbi_sm_run_enclave(regs: &mut sbi_trap::sbi_trap_regs /*opensbi*/, eid: usize, tid: usize) -> usize {
+    regs.a0 = sbi_ret(enclave::run_enclave(regs, eid, tid)); // enclave.rs
     regs.mepc += 4;
     opensbi::sbi_trap_exit(regs); // opensbi 提供函数
     0
 }
 
 fn sbi_sm_exit_enclave(regs: &mut sbi_trap::sbi_trap_regs, retval: usize) -> usize {
-    regs.a0 = enclave::exit_enclave(regs, cpu::cpu_get_enclave_id()); // enclave.rs
+    regs.a0 = sbi_ret(enclave::exit_enclave(regs, cpu::cpu_get_enclave_id(), cpu::cpu_get_thread_id())); // enclave.rs
     regs.a1 = retval;
     regs.mepc += 4;
     opensbi::sbi_trap_exit(regs); // opensbi 提供函数
@@ -44,14 +125,43 @@ fn sbi_sm_exit_enclave(regs: &mut sbi_trap::sbi_trap_regs, retval: usize) -> usi
 }
 
 pub fn sbi_sm_stop_enclave(regs: &mut sbi_trap::sbi_trap_regs, request: usize) -> usize {
-    regs.a0 = enclave::stop_enclave(regs, request, cpu::cpu_get_enclave_id()); // enclave.rs
+    regs.a0 = sbi_ret(enclave::stop_enclave(regs, request, cpu::cpu_get_enclave_id(), cpu::cpu_get_thread_id())); // enclave.rs
     regs.mepc += 4;
     opensbi::sbi_trap_exit(regs); // opensbi 提供函数
     0
 }
 
-fn sbi_sm_resume_enclave(regs: &mut sbi_trap::sbi_trap_regs, eid: usize) -> usize {
-    let ret: usize = enclave::resume_enclave(regs, eid); // enclave.rs
+pub fn sbi_sm_fault_enclave(regs: &mut sbi_trap::sbi_trap_regs, fault: &sm::keystone_sbi_fault) -> usize {
+    regs.a0 = sbi_ret(enclave::fault_enclave(regs, fault, cpu::cpu_get_enclave_id(), cpu::cpu_get_thread_id())); // enclave.rs
+    regs.mepc += 4;
+    opensbi::sbi_trap_exit(regs); // opensbi 提供函数
+    0
+}
+
+fn sbi_sm_resume_enclave(regs: &mut sbi_trap::sbi_trap_regs, eid: usize, tid: usize) -> usize {
+    let ret: usize = sbi_ret(enclave::resume_enclave(regs, eid, tid)); // enclave.rs
+    if regs.zero == 0 {
+        regs.a0 = ret;
+    }
+    regs.mepc += 4;
+
+    opensbi::sbi_trap_exit(regs); // opensbi 提供函数
+    return 0;
+}
+
+fn sbi_sm_debug_step(regs: &mut sbi_trap::sbi_trap_regs, eid: usize, tid: usize) -> usize {
+    let ret: usize = sbi_ret(enclave::debug_step_enclave(regs, eid, tid)); // enclave.rs
+    if regs.zero == 0 {
+        regs.a0 = ret;
+    }
+    regs.mepc += 4;
+
+    opensbi::sbi_trap_exit(regs); // opensbi 提供函数
+    return 0;
+}
+
+fn sbi_sm_debug_continue(regs: &mut sbi_trap::sbi_trap_regs, eid: usize, tid: usize) -> usize {
+    let ret: usize = sbi_ret(enclave::debug_continue_enclave(regs, eid, tid)); // enclave.rs
     if regs.zero == 0 {
         regs.a0 = ret;
     }
@@ -62,16 +172,102 @@ fn sbi_sm_resume_enclave(regs: &mut sbi_trap::sbi_trap_regs, eid: usize) -> usiz
 }
 
 fn sbi_sm_attest_enclave(report: usize, data: usize, size: usize) -> usize {
-    let ret: usize = enclave::attest_enclave(report, data, size, cpu::cpu_get_enclave_id()); // enclave.rs
+    let ret: usize = sbi_ret(enclave::attest_enclave(report, data, size, cpu::cpu_get_enclave_id())); // enclave.rs
+    return ret;
+}
+
+fn sbi_sm_local_attest_enclave(report: usize, target: usize, data: usize) -> usize {
+    let ret: usize = sbi_ret(enclave::local_attest_enclave(report, target, data, cpu::cpu_get_enclave_id())); // enclave.rs
+    return ret;
+}
+
+fn sbi_sm_get_report_key(key: usize) -> usize {
+    let ret: usize = sbi_ret(enclave::get_report_key(key, cpu::cpu_get_enclave_id())); // enclave.rs
+    return ret;
+}
+
+fn sbi_sm_get_sealing_key(seal_key: usize, key_request: usize) -> usize {
+    let request: enclave::sealing_key_request; // enclave.rs key request
+    let ret = enclave::copy_sealing_key_request(key_request, &mut request) // enclave.rs
+        .and_then(|_| enclave::get_sealing_key(seal_key, &request, cpu::cpu_get_enclave_id())); // enclave.rs cpu.rs
+    return sbi_ret(ret);
+}
+
+fn sbi_sm_counter_create(counter_id: usize) -> usize {
+    let owner: &[u8] = enclave::get_enclave_hash(cpu::cpu_get_enclave_id()); // enclave.rs cpu.rs
+    let ret: usize = sbi_ret(counter::counter_create(counter_id, owner)); // counter.rs
+    return ret;
+}
+
+fn sbi_sm_counter_increment(id: usize, value: usize) -> usize {
+    let owner: &[u8] = enclave::get_enclave_hash(cpu::cpu_get_enclave_id());
+    let ret: usize = sbi_ret(counter::counter_increment(id, value, owner));
+    return ret;
+}
+
+fn sbi_sm_counter_read(id: usize, value: usize) -> usize {
+    let owner: &[u8] = enclave::get_enclave_hash(cpu::cpu_get_enclave_id());
+    let ret: usize = sbi_ret(counter::counter_read(id, value, owner));
+    return ret;
+}
+
+fn sbi_sm_counter_destroy(id: usize) -> usize {
+    let owner: &[u8] = enclave::get_enclave_hash(cpu::cpu_get_enclave_id());
+    let ret: usize = sbi_ret(counter::counter_destroy(id, owner));
+    return ret;
+}
+
+fn sbi_sm_donate_memory(eid: usize, paddr: usize, size: usize) -> usize {
+    let ret: usize = sbi_ret(enclave::donate_memory(eid, paddr, size)); // enclave.rs
     return ret;
 }
 
-fn sbi_sm_get_sealing_key(seal_key: usize, key_ident: usize, key_ident_size: usize) -> usize {
-    unsafe {
-        let key_ident_ptr = any_as_u8_slice(&key_ident);
-        let ret: usize = enclave::get_sealing_key(seal_key as *mut enclave::sealing_key, key_ident_ptr, key_ident_size, cpu::cpu_get_enclave_id()); // enclave.rs cpu.rs
-        return ret;
+fn sbi_sm_accept_memory(paddr: usize, size: usize) -> usize {
+    let ret: usize = sbi_ret(enclave::accept_memory(cpu::cpu_get_enclave_id(), paddr, size)); // enclave.rs
+    return ret;
+}
+
+fn sbi_sm_release_memory(paddr: usize, size: usize) -> usize {
+    let ret: usize = sbi_ret(enclave::release_memory(cpu::cpu_get_enclave_id(), paddr, size)); // enclave.rs
+    return ret;
+}
+
+fn sbi_sm_collect_released_memory(eid: usize, range: usize) -> usize {
+    let ret: usize = sbi_ret(enclave::collect_released_memory(eid, range)); // enclave.rs
+    return ret;
+}
+
+fn sbi_sm_share_offer(paddr: usize, size: usize, peer_hash: usize, id: usize) -> usize {
+    let ret: usize = sbi_ret(shm::share_offer(cpu::cpu_get_enclave_id(), paddr, size, peer_hash, id)); // shm.rs
+    return ret;
+}
+
+fn sbi_sm_share_accept(id: usize, info: usize) -> usize {
+    let ret: usize = sbi_ret(shm::share_accept(cpu::cpu_get_enclave_id(), id, info)); // shm.rs
+    return ret;
+}
+
+fn sbi_sm_migrate_authorize(dev_key: usize) -> usize {
+    let ret: usize = sbi_ret(enclave::authorize_migration(cpu::cpu_get_enclave_id(), dev_key)); // enclave.rs
+    return ret;
+}
+
+fn sbi_sm_page_evict(paddr: usize, slot: usize, dest: usize) -> usize {
+    let ret: usize = sbi_ret(swap::page_evict(cpu::cpu_get_enclave_id(), paddr, slot, dest)); // swap.rs
+    return ret;
+}
+
+fn sbi_sm_page_load(paddr: usize, slot: usize, src: usize) -> usize {
+    let ret: usize = sbi_ret(swap::page_load(cpu::cpu_get_enclave_id(), paddr, slot, src)); // swap.rs
+    return ret;
+}
+
+fn sbi_sm_provision_device_key(secret: usize, size: usize) -> usize {
+    if cpu::cpu_is_enclave_context() != 0 {
+        return ERROR::SBI_ERR_SM_ENCLAVE_SBI_PROHIBITED;
     }
+    let ret: usize = sbi_ret(provision::provision_device_key(secret, size)); // provision.rs
+    return ret;
 }
 
 // fn sbi_sm_random() -> usize {
@@ -80,5 +276,15 @@ fn sbi_sm_get_sealing_key(seal_key: usize, key_ident: usize, key_ident_size: usi
 
 
 fn sbi_sm_call_plugin(plugin_id: usize, call_id: usize, arg0: usize, arg1: usize) -> usize {
-    0
+    // plugins act on behalf of the calling enclave
+    if cpu::cpu_is_enclave_context() == 0 {
+        return ERROR::SBI_ERR_SM_ENCLAVE_SBI_PROHIBITED;
+    }
+
+    let eid: usize = cpu::cpu_get_enclave_id();
+    let ret: usize = match plugin_id {
+        mailbox::PLUGIN_ID_MAILBOX => sbi_ret(mailbox::mailbox_call(eid, call_id, arg0, arg1)), // mailbox.rs
+        _ => ERROR::SBI_ERR_SM_NOT_IMPLEMENTED
+    };
+    return ret;
 }
\ No newline at end of file
@@ -0,0 +1,96 @@
+use crate::error_code::SmError;
+use crate::platform;
+
+/*
+ * Persistent storage used by the SM for state that must survive a reboot
+ * (device secret, monotonic counters).
+ * Backends address fixed-size slots, see platform::storage_slot. A slot
+ * that was never written reads as zeros; any other failure is an error.
+ */
+pub trait storage_backend {
+  fn read(&self, slot: usize, buf: &mut [u8]) -> Result<(), SmError>;
+  fn write(&mut self, slot: usize, buf: &[u8]) -> Result<(), SmError>;
+}
+
+/* Storage provided by the platform hooks */
+pub struct platform_storage {
+
+}
+
+impl platform_storage {
+  pub fn new() -> Self {
+    Self {
+
+    }
+  }
+}
+
+impl storage_backend for platform_storage {
+  fn read(&self, slot: usize, buf: &mut [u8]) -> Result<(), SmError> {
+    return SmError::from_code(platform::platform_storage_read(slot, buf));
+  }
+
+  fn write(&mut self, slot: usize, buf: &[u8]) -> Result<(), SmError> {
+    return SmError::from_code(platform::platform_storage_write(slot, buf));
+  }
+}
+
+/* File-backed stand-in for testing off-target, one file per slot */
+#[cfg(feature = "file-storage")]
+pub struct file_storage {
+  dir: &'static str
+}
+
+#[cfg(feature = "file-storage")]
+const FILE_STORAGE_DIR: &str = "sm-storage";
+
+#[cfg(feature = "file-storage")]
+impl file_storage {
+  pub fn new() -> Self {
+    Self::in_dir(FILE_STORAGE_DIR)
+  }
+
+  pub fn in_dir(dir: &'static str) -> Self {
+    Self {
+      dir: dir
+    }
+  }
+
+  fn slot_path(&self, slot: usize) -> std::path::PathBuf {
+    return std::path::Path::new(self.dir).join(format!("slot{}", slot));
+  }
+}
+
+#[cfg(feature = "file-storage")]
+impl storage_backend for file_storage {
+  fn read(&self, slot: usize, buf: &mut [u8]) -> Result<(), SmError> {
+    match std::fs::read(self.slot_path(slot)) {
+      Ok(data) if data.len() == buf.len() => {
+        buf.copy_from_slice(&data);
+        return Ok(());
+      },
+      // never written, reads as erased
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+        buf.fill(0);
+        return Ok(());
+      },
+      _ => return Err(SmError::NotAccessible)
+    }
+  }
+
+  fn write(&mut self, slot: usize, buf: &[u8]) -> Result<(), SmError> {
+    if std::fs::create_dir_all(self.dir).is_err() {
+      return Err(SmError::NotAccessible);
+    }
+    match std::fs::write(self.slot_path(slot), buf) {
+      Ok(_) => return Ok(()),
+      Err(_) => return Err(SmError::NotAccessible)
+    }
+  }
+}
+
+#[cfg(not(feature = "file-storage"))]
+pub type sm_storage = platform_storage;
+
+#[cfg(feature = "file-storage")]
+pub type sm_storage = file_storage;
@@ -0,0 +1,229 @@
+use crate::error_code::SmError;
+use crate::enclave;
+use crate::enclave::enclave_id;
+use crate::config;
+use crate::crypto;
+use crate::opensbi;
+use crate::page;
+use crate::sm;
+
+use std::mem;
+
+/*
+ * Paging of enclave memory to the host.
+ * The runtime evicts an EPM page into one of its swap slots; the SM
+ * encrypts it into the UTM, where the host picks it up, and bumps the
+ * version of the slot. Loading it back checks the MAC against the slot
+ * and its current version, so the host can neither alter a page nor
+ * hand back an older copy. Keys are per enclave instance and never
+ * leave the SM.
+ */
+
+const SWAP_SLOTS_MAX: usize = config::SWAP_SLOTS_MAX;
+const SWAP_KEY_INFO: &[u8] = b"keystone-swap";
+
+/* an evicted page as found in the UTM */
+pub struct swapped_page {
+  pub data: [u8; page::RISCV_PGSIZE],
+  pub tag: [u8; crypto::MDSIZE]
+}
+
+impl swapped_page {
+  pub fn new() -> Self {
+    Self {
+      data: [0; page::RISCV_PGSIZE],
+      tag: [0; crypto::MDSIZE]
+    }
+  }
+}
+
+#[derive(Clone, Copy)]
+struct swap_slot {
+  evicted: bool, // holds a page that was not loaded back yet
+  version: u64
+}
+
+impl swap_slot {
+  pub fn new() -> Self {
+    Self {
+      evicted: false,
+      version: 0
+    }
+  }
+}
+
+#[derive(Clone, Copy)]
+struct swap_state {
+  keyed: bool,
+  keys: [u8; 2 * crypto::MDSIZE], // cipher key, MAC key
+  slots: [swap_slot; SWAP_SLOTS_MAX]
+}
+
+impl swap_state {
+  pub fn new() -> Self {
+    Self {
+      keyed: false,
+      keys: [0; 2 * crypto::MDSIZE],
+      slots: [swap_slot::new(); SWAP_SLOTS_MAX]
+    }
+  }
+}
+
+static swap_lock: opensbi::spinlock_t = opensbi::spinlock_t::new(); // nests inside encl_lock
+
+static mut swap_states: [swap_state; config::ENCL_MAX] = [swap_state::new(); config::ENCL_MAX];
+static mut swap_generation: u64 = 0;
+
+/* pages are encrypted and MAC'd here, away from the host */
+static mut swap_buf: swapped_page = swapped_page::new();
+
+/*
+ * Keys for the current instance of enclave `eid`, swap_lock must be held.
+ * Two instances never share keys, so a (slot, version) pair never repeats
+ * under one key.
+ */
+fn swap_keys(eid: enclave_id) -> Result<&'static [u8], SmError> {
+  let st: &mut swap_state = &mut swap_states[eid];
+  let info: [u8; 64] = [0; 64];
+  let info_len: usize = SWAP_KEY_INFO.len() + 24;
+
+  if !st.keyed {
+    info[..SWAP_KEY_INFO.len()].copy_from_slice(SWAP_KEY_INFO);
+    info[SWAP_KEY_INFO.len()..SWAP_KEY_INFO.len() + 8].copy_from_slice(&(eid as u64).to_le_bytes());
+    info[SWAP_KEY_INFO.len() + 8..SWAP_KEY_INFO.len() + 16].copy_from_slice(&swap_generation.to_le_bytes());
+    info[SWAP_KEY_INFO.len() + 16..info_len].copy_from_slice(&(opensbi::csr_read("mcycle") as u64).to_le_bytes());
+    swap_generation += 1;
+    if sm::sm_derive_key(&mut st.keys, &info[..info_len]) != 0 {
+      return Err(SmError::UnknownError);
+    }
+    st.keyed = true;
+  }
+  return Ok(&st.keys);
+}
+
+fn slot_iv(slot: usize, version: u64) -> [u8; 16] {
+  let iv: [u8; 16] = [0; 16];
+
+  iv[..8].copy_from_slice(&(slot as u64).to_le_bytes());
+  iv[8..].copy_from_slice(&version.to_le_bytes());
+  return iv;
+}
+
+fn compute_tag(mac_key: &[u8], iv: &[u8], data: &[u8], tag: &mut [u8]) {
+  let ctx: crypto::mac_ctx = crypto::mac_init(mac_key);
+
+  crypto::mac_extend(&mut ctx, iv);
+  crypto::mac_extend(&mut ctx, data);
+  crypto::mac_finalize(tag, &mut ctx);
+}
+
+fn check_swap_args(paddr: usize, slot: usize) -> Result<(), SmError> {
+  if slot >= SWAP_SLOTS_MAX || paddr & (page::RISCV_PGSIZE - 1) != 0 {
+    return Err(SmError::IllegalArgument);
+  }
+  return Ok(());
+}
+
+/*
+ * Evicts the EPM page at `paddr` of enclave `eid` into swap slot `slot`,
+ * writing it encrypted to the UTM at `dest`. The page is zeroed after.
+ */
+pub fn page_evict(eid: enclave_id, paddr: usize, slot: usize, dest: usize) -> Result<(), SmError> {
+  check_swap_args(paddr, slot)?;
+  // the page and the buffer must stay the caller's own while they are used
+  return enclave::with_epm_page_and_utm(eid, paddr, dest, mem::size_of::<swapped_page>(), &|| evict_page(eid, paddr, slot, dest));
+}
+
+/* page_evict() once the page and the buffer are checked, encl_lock is held */
+fn evict_page(eid: enclave_id, paddr: usize, slot: usize, dest: usize) -> Result<(), SmError> {
+  opensbi::spin_lock(&mut swap_lock);
+  if swap_states[eid].slots[slot].evicted {
+    opensbi::spin_unlock(&mut swap_lock);
+    return Err(SmError::IllegalArgument);
+  }
+  let keys: &[u8] = match swap_keys(eid) {
+    Ok(keys) => keys,
+    Err(err) => {
+      opensbi::spin_unlock(&mut swap_lock);
+      return Err(err);
+    }
+  };
+  let version: u64 = match swap_states[eid].slots[slot].version.checked_add(1) {
+    Some(v) => v,
+    None => {
+      opensbi::spin_unlock(&mut swap_lock);
+      return Err(SmError::CounterOverflow);
+    }
+  };
+  let iv: [u8; 16] = slot_iv(slot, version);
+
+  crypto::keystream_xor(&keys[..crypto::MDSIZE], &iv, 0, paddr, swap_buf.data.as_ptr() as usize, page::RISCV_PGSIZE);
+  compute_tag(&keys[crypto::MDSIZE..], &iv, &swap_buf.data, &mut swap_buf.tag);
+  opensbi::sbi_memcpy(dest, &swap_buf as *const swapped_page as usize, mem::size_of::<swapped_page>());
+  opensbi::sbi_memset(paddr, 0, page::RISCV_PGSIZE);
+
+  swap_states[eid].slots[slot].version = version;
+  swap_states[eid].slots[slot].evicted = true;
+  opensbi::spin_unlock(&mut swap_lock);
+
+  return Ok(());
+}
+
+/*
+ * Loads the page in swap slot `slot` of enclave `eid` from the UTM at
+ * `src` into the EPM page at `paddr`. Fails with IllegalPte if the page
+ * was altered or is not the latest one evicted to the slot.
+ */
+pub fn page_load(eid: enclave_id, paddr: usize, slot: usize, src: usize) -> Result<(), SmError> {
+  check_swap_args(paddr, slot)?;
+  return enclave::with_epm_page_and_utm(eid, paddr, src, mem::size_of::<swapped_page>(), &|| load_page(eid, paddr, slot, src));
+}
+
+/* page_load() once the page and the buffer are checked, encl_lock is held */
+fn load_page(eid: enclave_id, paddr: usize, slot: usize, src: usize) -> Result<(), SmError> {
+  let tag: [u8; crypto::MDSIZE] = [0; crypto::MDSIZE];
+  let diff: u8 = 0;
+
+  opensbi::spin_lock(&mut swap_lock);
+  if !swap_states[eid].slots[slot].evicted {
+    opensbi::spin_unlock(&mut swap_lock);
+    return Err(SmError::IllegalArgument);
+  }
+  let keys: &[u8] = &swap_states[eid].keys;
+  let iv: [u8; 16] = slot_iv(slot, swap_states[eid].slots[slot].version);
+
+  // the UTM is host memory, read it exactly once
+  opensbi::sbi_memcpy(&swap_buf as *const swapped_page as usize, src, mem::size_of::<swapped_page>());
+  compute_tag(&keys[crypto::MDSIZE..], &iv, &swap_buf.data, &mut tag);
+  for i in 0..crypto::MDSIZE {
+    diff |= tag[i] ^ swap_buf.tag[i];
+  }
+  if diff != 0 {
+    opensbi::spin_unlock(&mut swap_lock);
+    return Err(SmError::IllegalPte);
+  }
+
+  crypto::keystream_xor(&keys[..crypto::MDSIZE], &iv, 0, swap_buf.data.as_ptr() as usize, paddr, page::RISCV_PGSIZE);
+  swap_states[eid].slots[slot].evicted = false;
+  opensbi::spin_unlock(&mut swap_lock);
+
+  return Ok(());
+}
+
+/* true if enclave `eid` has pages out in the host */
+pub fn has_evicted(eid: enclave_id) -> bool {
+  let ret: bool;
+
+  opensbi::spin_lock(&mut swap_lock);
+  ret = swap_states[eid].slots.iter().any(|s| s.evicted);
+  opensbi::spin_unlock(&mut swap_lock);
+  return ret;
+}
+
+/* forgets the keys and slots of `eid`, its pages in the host are garbage now */
+pub fn swap_clear(eid: enclave_id) {
+  opensbi::spin_lock(&mut swap_lock);
+  opensbi::sbi_memset(swap_states[eid].keys.as_ptr() as usize, 0, swap_states[eid].keys.len());
+  swap_states[eid] = swap_state::new();
+  opensbi::spin_unlock(&mut swap_lock);
+}
@@ -7,7 +7,18 @@ extern "C" {
   fn trap_vector_enclave();
 }
 
+/* lifecycle of a single enclave thread */
+pub mod thread_status {
+  pub const THREAD_INVALID: isize = -1;
+  pub const THREAD_FRESH: isize = 0;
+  pub const THREAD_RUNNING: isize = 1;
+  pub const THREAD_STOPPED: isize = 2;
+  pub const THREAD_EXITED: isize = 3;
+}
+
+#[derive(Clone, Copy)]
 pub struct thread_state {
+  pub status: isize,
   prev_mpp: i32,
   prev_mepc: usize,
   prev_mstatus: usize,
@@ -18,6 +29,7 @@ pub struct thread_state {
 impl thread_state {
   pub fn new() -> Self {
     Self {
+      status: thread_status::THREAD_INVALID,
       prev_mpp: 0,
       prev_mepc: 0,
       prev_mstatus: 0,
@@ -27,6 +39,7 @@ impl thread_state {
   }
 }
 
+#[derive(Clone, Copy)]
 pub struct ctx {
   slot: usize,
   ra: usize,
@@ -101,6 +114,7 @@ impl ctx {
   }
 }
 
+#[derive(Clone, Copy)]
 pub struct csrs {
   sstatus: usize,    //Supervisor status register.
   sedeleg: usize,    //Supervisor exception delegation register.
@@ -250,6 +264,14 @@ unsafe fn any_as_usize_slice<T: Sized>(p: &T) -> &[usize] {
   )
 }
 
+/* `p` as words to write through, T must be made of usize */
+unsafe fn any_as_usize_slice_mut<T: Sized>(p: &mut T) -> &mut [usize] {
+  ::std::slice::from_raw_parts_mut(
+      (p as *mut T) as *mut usize,
+      ::std::mem::size_of::<T>() / ::std::mem::size_of::<usize>(),
+  )
+}
+
 pub fn clean_state(state: &mut thread_state) {
   let i: i32;
   
@@ -261,6 +283,7 @@ pub fn clean_state(state: &mut thread_state) {
   }
 
   state.prev_mpp = -1; // 0x800;
+  state.status = thread_status::THREAD_FRESH;
   clean_smode_csrs(state); 
 }
 
@@ -285,6 +308,69 @@ fn clean_smode_csrs(state: &mut thread_state){
 
 }
 
+/* page table the thread resumes with, 0 for a thread that never ran */
+pub fn get_prev_satp(state: &thread_state) -> usize {
+  state.prev_csrs.satp
+}
+
+pub fn set_prev_satp(state: &mut thread_state, satp: usize) {
+  state.prev_csrs.satp = satp;
+}
+
+/* x1-x31 of a thread off its hart into regs[1..32], regs[0] reads as zero */
+pub fn get_prev_regs(state: &thread_state, regs: &mut [usize]) {
+  unsafe {
+    let prev: &[usize] = any_as_usize_slice(&state.prev_state);
+    regs[0] = 0;
+    for i in 1..32 {
+      regs[i] = prev[i];
+    }
+  }
+}
+
+/* slot 0 holds the resume marker and is left alone */
+pub fn set_prev_regs(state: &mut thread_state, regs: &[usize]) {
+  unsafe {
+    let prev: &mut [usize] = any_as_usize_slice_mut(&mut state.prev_state);
+    for i in 1..32 {
+      prev[i] = regs[i];
+    }
+  }
+}
+
+/* pc the thread resumes at, resuming adds 4 to the saved mepc */
+pub fn get_prev_pc(state: &thread_state) -> usize {
+  state.prev_mepc.wrapping_add(4)
+}
+
+pub fn set_prev_pc(state: &mut thread_state, pc: usize) {
+  state.prev_mepc = pc.wrapping_sub(4);
+}
+
+/* breakpoints reach the SM instead of the runtime, so it can stop the thread */
+pub fn trap_breakpoints() {
+  opensbi::csr_clear("medeleg", 1 << opensbi::CAUSE_BREAKPOINT);
+}
+
+/*
+ * Single-stepping through the first Sdtrig trigger: an instruction count
+ * of one in S and U mode raises a breakpoint once the next instruction
+ * retires.
+ */
+pub fn arm_single_step() {
+  trap_breakpoints();
+  opensbi::csr_write("tselect", 0);
+  opensbi::csr_write("tdata1", (opensbi::TDATA1_TYPE_ICOUNT << (opensbi::Const::BITS_PER_LONG - 4))
+    | (1 << opensbi::ICOUNT_COUNT_SHIFT) | opensbi::ICOUNT_S | opensbi::ICOUNT_U);
+}
+
+/* disarms the step and gives breakpoints back to S-mode, as OpenSBI delegates them */
+pub fn release_breakpoints() {
+  opensbi::csr_write("tselect", 0);
+  opensbi::csr_write("tdata1", 0);
+  opensbi::csr_set("medeleg", 1 << opensbi::CAUSE_BREAKPOINT);
+}
+
 pub fn swap_prev_mstatus(thread: &mut thread_state, regs: &mut sbi_trap::sbi_trap_regs, current_mstatus: usize) {
   //Time interrupts can occur in either user mode or supervisor mode
   let mstatus_mask: usize = opensbi::MSTATUS_SIE | opensbi::MSTATUS_SPIE | opensbi::MSTATUS_SPP | opensbi::MSTATUS_MPP | opensbi::MSTATUS_FS | opensbi::MSTATUS_SUM | opensbi::MSTATUS_MXR; // opensbi